@@ -0,0 +1,61 @@
+// Decodes a full 256KiB piece arriving as 16KiB blocks and keeps every
+// block, the way a download does, so copies on the receive path show up.
+// The copying variant is how blocks used to be kept, for comparison.
+use bittorrent_starter_rust::peer_protocol::{PeerMessage, PeerMessageCodec};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+const PIECE_LEN: usize = 256 * 1024;
+const BLOCK_LEN: usize = 16 * 1024;
+
+fn piece_frames() -> BytesMut {
+    let mut buf = BytesMut::new();
+    for begin in (0..PIECE_LEN).step_by(BLOCK_LEN) {
+        let block = vec![(begin / BLOCK_LEN) as u8; BLOCK_LEN];
+        let msg = PeerMessage::Piece {
+            index: 0,
+            begin: begin as u32,
+            block: block.into(),
+        };
+        PeerMessageCodec.encode(msg, &mut buf).unwrap();
+    }
+    buf
+}
+
+fn decode_piece(c: &mut Criterion) {
+    let frames = piece_frames();
+    let mut group = c.benchmark_group("codec");
+    group.throughput(Throughput::Bytes(PIECE_LEN as u64));
+
+    group.bench_function("decode piece", |b| {
+        b.iter_batched(
+            || frames.clone(),
+            |mut buf| {
+                let mut blocks = Vec::new();
+                while let Some(PeerMessage::Piece { begin, block, .. }) = PeerMessageCodec.decode(&mut buf).unwrap() {
+                    blocks.push((begin, block));
+                }
+                blocks
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("decode piece, copying blocks", |b| {
+        b.iter_batched(
+            || frames.clone(),
+            |mut buf| {
+                let mut blocks = Vec::new();
+                while let Some(PeerMessage::Piece { begin, block, .. }) = PeerMessageCodec.decode(&mut buf).unwrap() {
+                    blocks.push((begin, block.to_vec()));
+                }
+                blocks
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode_piece);
+criterion_main!(benches);
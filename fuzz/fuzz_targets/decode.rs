@@ -0,0 +1,16 @@
+// Feeds arbitrary bytes to the peer message decoder, which must return
+// messages or errors but never panic. Run with `cargo fuzz run decode`.
+#![no_main]
+
+use bittorrent_starter_rust::peer_protocol::PeerMessageCodec;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = BytesMut::from(data);
+    let mut codec = PeerMessageCodec;
+    // a stream ends on the first error, same as Framed
+    while let Ok(Some(_)) = codec.decode(&mut buf) {}
+    let _ = codec.decode_eof(&mut buf);
+});
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const BASE_DELAY: Duration = Duration::from_secs(5);
+pub const MAX_DELAY: Duration = Duration::from_secs(300);
+// failures in a row before we stop trying a peer altogether
+pub const MAX_FAILURES: u32 = 5;
+
+struct Health {
+    failures: u32,
+    retry_at: Instant,
+}
+
+// Exponential backoff per peer address. Like the choker it takes `now`
+// from the caller rather than reading the clock.
+#[derive(Default)]
+pub struct PeerBackoff {
+    peers: HashMap<String, Health>,
+}
+
+impl PeerBackoff {
+    pub fn record_failure(&mut self, addr: &str, now: Instant) {
+        let health = self.peers.entry(addr.to_owned()).or_insert(Health {
+            failures: 0,
+            retry_at: now,
+        });
+        health.failures += 1;
+        let delay = BASE_DELAY
+            .saturating_mul(1 << (health.failures - 1).min(16))
+            .min(MAX_DELAY);
+        health.retry_at = now + delay;
+    }
+
+    // a peer that delivered something starts over with a clean slate
+    pub fn record_success(&mut self, addr: &str) {
+        self.peers.remove(addr);
+    }
+
+    pub fn is_blacklisted(&self, addr: &str) -> bool {
+        self.peers
+            .get(addr)
+            .is_some_and(|h| h.failures >= MAX_FAILURES)
+    }
+
+    pub fn is_ready(&self, addr: &str, now: Instant) -> bool {
+        match self.peers.get(addr) {
+            Some(h) => h.failures < MAX_FAILURES && now >= h.retry_at,
+            None => true,
+        }
+    }
+
+    // when the first of `addrs` that isn't blacklisted may be tried again,
+    // None if they are all blacklisted
+    pub fn next_retry<'a>(&self, addrs: impl IntoIterator<Item = &'a str>) -> Option<Instant> {
+        addrs
+            .into_iter()
+            .filter(|addr| !self.is_blacklisted(addr))
+            .map(|addr| self.peers.get(addr).map(|h| h.retry_at))
+            .min()
+            .map(|at| at.unwrap_or_else(Instant::now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "10.0.0.1:6881";
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let mut backoff = PeerBackoff::default();
+        let now = Instant::now();
+        let mut delays = Vec::new();
+        for _ in 0..MAX_FAILURES - 1 {
+            backoff.record_failure(PEER, now);
+            delays.push(backoff.next_retry([PEER]).unwrap() - now);
+        }
+        let secs: Vec<u64> = delays.iter().map(Duration::as_secs).collect();
+        assert_eq!(secs, [5, 10, 20, 40]);
+
+        // the cap only shows with more failures than MAX_FAILURES allows
+        backoff.peers.insert(PEER.to_owned(), Health { failures: 10, retry_at: now });
+        backoff.record_failure(PEER, now);
+        assert_eq!(backoff.peers[PEER].retry_at, now + MAX_DELAY);
+    }
+
+    #[test]
+    fn peer_is_ready_once_its_delay_has_passed() {
+        let mut backoff = PeerBackoff::default();
+        let now = Instant::now();
+        assert!(backoff.is_ready(PEER, now));
+        backoff.record_failure(PEER, now);
+        assert!(!backoff.is_ready(PEER, now));
+        assert!(!backoff.is_ready(PEER, now + BASE_DELAY - Duration::from_millis(1)));
+        assert!(backoff.is_ready(PEER, now + BASE_DELAY));
+    }
+
+    #[test]
+    fn too_many_failures_blacklist_a_peer() {
+        let mut backoff = PeerBackoff::default();
+        let now = Instant::now();
+        for _ in 0..MAX_FAILURES {
+            assert!(!backoff.is_blacklisted(PEER));
+            backoff.record_failure(PEER, now);
+        }
+        assert!(backoff.is_blacklisted(PEER));
+        assert!(!backoff.is_ready(PEER, now + MAX_DELAY * 10));
+        assert_eq!(backoff.next_retry([PEER]), None);
+    }
+
+    #[test]
+    fn success_starts_over() {
+        let mut backoff = PeerBackoff::default();
+        let now = Instant::now();
+        for _ in 0..MAX_FAILURES - 1 {
+            backoff.record_failure(PEER, now);
+        }
+        backoff.record_success(PEER);
+        assert!(backoff.is_ready(PEER, now));
+        backoff.record_failure(PEER, now);
+        assert_eq!(backoff.next_retry([PEER]), Some(now + BASE_DELAY));
+    }
+
+    #[test]
+    fn next_retry_is_the_soonest_peer_not_blacklisted() {
+        let mut backoff = PeerBackoff::default();
+        let now = Instant::now();
+        backoff.record_failure("a", now);
+        backoff.record_failure("b", now);
+        backoff.record_failure("b", now);
+        for _ in 0..MAX_FAILURES {
+            backoff.record_failure("c", now);
+        }
+        assert_eq!(backoff.next_retry(["b", "c"]), Some(now + BASE_DELAY * 2));
+        assert_eq!(backoff.next_retry(["a", "b", "c"]), Some(now + BASE_DELAY));
+        // a peer we've never failed with can go right away
+        let fresh = backoff.next_retry(["b", "new"]).unwrap();
+        assert!(fresh <= Instant::now());
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    // bytes per second, None for unlimited
+    rate: Option<u64>,
+    // can go negative, a large frame is let through and paid off after
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            // at most a second's worth of burst
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.refilled = now;
+    }
+}
+
+// A token bucket shared by every connection it's cloned into. A limiter
+// made with `with_parent` also draws from the parent, so a torrent's
+// limit sits under the session's.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    parent: Option<Box<RateLimiter>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                refilled: Instant::now(),
+            })),
+            parent: None,
+        }
+    }
+
+    pub fn with_parent(rate: Option<u64>, parent: &RateLimiter) -> Self {
+        Self {
+            parent: Some(Box::new(parent.clone())),
+            ..Self::new(rate)
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    // takes effect straight away for every connection sharing the limiter
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        bucket.tokens = match rate {
+            Some(rate) => bucket.tokens.min(rate as f64),
+            None => 0.0,
+        };
+    }
+
+    // Waits until whatever was consumed so far has been paid off. Nothing
+    // is taken from the bucket while waiting, so this is safe to cancel.
+    pub async fn wait(&self) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            loop {
+                let delay = {
+                    let mut bucket = current.bucket.lock().unwrap();
+                    bucket.refill(Instant::now());
+                    match bucket.rate {
+                        Some(rate) if bucket.tokens < 0.0 => {
+                            // a rate of zero would never pay anything off
+                            Duration::from_secs_f64(-bucket.tokens / rate.max(1) as f64)
+                        }
+                        _ => break,
+                    }
+                };
+                tokio::time::sleep(delay).await;
+            }
+            limiter = current.parent.as_deref();
+        }
+    }
+
+    // charge `bytes` that have already been sent or received
+    pub fn consume(&self, bytes: usize) {
+        let mut limiter = Some(self);
+        while let Some(current) = limiter {
+            let mut bucket = current.bucket.lock().unwrap();
+            if bucket.rate.is_some() {
+                bucket.refill(Instant::now());
+                bucket.tokens -= bytes as f64;
+            }
+            drop(bucket);
+            limiter = current.parent.as_deref();
+        }
+    }
+}
+
+// The pair of limiters a connection is subject to.
+#[derive(Clone, Default)]
+pub struct Bandwidth {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl Bandwidth {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+
+    // limits of their own that also count against `self`
+    pub fn child(&self, download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: RateLimiter::with_parent(download, &self.download),
+            upload: RateLimiter::with_parent(upload, &self.upload),
+        }
+    }
+}
@@ -1,55 +1,59 @@
 use serde_json::{self, Map};
 
+use crate::error::{Error, Result};
 
-fn extract_string(encoded_value: &str) -> (Option<serde_json::Value>, &str) {
-    eprintln!("processing string {} ", encoded_value);
+type Decoded<'a> = Result<(Option<serde_json::Value>, &'a str)>;
+
+fn invalid(what: &str, encoded_value: &str) -> Error {
+    // only the start of what's left, it can be the rest of a large file
+    Error::Bencode(format!("{} (at {:.32})", what, encoded_value))
+}
+
+fn extract_string(encoded_value: &str) -> Decoded<'_> {
     // Example: "5:hello" -> "hello"
-    let colon_index = encoded_value.find(':').unwrap();
+    let colon_index = encoded_value
+        .find(':')
+        .ok_or_else(|| invalid("string without length", encoded_value))?;
     let number_string = &encoded_value[..colon_index];
-    let number = number_string.parse::<i64>().unwrap();
-    let end_point = colon_index + 1 + number as usize;
-    let string = &encoded_value[colon_index + 1..end_point];
-    return (
+    let number = number_string
+        .parse::<usize>()
+        .map_err(|_| invalid("string length invalid", encoded_value))?;
+    let end_point = colon_index + 1 + number;
+    let string = encoded_value
+        .get(colon_index + 1..end_point)
+        .ok_or_else(|| invalid("string truncated", encoded_value))?;
+    Ok((
         Some(serde_json::Value::String(string.to_string())),
         &encoded_value[end_point..],
-    );
+    ))
 }
 
-fn extract_number(encoded_value: &str) -> (Option<serde_json::Value>, &str) {
-    eprintln!("processing number {} ", encoded_value);
+fn extract_number(encoded_value: &str) -> Decoded<'_> {
     if let Some(e_index) = encoded_value.find('e') {
         let number_string = &encoded_value[1..e_index];
         if let Ok(number) = number_string.parse() {
-            return (
+            return Ok((
                 Some(serde_json::Value::Number(number)),
                 &encoded_value[e_index + 1..],
-            );
+            ));
         }
     }
-    panic!("encoded integer invalid ({encoded_value})")
+    Err(invalid("encoded integer invalid", encoded_value))
 }
 
-fn extract_list(encoded_value: &str) -> (Option<serde_json::Value>, &str) {
+fn extract_list(encoded_value: &str) -> Decoded<'_> {
     // locate the start and end of the list.
-    eprintln!("processing list {} ", encoded_value);
     if let Some(start_index) = encoded_value.find('l') {
         //we recurse to get the values from the list
         //calls know what they take and return unprocessed
         //elements
         let mut current_str = &encoded_value[start_index + 1..];
-        eprintln!("begin processing elements  {} ", current_str);
         let mut vec = vec![];
         let mut keep_processing = true;
         while keep_processing {
-            match decode_bencoded_value_r(&current_str) {
+            match decode_bencoded_value_r(current_str)? {
                 (Some(v), remaining) => {
                     //add element into vector for list.
-                    eprintln!(
-                        "pushing {} , remaining: '{}'[{}]",
-                        v.to_string(),
-                        remaining,
-                        remaining.len()
-                    );
                     vec.push(v);
                     current_str = remaining
                 }
@@ -59,39 +63,29 @@ fn extract_list(encoded_value: &str) -> (Option<serde_json::Value>, &str) {
                 }
             }
         }
-        return (Some(serde_json::Value::Array(vec)), current_str);
+        return Ok((Some(serde_json::Value::Array(vec)), current_str));
     }
-    panic!("encoded list invalid ({encoded_value})")
+    Err(invalid("encoded list invalid", encoded_value))
 }
 
-fn extract_dictionary(encoded_value: &str) -> (Option<serde_json::Value>, &str) {
+fn extract_dictionary(encoded_value: &str) -> Decoded<'_> {
     if let Some(start_index) = encoded_value.find('d') {
         //similarly to list we need to recurse, however
         //the format differs in that we MUST have a string then colon
         //then recurse - skip past the 'd'
         let mut current_str = &encoded_value[start_index + 1..];
-        eprintln!("begin processing map elements  {} ", current_str);
         let mut map = Map::new();
         //again we will try toprocess an 'e' when we reach the end of the
         //map (like list), this returns None and the remaining string
         let mut keep_processing = true;
         while keep_processing {
-            if current_str.chars().next().unwrap() != 'e' {
-                match extract_string(current_str) {
+            if !current_str.starts_with('e') {
+                match extract_string(current_str)? {
                     (Some(key), remainder) => {
-                        eprintln!("key  {} / remainder '{}' ", key.to_string(), remainder);
-                        let key = key.as_str().expect("dodgy key???");
-                        match decode_bencoded_value_r(&remainder) {
+                        let key = key.as_str().unwrap_or_default();
+                        match decode_bencoded_value_r(remainder)? {
                             (Some(v), remaining) => {
                                 //add element into vector for list.
-                                eprintln!(
-                                    "adding {}:{} , remaining: '{}'[{}]",
-                                    key.to_string(),
-                                    v,
-                                    remaining,
-                                    remaining.len()
-                                );
-
                                 match v.as_str() {
                                     Some(s) => map.insert(key.to_string(), s.into()),
                                     None => map.insert(key.to_string(), v),
@@ -104,50 +98,48 @@ fn extract_dictionary(encoded_value: &str) -> (Option<serde_json::Value>, &str)
                             }
                         }
                     }
-                    (None, _) => panic!("Invalid map format {}", encoded_value),
+                    (None, _) => return Err(invalid("Invalid map format", encoded_value)),
                 }
-                eprintln!("remaining '{}' ", current_str);
             } else {
                 keep_processing = false;
                 current_str = &current_str[1..];
             }
         }
-        return (Some(serde_json::Value::Object(map)), &current_str);
+        return Ok((Some(serde_json::Value::Object(map)), current_str));
     }
-    panic!("encoded dictionary invalid ({encoded_value})")
+    Err(invalid("encoded dictionary invalid", encoded_value))
 }
 
-fn container_end(encoded_value: &str) -> (Option<serde_json::Value>, &str) {
+fn container_end(encoded_value: &str) -> Decoded<'_> {
     // we have the end of the list so we need to indicate that
-    (None, &encoded_value[1..])
+    Ok((None, &encoded_value[1..]))
 }
 
-#[allow(dead_code)]
-fn decode_bencoded_value_r(encoded_value: &str) -> (Option<serde_json::Value>, &str) {
+fn decode_bencoded_value_r(encoded_value: &str) -> Decoded<'_> {
     //
     // This is a recursive call so I need to
     //
-    if encoded_value.chars().next().unwrap().is_digit(10) {
-        return extract_string(encoded_value);
-    } else if encoded_value.chars().next().unwrap() == 'i' {
-        return extract_number(encoded_value);
-    } else if encoded_value.chars().next().unwrap() == 'l' {
-        return extract_list(encoded_value);
-    } else if encoded_value.chars().next().unwrap() == 'd' {
-        return extract_dictionary(encoded_value);
-    } else if encoded_value.chars().next().unwrap() == 'e' {
-        return container_end(encoded_value);
+    if encoded_value.starts_with(|c: char| c.is_ascii_digit()) {
+        extract_string(encoded_value)
+    } else if encoded_value.starts_with('i') {
+        extract_number(encoded_value)
+    } else if encoded_value.starts_with('l') {
+        extract_list(encoded_value)
+    } else if encoded_value.starts_with('d') {
+        extract_dictionary(encoded_value)
+    } else if encoded_value.starts_with('e') {
+        container_end(encoded_value)
     } else {
-        panic!("Unhandled encoded value: {}", encoded_value)
+        Err(invalid("Unhandled encoded value", encoded_value))
     }
 }
 
-pub fn decode_bencoded_value(encoded_value: &str) -> serde_json::Value {
+pub fn decode_bencoded_value(encoded_value: &str) -> Result<serde_json::Value> {
     // basically we have one item being encoded in the example so
     // at the top level I can return just the value
-    match decode_bencoded_value_r(encoded_value) {
-        (Some(v), _) => v,
-        (None, _) => panic!("Got none why!!!"),
+    match decode_bencoded_value_r(encoded_value)? {
+        (Some(v), _) => Ok(v),
+        (None, _) => Err(invalid("unexpected end marker", encoded_value)),
     }
 }
 
@@ -0,0 +1,235 @@
+use std::fmt;
+
+use tokio_util::bytes::Bytes;
+
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidBitfield {
+    #[error("bitfield is {got} bytes, expected {expected}")]
+    Length { expected: usize, got: usize },
+    #[error("bitfield has spare bits set past the last piece")]
+    SpareBits,
+}
+
+// One bit per piece, most significant bit of the first byte is piece 0,
+// same layout as the wire so converting either way is a copy at most.
+// The spare bits in the last byte are always zero.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    // no pieces set
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    // every piece set
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self {
+            bits: vec![0xff; len.div_ceil(8)],
+            len,
+        };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    // A bitfield as a peer sent it. It has to be exactly long enough for
+    // `len` pieces and have the spare bits clear.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, InvalidBitfield> {
+        let expected = len.div_ceil(8);
+        if bytes.len() != expected {
+            return Err(InvalidBitfield::Length {
+                expected,
+                got: bytes.len(),
+            });
+        }
+        let bitfield = Self {
+            bits: bytes.to_vec(),
+            len,
+        };
+        if bitfield.spare_mask() & bytes.last().copied().unwrap_or(0) != 0 {
+            return Err(InvalidBitfield::SpareBits);
+        }
+        Ok(bitfield)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        Bytes::copy_from_slice(&self.bits)
+    }
+
+    // number of pieces, set or not
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // out of range pieces are never set
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(index < self.len, "piece {} out of range", index);
+        if value {
+            self.bits[index / 8] |= 0x80 >> (index % 8);
+        } else {
+            self.bits[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    // how many pieces are set
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn none(&self) -> bool {
+        self.bits.iter().all(|&b| b == 0)
+    }
+
+    // indices of the pieces that are set
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&i| self.get(i))
+    }
+
+    // indices of the pieces that are not set
+    pub fn zeros(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&i| !self.get(i))
+    }
+
+    // pieces set in both
+    pub fn intersection(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & b)
+    }
+
+    // pieces set here but not in `other`, e.g. what a peer has that we don't
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        self.combine(other, |a, b| a & !b)
+    }
+
+    fn combine(&self, other: &Bitfield, op: impl Fn(u8, u8) -> u8) -> Bitfield {
+        assert_eq!(self.len, other.len, "bitfields for different torrents");
+        Bitfield {
+            bits: self.bits.iter().zip(&other.bits).map(|(&a, &b)| op(a, b)).collect(),
+            len: self.len,
+        }
+    }
+
+    // the bits in the last byte that don't belong to a piece
+    fn spare_mask(&self) -> u8 {
+        match self.len % 8 {
+            0 => 0,
+            used => 0xff >> used,
+        }
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let mask = self.spare_mask();
+        if let Some(last) = self.bits.last_mut() {
+            *last &= !mask;
+        }
+    }
+}
+
+// a torrent can have thousands of pieces, a count is all that's useful
+// in logs
+impl fmt::Debug for Bitfield {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bitfield({}/{})", self.count(), self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn of(len: usize, ones: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for &i in ones {
+            bitfield.set(i, true);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn from_bytes_checks_length_and_spare_bits() {
+        let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b0100_0000], 10).unwrap();
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), [0, 2, 9]);
+        assert_eq!(bitfield.as_bytes(), [0b1010_0000, 0b0100_0000]);
+
+        // bit 10 is past the last piece
+        assert!(matches!(
+            Bitfield::from_bytes(&[0, 0b0010_0000], 10),
+            Err(InvalidBitfield::SpareBits)
+        ));
+        assert!(matches!(
+            Bitfield::from_bytes(&[0, 0b0000_0001], 10),
+            Err(InvalidBitfield::SpareBits)
+        ));
+        // a whole number of bytes has no spare bits
+        assert!(Bitfield::from_bytes(&[0xff, 0xff], 16).unwrap().is_full());
+        assert!(matches!(
+            Bitfield::from_bytes(&[0], 10),
+            Err(InvalidBitfield::Length { expected: 2, got: 1 })
+        ));
+        assert!(matches!(
+            Bitfield::from_bytes(&[0, 0, 0], 10),
+            Err(InvalidBitfield::Length { expected: 2, got: 3 })
+        ));
+    }
+
+    #[test]
+    fn full_leaves_spare_bits_clear() {
+        let full = Bitfield::full(10);
+        assert_eq!(full.as_bytes(), [0xff, 0b1100_0000]);
+        assert_eq!(full.count(), 10);
+        assert!(full.is_full());
+        assert!(Bitfield::from_bytes(full.as_bytes(), 10).is_ok());
+    }
+
+    #[test]
+    fn ones_and_zeros_split_every_piece() {
+        let bitfield = of(11, &[0, 7, 8, 10]);
+        assert_eq!(bitfield.ones().collect::<Vec<_>>(), [0, 7, 8, 10]);
+        assert_eq!(bitfield.zeros().collect::<Vec<_>>(), [1, 2, 3, 4, 5, 6, 9]);
+        assert_eq!(Bitfield::new(3).ones().count(), 0);
+        assert_eq!(Bitfield::full(3).zeros().count(), 0);
+        assert!(!bitfield.get(11));
+    }
+
+    #[test]
+    fn intersection_and_difference() {
+        let ours = of(12, &[0, 1, 2, 9]);
+        let theirs = of(12, &[1, 2, 3, 10, 11]);
+        assert_eq!(ours.intersection(&theirs), of(12, &[1, 2]));
+        assert_eq!(theirs.intersection(&ours), of(12, &[1, 2]));
+        // what they have that we don't, and the other way round
+        assert_eq!(theirs.difference(&ours), of(12, &[3, 10, 11]));
+        assert_eq!(ours.difference(&theirs), of(12, &[0, 9]));
+        assert!(ours.difference(&ours).none());
+        assert_eq!(ours.difference(&Bitfield::new(12)), ours);
+        // the spare bits stay clear
+        assert!(Bitfield::full(12).difference(&ours).as_bytes()[1] & 0x0f == 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "different torrents")]
+    fn combining_different_lengths_panics() {
+        Bitfield::new(8).intersection(&Bitfield::new(9));
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+// a peer that hasn't sent us a block for this long is snubbing us and
+// only gets uploads through the optimistic slot
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+pub type PeerKey = u64;
+
+struct PeerState {
+    interested: bool,
+    unchoked: bool,
+    // bytes moved since the last rechoke, turned into rates on the next one
+    downloaded: u64,
+    uploaded: u64,
+    download_rate: f64,
+    upload_rate: f64,
+    last_block: Instant,
+    last_optimistic: Option<Instant>,
+}
+
+// Tit-for-tat choking. The choker never looks at the clock itself, every
+// call takes `now` so the algorithm can be driven by a simulated clock.
+// While seeding there is nothing to download, so peers are ranked by how
+// fast they take data from us instead.
+pub struct Choker {
+    peers: HashMap<PeerKey, PeerState>,
+    slots: usize,
+    seeding: bool,
+    next_key: PeerKey,
+    last_rechoke: Option<Instant>,
+    optimistic: Option<PeerKey>,
+    optimistic_since: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(slots: usize, seeding: bool) -> Self {
+        Self {
+            peers: HashMap::new(),
+            slots: slots.max(1),
+            seeding,
+            next_key: 0,
+            last_rechoke: None,
+            optimistic: None,
+            optimistic_since: None,
+        }
+    }
+
+    pub fn add_peer(&mut self, now: Instant) -> PeerKey {
+        let key = self.next_key;
+        self.next_key += 1;
+        self.peers.insert(
+            key,
+            PeerState {
+                interested: false,
+                unchoked: false,
+                downloaded: 0,
+                uploaded: 0,
+                download_rate: 0.0,
+                upload_rate: 0.0,
+                last_block: now,
+                last_optimistic: None,
+            },
+        );
+        key
+    }
+
+    pub fn remove_peer(&mut self, key: PeerKey) {
+        self.peers.remove(&key);
+        if self.optimistic == Some(key) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn set_interested(&mut self, key: PeerKey, interested: bool) {
+        if let Some(peer) = self.peers.get_mut(&key) {
+            peer.interested = interested;
+        }
+    }
+
+    pub fn record_download(&mut self, key: PeerKey, bytes: u64, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(&key) {
+            peer.downloaded += bytes;
+            peer.last_block = now;
+        }
+    }
+
+    pub fn record_upload(&mut self, key: PeerKey, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(&key) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    fn is_snubbed(&self, peer: &PeerState, now: Instant) -> bool {
+        !self.seeding && now.duration_since(peer.last_block) >= SNUB_TIMEOUT
+    }
+
+    // Re-evaluate who gets an upload slot. Rates are only recomputed every
+    // RECHOKE_INTERVAL, but a free slot is handed out as soon as an
+    // interested peer is waiting for one. Returns the peers whose state
+    // changed, `true` meaning they are now unchoked.
+    pub fn tick(&mut self, now: Instant) -> Vec<(PeerKey, bool)> {
+        let due = self
+            .last_rechoke
+            .is_none_or(|t| now.duration_since(t) >= RECHOKE_INTERVAL);
+        let unchoked = self.peers.values().filter(|p| p.unchoked).count();
+        let waiting = self.peers.values().any(|p| p.interested && !p.unchoked);
+        let free_slot = waiting && unchoked < self.slots;
+        if !due && !free_slot {
+            return Vec::new();
+        }
+
+        if due {
+            let elapsed = self
+                .last_rechoke
+                .map_or(RECHOKE_INTERVAL, |t| now.duration_since(t))
+                .as_secs_f64()
+                .max(f64::EPSILON);
+            for peer in self.peers.values_mut() {
+                peer.download_rate = peer.downloaded as f64 / elapsed;
+                peer.upload_rate = peer.uploaded as f64 / elapsed;
+                peer.downloaded = 0;
+                peer.uploaded = 0;
+            }
+            self.last_rechoke = Some(now);
+        }
+
+        // regular slots go to the best interested peers that aren't
+        // snubbing us, keeping one back for the optimistic unchoke
+        let mut ranked: Vec<(PeerKey, f64)> = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.interested && !self.is_snubbed(p, now))
+            .map(|(&k, p)| (k, if self.seeding { p.upload_rate } else { p.download_rate }))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let regular: Vec<PeerKey> = ranked
+            .into_iter()
+            .take(self.slots - 1)
+            .map(|(k, _)| k)
+            .collect();
+
+        // the optimistic slot rotates to whoever has gone longest without
+        // it, so new peers get a chance to show what they can do
+        let expired = self
+            .optimistic_since
+            .is_none_or(|t| now.duration_since(t) >= OPTIMISTIC_INTERVAL);
+        let still_valid = self.optimistic.is_some_and(|k| {
+            !regular.contains(&k) && self.peers.get(&k).is_some_and(|p| p.interested)
+        });
+        if expired || !still_valid {
+            let next = self
+                .peers
+                .iter()
+                .filter(|(k, p)| p.interested && !regular.contains(k))
+                .min_by_key(|(&k, p)| (p.last_optimistic, Some(k) == self.optimistic, k))
+                .map(|(&k, _)| k);
+            if next != self.optimistic || expired {
+                self.optimistic = next;
+                self.optimistic_since = Some(now);
+                if let Some(peer) = next.and_then(|k| self.peers.get_mut(&k)) {
+                    peer.last_optimistic = Some(now);
+                }
+            }
+        }
+
+        let mut changes = Vec::new();
+        for (&key, peer) in self.peers.iter_mut() {
+            let unchoke = regular.contains(&key) || self.optimistic == Some(key);
+            if unchoke != peer.unchoked {
+                peer.unchoked = unchoke;
+                changes.push((key, unchoke));
+            }
+        }
+        changes
+    }
+}
+
+struct Shared {
+    choker: Choker,
+    peers: HashMap<PeerKey, watch::Sender<bool>>,
+    // where each peer connected from, what we download comes in over
+    // other connections and is credited by address
+    addrs: HashMap<PeerKey, IpAddr>,
+}
+
+// Drives a choker off the real clock and tells each peer task whether it
+// may upload through a watch channel (`true` is unchoked).
+#[derive(Clone)]
+pub struct SharedChoker {
+    inner: Arc<Mutex<Shared>>,
+}
+
+impl SharedChoker {
+    pub fn new(choker: Choker) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Shared {
+                choker,
+                peers: HashMap::new(),
+                addrs: HashMap::new(),
+            })),
+        }
+    }
+
+    pub fn join(&self, addr: Option<IpAddr>) -> (PeerKey, watch::Receiver<bool>) {
+        let mut shared = self.inner.lock().unwrap();
+        let key = shared.choker.add_peer(Instant::now());
+        let (tx, rx) = watch::channel(false);
+        shared.peers.insert(key, tx);
+        if let Some(addr) = addr {
+            shared.addrs.insert(key, addr);
+        }
+        (key, rx)
+    }
+
+    pub fn leave(&self, key: PeerKey) {
+        let mut shared = self.inner.lock().unwrap();
+        shared.choker.remove_peer(key);
+        shared.peers.remove(&key);
+        shared.addrs.remove(&key);
+    }
+
+    pub fn set_interested(&self, key: PeerKey, interested: bool) {
+        self.inner.lock().unwrap().choker.set_interested(key, interested);
+    }
+
+    // bytes downloaded from `addr`, credited to whoever we upload to there
+    pub fn record_download(&self, addr: IpAddr, bytes: u64) {
+        let mut shared = self.inner.lock().unwrap();
+        let Shared { choker, addrs, .. } = &mut *shared;
+        for (&key, _) in addrs.iter().filter(|(_, a)| **a == addr) {
+            choker.record_download(key, bytes, Instant::now());
+        }
+    }
+
+    pub fn record_upload(&self, key: PeerKey, bytes: u64) {
+        self.inner.lock().unwrap().choker.record_upload(key, bytes);
+    }
+
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let mut shared = self.inner.lock().unwrap();
+            for (key, unchoke) in shared.choker.tick(Instant::now()) {
+                if let Some(tx) = shared.peers.get(&key) {
+                    let _ = tx.send(unchoke);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    fn unchoked(choker: &Choker) -> Vec<PeerKey> {
+        let mut keys: Vec<_> = choker.peers.iter().filter(|(_, p)| p.unchoked).map(|(&k, _)| k).collect();
+        keys.sort();
+        keys
+    }
+
+    fn sorted(mut changes: Vec<(PeerKey, bool)>) -> Vec<(PeerKey, bool)> {
+        changes.sort();
+        changes
+    }
+
+    fn interested_peers(choker: &mut Choker, n: usize, now: Instant) -> Vec<PeerKey> {
+        (0..n)
+            .map(|_| {
+                let key = choker.add_peer(now);
+                choker.set_interested(key, true);
+                key
+            })
+            .collect()
+    }
+
+    // the fastest peers get the regular slots, and only every 10 seconds
+    #[test]
+    fn rechokes_on_rates_every_interval() {
+        let t0 = Instant::now();
+        let mut choker = Choker::new(3, false);
+        let peers = interested_peers(&mut choker, 4, t0);
+        for (&key, bytes) in peers.iter().zip([100, 200, 300, 0]) {
+            choker.record_download(key, bytes, t0);
+        }
+        choker.tick(t0);
+        // 2 and 1 on rate, 0 optimistically
+        assert_eq!(unchoked(&choker), [0, 1, 2]);
+        assert_eq!(choker.optimistic, Some(0));
+
+        choker.record_download(0, 5000, t0 + secs(5));
+        choker.record_download(3, 1000, t0 + secs(5));
+        assert!(choker.tick(t0 + secs(5)).is_empty());
+        assert!(choker.tick(t0 + RECHOKE_INTERVAL - secs(1)).is_empty());
+
+        // 0 moves up to a regular slot, so the optimistic one goes to 1
+        let changes = choker.tick(t0 + RECHOKE_INTERVAL);
+        assert_eq!(sorted(changes), [(2, false), (3, true)]);
+        assert_eq!(unchoked(&choker), [0, 1, 3]);
+        assert_eq!(choker.optimistic, Some(1));
+    }
+
+    #[test]
+    fn seeding_ranks_by_upload() {
+        let t0 = Instant::now();
+        let mut choker = Choker::new(2, true);
+        let peers = interested_peers(&mut choker, 3, t0);
+        choker.record_upload(peers[2], 1000);
+        choker.tick(t0);
+        assert_eq!(unchoked(&choker), [0, 2]);
+        assert_eq!(choker.optimistic, Some(0));
+    }
+
+    // with no regular slots the optimistic one goes round everyone, the
+    // peer that's waited longest first
+    #[test]
+    fn optimistic_slot_rotates_every_interval() {
+        let t0 = Instant::now();
+        let mut choker = Choker::new(1, true);
+        interested_peers(&mut choker, 3, t0);
+        let mut holders = Vec::new();
+        for step in 0..=9 {
+            choker.tick(t0 + RECHOKE_INTERVAL * step);
+            holders.push(choker.optimistic.unwrap());
+            assert_eq!(unchoked(&choker), [choker.optimistic.unwrap()]);
+        }
+        assert_eq!(holders, [0, 0, 0, 1, 1, 1, 2, 2, 2, 0]);
+        assert_eq!(OPTIMISTIC_INTERVAL, RECHOKE_INTERVAL * 3);
+    }
+
+    // a peer that loses interest gives up the optimistic slot at the next
+    // rechoke rather than holding it for the full 30 seconds
+    #[test]
+    fn optimistic_slot_moves_on_when_its_peer_loses_interest() {
+        let t0 = Instant::now();
+        let mut choker = Choker::new(1, true);
+        interested_peers(&mut choker, 2, t0);
+        choker.tick(t0);
+        choker.set_interested(0, false);
+        assert!(choker.tick(t0 + secs(1)).is_empty());
+        let changes = choker.tick(t0 + RECHOKE_INTERVAL);
+        assert_eq!(sorted(changes), [(0, false), (1, true)]);
+    }
+
+    #[test]
+    fn free_slots_are_handed_out_between_rechokes() {
+        let t0 = Instant::now();
+        let mut choker = Choker::new(3, false);
+        interested_peers(&mut choker, 1, t0);
+        choker.tick(t0);
+        assert_eq!(unchoked(&choker), [0]);
+        let late = interested_peers(&mut choker, 1, t0 + secs(1));
+        assert_eq!(choker.tick(t0 + secs(2)), [(late[0], true)]);
+    }
+
+    // A peer that's sent nothing for a minute is left out of the regular
+    // slots, even with one going spare. It can still be unchoked
+    // optimistically, and gets back in as soon as it sends a block.
+    #[test]
+    fn snubbing_peers_only_get_the_optimistic_slot() {
+        let t0 = Instant::now();
+        let mut choker = Choker::new(3, false);
+        let peers = interested_peers(&mut choker, 3, t0);
+        let sender = peers[2];
+        for step in 0..=6 {
+            choker.record_download(sender, 1000, t0 + RECHOKE_INTERVAL * step);
+        }
+        choker.tick(t0);
+        assert_eq!(unchoked(&choker), [0, 1, 2]);
+        assert_eq!(choker.optimistic, Some(1));
+
+        // 0 and 1 are snubbing us, 0 is next in line for the optimistic slot
+        let changes = choker.tick(t0 + SNUB_TIMEOUT);
+        assert_eq!(changes, [(1, false)]);
+        assert_eq!(choker.optimistic, Some(0));
+        assert!(choker.tick(t0 + SNUB_TIMEOUT + secs(1)).is_empty());
+
+        choker.record_download(1, 100, t0 + SNUB_TIMEOUT + secs(2));
+        assert_eq!(choker.tick(t0 + SNUB_TIMEOUT + secs(3)), [(1, true)]);
+    }
+
+    // what comes in over a download connection counts for whoever we
+    // upload to at the same address
+    #[test]
+    fn downloads_are_credited_by_address() {
+        let shared = SharedChoker::new(Choker::new(2, false));
+        let here: IpAddr = [127, 0, 0, 1].into();
+        let there: IpAddr = [10, 0, 0, 1].into();
+        let (a, _) = shared.join(Some(here));
+        let (b, _) = shared.join(Some(there));
+        let (c, _) = shared.join(None);
+        shared.record_download(here, 1000);
+        shared.leave(b);
+        shared.record_download(there, 500);
+
+        let inner = shared.inner.lock().unwrap();
+        let downloaded = |key| inner.choker.peers.get(&key).map(|p| p.downloaded);
+        assert_eq!((downloaded(a), downloaded(b), downloaded(c)), (Some(1000), None, Some(0)));
+    }
+}
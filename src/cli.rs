@@ -1,14 +1,87 @@
 
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bittorrent_starter_rust::connection::Timeouts;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::picker::{DownloadMode, FilePriority};
+use bittorrent_starter_rust::queue::{self, Priority};
+use bittorrent_starter_rust::rpc;
+
+use crate::output::Format;
+use crate::progress::ProgressStyle;
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
 pub struct Cli {
+    /// Port to accept peer connections on, reported to the tracker
+    #[arg(long, global = true, default_value_t = bittorrent_starter_rust::listener::DEFAULT_PORT)]
+    pub port: u16,
+    /// Use this 20 byte peer id instead of a random one
+    #[arg(long, global = true, value_parser = PeerId::parse)]
+    pub peer_id: Option<PeerId>,
+    /// Seconds to wait for a peer to accept a connection
+    #[arg(long, global = true, default_value_t = 10)]
+    pub connect_timeout: u64,
+    /// Seconds to wait for a peer's handshake
+    #[arg(long, global = true, default_value_t = 10)]
+    pub handshake_timeout: u64,
+    /// Seconds a piece may go without a block arriving before we give up on the peer
+    #[arg(long, global = true, default_value_t = 30)]
+    pub request_timeout: u64,
+    /// Seconds a peer may stay completely silent before it's disconnected
+    #[arg(long, global = true, default_value_t = 180)]
+    pub idle_timeout: u64,
+    /// Cap on download speed across all peers, in KiB/s
+    #[arg(long, global = true)]
+    pub max_download_rate: Option<u64>,
+    /// Cap on upload speed across all peers, in KiB/s
+    #[arg(long, global = true)]
+    pub max_upload_rate: Option<u64>,
+    /// Find peers on the local network too (BEP 14), private torrents never do
+    #[arg(long, global = true)]
+    pub local_discovery: bool,
+    /// Seconds to wait for local peers once the tracker's have all failed
+    #[arg(long, global = true, default_value_t = 300)]
+    pub discovery_timeout: u64,
+    /// Log more, repeat for even more (-v info, -vv debug, -vvv trace). RUST_LOG overrides it
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Print each command's result as text or as a single JSON document
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    pub format: Format,
+    /// Write logs to stderr as JSON, one object per line
+    #[arg(long, global = true)]
+    pub log_json: bool,
+    /// Unix socket the daemon is controlled through
+    #[arg(long, global = true, default_value_os_t = rpc::default_socket())]
+    pub socket: PathBuf,
     #[command(subcommand)]
     pub command: Commands,
 }
 
+impl Cli {
+    // limits in bytes per second
+    pub fn rates(&self) -> (Option<u64>, Option<u64>) {
+        (
+            self.max_download_rate.map(|kib| kib * 1024),
+            self.max_upload_rate.map(|kib| kib * 1024),
+        )
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(self.connect_timeout),
+            handshake: Duration::from_secs(self.handshake_timeout),
+            request: Duration::from_secs(self.request_timeout),
+            idle: Duration::from_secs(self.idle_timeout),
+            discovery: Duration::from_secs(self.discovery_timeout),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Adds files to myapp
@@ -31,6 +104,113 @@ pub enum Commands {
         #[arg(short, long)]
         output:String,
         path:String,
-    }
-    
+        /// How to show progress while downloading
+        #[arg(long, value_enum, default_value_t = ProgressStyle::Bar)]
+        progress: ProgressStyle,
+        /// Have the running daemon download it, and wait for it to finish
+        #[arg(long)]
+        daemon: bool,
+        /// Only download files whose path in the torrent matches, can be given more than once
+        #[arg(long, value_name = "GLOB")]
+        only: Vec<glob::Pattern>,
+        /// normal, sequential to fetch pieces front to back, or streaming
+        #[arg(long, value_parser = parse_mode, default_value = "normal")]
+        mode: DownloadMode,
+    },
+    /// Write one of the torrent's files to stdout as it downloads, fetching
+    /// what's needed next first
+    Stream {
+        path: String,
+        /// Where the data is saved while it's streamed
+        #[arg(short, long)]
+        output: String,
+        /// Which file, counting from 0 in the order `info` lists them
+        #[arg(long, default_value_t = 0)]
+        file: usize,
+        /// How to show progress on stderr
+        #[arg(long, value_enum, default_value_t = ProgressStyle::Bar)]
+        progress: ProgressStyle,
+    },
+    /// Download whatever `data` is missing, then serve it to the torrent's peers
+    Seed {
+        path:String,
+        data:String,
+        /// How to show progress while downloading and seeding
+        #[arg(long, value_enum, default_value_t = ProgressStyle::Bar)]
+        progress: ProgressStyle,
+        /// Hand it to the running daemon to seed, instead of seeding from this process
+        #[arg(long)]
+        daemon: bool,
+    },
+    /// Run a session that keeps going in the background, controlled through --socket
+    Daemon {
+        /// Where the torrents and their progress are kept between runs
+        #[arg(long, default_value = ".bittorrent")]
+        state_dir: PathBuf,
+        /// Torrents allowed to download at once, 0 for no limit
+        #[arg(long, default_value_t = queue::DEFAULT_ACTIVE_DOWNLOADS)]
+        max_active_downloads: usize,
+        /// Finished torrents allowed to seed at once, 0 for no limit
+        #[arg(long, default_value_t = queue::DEFAULT_ACTIVE_SEEDS)]
+        max_active_seeds: usize,
+    },
+    /// Add a torrent to the running daemon
+    Add {
+        path: String,
+        /// Where the daemon saves the data
+        #[arg(short, long)]
+        output: String,
+        /// Add it without starting it
+        #[arg(long)]
+        paused: bool,
+        /// low, normal or high, higher priority torrents are started first
+        #[arg(long, value_parser = parse_priority, default_value = "normal")]
+        priority: Priority,
+        /// Only download files whose path in the torrent matches, can be given more than once
+        #[arg(long, value_name = "GLOB")]
+        only: Vec<glob::Pattern>,
+        /// normal, sequential to fetch pieces front to back, or streaming
+        #[arg(long, value_parser = parse_mode, default_value = "normal")]
+        mode: DownloadMode,
+    },
+    /// Remove a torrent from the daemon, its data stays on disk
+    Remove { info_hash: String },
+    /// Stop one of the daemon's torrents until it's resumed
+    Pause { info_hash: String },
+    Resume { info_hash: String },
+    /// The daemon's torrents, in queue order
+    List,
+    /// Transfer stats for one of the daemon's torrents
+    Stats { info_hash: String },
+    SetPriority {
+        info_hash: String,
+        #[arg(value_parser = parse_priority)]
+        priority: Priority,
+    },
+    /// skip, low, normal or high for one of a torrent's files, by its index in `files`
+    SetFilePriority {
+        info_hash: String,
+        file: usize,
+        #[arg(value_parser = parse_file_priority)]
+        priority: FilePriority,
+    },
+    /// The files in one of the daemon's torrents, with their priorities
+    Files { info_hash: String },
+    /// Print the daemon's events as they happen
+    Events,
+}
+
+fn parse_file_priority(s: &str) -> Result<FilePriority, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_owned()))
+        .map_err(|_| "expected skip, low, normal or high".to_owned())
+}
+
+fn parse_mode(s: &str) -> Result<DownloadMode, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_owned()))
+        .map_err(|_| "expected normal, sequential or streaming".to_owned())
+}
+
+fn parse_priority(s: &str) -> Result<Priority, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_owned()))
+        .map_err(|_| "expected low, normal or high".to_owned())
 }
@@ -0,0 +1,387 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::Framed;
+use tracing::debug;
+
+use crate::bandwidth::Bandwidth;
+use crate::bitfield::Bitfield;
+use crate::error::{Error, Result};
+use crate::peer_protocol::{Handshake, PeerMessage, PeerMessageCodec};
+use crate::stats::{PeerStats, Stats};
+use crate::torrent::Info;
+
+pub const BLOCK_SIZE: usize = 16384;
+// how many block requests we keep outstanding with a peer
+pub const PIPELINE_DEPTH: usize = 5;
+// we send a keep-alive when we've been quiet this long, peers commonly
+// drop connections after two minutes of silence
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(110);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    // how long a piece download may go without a block arriving, whether
+    // we're choked or the peer has just gone quiet
+    pub request: Duration,
+    // a peer that sends nothing at all, not even a keep-alive, for this
+    // long is disconnected
+    pub idle: Duration,
+    // with local discovery on and no other peers left, how long to wait
+    // for one to turn up on the network before giving up
+    pub discovery: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            request: Duration::from_secs(30),
+            idle: Duration::from_secs(180),
+            // everyone already there announces once in this long
+            discovery: crate::lsd::ANNOUNCE_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+// One peer connection after the handshake. Every message in either
+// direction goes through here so the choke and interest flags on both
+// sides, and the set of pieces the peer has, are always current no
+// matter what order the peer sends things in.
+pub struct PeerConnection {
+    peer_framer: Framed<TcpStream, PeerMessageCodec>,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    piece_count: usize,
+    // None until the peer sends a bitfield or a have, a peer with no
+    // pieces is allowed to send neither
+    pieces: Option<Bitfield>,
+    // requests waiting for an unchoke, and those sent but not answered
+    pending: VecDeque<BlockRequest>,
+    in_flight: Vec<BlockRequest>,
+    timeouts: Timeouts,
+    last_sent: Instant,
+    last_received: Instant,
+    // when a block last came in, or we started waiting for one
+    last_block: Instant,
+    bandwidth: Bandwidth,
+    stats: PeerStats,
+}
+
+impl PeerConnection {
+    pub fn new(stream: TcpStream, piece_count: usize) -> Self {
+        Self {
+            peer_framer: Framed::new(stream, PeerMessageCodec),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            piece_count,
+            pieces: None,
+            pending: VecDeque::new(),
+            in_flight: Vec::new(),
+            timeouts: Timeouts::default(),
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            last_block: Instant::now(),
+            bandwidth: Bandwidth::default(),
+            stats: Stats::default().peer(),
+        }
+    }
+
+    // rate limits for everything sent and received from here on
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = bandwidth;
+    }
+
+    // count this connection and its transfers towards `stats`
+    pub fn set_stats(&mut self, stats: &Stats) {
+        let mut peer = stats.peer();
+        peer.set_choked(self.peer_choking);
+        self.stats = peer;
+    }
+
+    // Connects and handshakes with a peer, neither step is allowed to
+    // take longer than its timeout.
+    pub async fn connect(
+        addr: &str,
+        handshake: &Handshake,
+        piece_count: usize,
+        timeouts: Timeouts,
+    ) -> Result<(Self, Handshake)> {
+        let mut stream = tokio::time::timeout(timeouts.connect, TcpStream::connect(addr))
+            .await
+            .map_err(|_| Error::Timeout("connect"))??;
+        let theirs = tokio::time::timeout(timeouts.handshake, handshake.perform_handshake(&mut stream))
+            .await
+            .map_err(|_| Error::Timeout("handshake"))??;
+
+        let mut conn = Self::new(stream, piece_count);
+        conn.timeouts = timeouts;
+        Ok((conn, theirs))
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_framer.get_ref().peer_addr().ok()
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    // Some(false) only once the peer has told us what it has and this
+    // piece isn't part of it
+    pub fn has_piece(&self, index: usize) -> Option<bool> {
+        self.pieces.as_ref().map(|p| p.get(index))
+    }
+
+    // Tells the peer whether we're interested, only sending anything if
+    // that has changed. `wanted` is the set of pieces we still need, a
+    // peer that hasn't said what it has yet is assumed to have them.
+    pub async fn update_interest(&mut self, wanted: &Bitfield) -> Result<()> {
+        let interested = match &self.pieces {
+            Some(pieces) => !pieces.intersection(wanted).none(),
+            None => !wanted.none(),
+        };
+        if interested != self.am_interested {
+            let msg = if interested {
+                PeerMessage::Interested
+            } else {
+                PeerMessage::NotInterested
+            };
+            self.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send(&mut self, msg: PeerMessage) -> Result<()> {
+        match msg {
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::Unchoke => self.am_choking = false,
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+        self.bandwidth.upload.wait().await;
+        let len = msg.wire_len();
+        let block_len = match &msg {
+            PeerMessage::Piece { block, .. } => block.len(),
+            _ => 0,
+        };
+        self.peer_framer.send(msg).await?;
+        self.bandwidth.upload.consume(len);
+        self.stats.uploaded(block_len);
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    // Next message from the peer, or None once it has hung up. Blocks we
+    // never asked for are dropped here, so a Piece coming out of this is
+    // always one of our requests. While waiting we keep the connection
+    // alive from our side and give up on a peer that has gone silent, or
+    // that has our requests and isn't answering them however chatty it is
+    // otherwise. Safe to use in a select!.
+    pub async fn recv(&mut self) -> Result<Option<PeerMessage>> {
+        loop {
+            self.throttle().await;
+            let keepalive_at = self.last_sent + KEEPALIVE_INTERVAL;
+            let idle_at = self.last_received + self.timeouts.idle;
+            let block_at = self.last_block + self.timeouts.request;
+            let awaiting_blocks = !self.pending.is_empty() || !self.in_flight.is_empty();
+            let msg = tokio::select! {
+                msg = self.peer_framer.next() => match msg {
+                    Some(msg) => msg?,
+                    None => return Ok(None),
+                },
+                _ = tokio::time::sleep_until(keepalive_at.into()) => {
+                    self.send(PeerMessage::KeepAlive).await?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(idle_at.into()) => {
+                    return Err(Error::Timeout("a message from the peer"));
+                }
+                _ = tokio::time::sleep_until(block_at.into()), if awaiting_blocks => {
+                    return Err(Error::Timeout("block"));
+                }
+            };
+            self.last_received = Instant::now();
+            self.bandwidth.download.consume(msg.wire_len());
+            match msg {
+                PeerMessage::Choke => {
+                    // anything in flight is discarded by the peer, ask
+                    // for it again first once we're unchoked
+                    self.peer_choking = true;
+                    self.stats.set_choked(true);
+                    for request in self.in_flight.drain(..).rev() {
+                        self.pending.push_front(request);
+                    }
+                }
+                PeerMessage::Unchoke => {
+                    self.peer_choking = false;
+                    self.stats.set_choked(false);
+                }
+                PeerMessage::Interested => self.peer_interested = true,
+                PeerMessage::NotInterested => self.peer_interested = false,
+                PeerMessage::Have(index) => {
+                    let index = index as usize;
+                    if index < self.piece_count {
+                        self.pieces
+                            .get_or_insert_with(|| Bitfield::new(self.piece_count))
+                            .set(index, true);
+                    }
+                }
+                PeerMessage::Bitfield(ref bitfield) => {
+                    let bitfield = Bitfield::from_bytes(bitfield, self.piece_count)
+                        .map_err(|e| Error::Protocol(e.to_string()))?;
+                    self.pieces = Some(bitfield);
+                }
+                PeerMessage::Piece { index, begin, ref block } => {
+                    let request = BlockRequest {
+                        index,
+                        begin,
+                        length: block.len() as u32,
+                    };
+                    // a block can still arrive after a choke moved its
+                    // request back to pending
+                    if let Some(pos) = self.in_flight.iter().position(|r| *r == request) {
+                        self.in_flight.swap_remove(pos);
+                    } else if let Some(pos) = self.pending.iter().position(|r| *r == request) {
+                        self.pending.remove(pos);
+                    } else {
+                        debug!(index, begin, len = block.len(), "dropping unrequested block");
+                        continue;
+                    }
+                    self.last_block = self.last_received;
+                    self.stats.downloaded(block.len());
+                }
+                _ => {}
+            }
+            return Ok(Some(msg));
+        }
+    }
+
+    // Over the download limit we stop reading and let the peer's sends
+    // back up in TCP. That's us holding things up, not the peer, so the
+    // wait doesn't count towards the peer going quiet.
+    async fn throttle(&mut self) {
+        let started = Instant::now();
+        self.bandwidth.download.wait().await;
+        let waited = started.elapsed();
+        self.last_received += waited;
+        self.last_block += waited;
+    }
+
+    // drops our requests for a piece we're giving up on
+    fn forget_piece(&mut self, index: usize) {
+        self.pending.retain(|r| r.index as usize != index);
+        self.in_flight.retain(|r| r.index as usize != index);
+    }
+
+    // send queued requests while the peer lets us
+    async fn fill_pipeline(&mut self) -> Result<()> {
+        while !self.peer_choking && self.in_flight.len() < PIPELINE_DEPTH {
+            let Some(request) = self.pending.pop_front() else {
+                break;
+            };
+            self.send(PeerMessage::Request {
+                index: request.index,
+                begin: request.begin,
+                length: request.length,
+            })
+            .await?;
+            self.in_flight.push(request);
+        }
+        Ok(())
+    }
+
+    // The piece's blocks in order, exactly as they came off the wire. They
+    // are left for the caller to hash and write rather than copied into
+    // one buffer.
+    pub async fn download_piece(&mut self, info: &Info, index: usize) -> Result<Vec<Bytes>> {
+        self.stats.piece_started();
+        let result = self.fetch_piece(info, index).await;
+        self.stats.piece_finished();
+        result
+    }
+
+    async fn fetch_piece(&mut self, info: &Info, index: usize) -> Result<Vec<Bytes>> {
+        let piece_len = info.piece_len(index);
+        let mut blocks = vec![Bytes::new(); piece_len.div_ceil(BLOCK_SIZE)];
+
+        // the clock starts now if nothing else was outstanding
+        if self.pending.is_empty() && self.in_flight.is_empty() {
+            self.last_block = Instant::now();
+        }
+        let mut begin = 0;
+        while begin < piece_len {
+            let length = (piece_len - begin).min(BLOCK_SIZE);
+            self.pending.push_back(BlockRequest {
+                index: index as u32,
+                begin: begin as u32,
+                length: length as u32,
+            });
+            begin += length;
+        }
+
+        if !self.am_interested {
+            self.send(PeerMessage::Interested).await?;
+        }
+
+        let mut left = piece_len;
+        while left > 0 {
+            if self.has_piece(index) == Some(false) {
+                self.forget_piece(index);
+                return Err(Error::Protocol(format!("peer does not have piece {}", index)));
+            }
+            self.fill_pipeline().await?;
+
+            // a stalled piece is abandoned so the caller can get it from
+            // someone else
+            let msg = match self.recv().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.forget_piece(index);
+                    return Err(e);
+                }
+            };
+            match msg {
+                // recv only lets through blocks we asked for, and we
+                // only ask on BLOCK_SIZE boundaries
+                Some(PeerMessage::Piece { index: i, begin, block }) if i as usize == index => {
+                    left -= block.len();
+                    blocks[begin as usize / BLOCK_SIZE] = block;
+                }
+                Some(PeerMessage::Choke) => debug!(requeued = self.pending.len(), "choked"),
+                Some(_) => {}
+                None => {
+                    return Err(Error::Protocol("peer hung up mid piece".to_owned()))
+                }
+            }
+        }
+        Ok(blocks)
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        Ok(self.peer_framer.into_inner().shutdown().await?)
+    }
+}
@@ -0,0 +1,252 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use bittorrent_starter_rust::rpc::{self, Client, FileStatus, SessionStatus, TorrentStatus};
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::{
+    DownloadMode, Error, Event, FilePriority, Priority, QueueLimits, Result, Session, SessionConfig, TorrentStats,
+};
+use serde_json::json;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::output::{self, Format};
+use crate::progress::{self, ProgressStyle};
+
+// Runs `session` until we're told to stop with ctrl-c or SIGTERM. The
+// session saves itself as it goes, on the way out it's saved once more
+// for the pieces it was holding back, and the socket file goes.
+pub async fn run(config: SessionConfig, socket: &Path, format: Format) -> Result<()> {
+    let state_dir = config.state_dir.clone().unwrap_or_default();
+    // bound first, a second daemon mustn't get as far as restoring the
+    // torrents the first one is already running
+    let listener = rpc::bind(socket).await?;
+    let session = match Session::new(config).await {
+        Ok(session) => Arc::new(session),
+        Err(e) => {
+            let _ = std::fs::remove_file(socket);
+            return Err(e);
+        }
+    };
+    let started = output::DaemonStarted {
+        socket: socket.display().to_string(),
+        state_dir: state_dir.display().to_string(),
+        port: session.port(),
+        torrents: session.torrents().len(),
+    };
+    output::print(format, &started);
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let result = tokio::select! {
+        result = rpc::serve(session.clone(), listener) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+        _ = terminate.recv() => Ok(()),
+    };
+    let saved = session.save().await;
+    let _ = std::fs::remove_file(socket);
+    result.and(saved)
+}
+
+pub fn queue_limits(downloads: usize, seeds: usize) -> QueueLimits {
+    let limit = |n| (n > 0).then_some(n);
+    QueueLimits {
+        downloads: limit(downloads),
+        seeds: limit(seeds),
+    }
+}
+
+// the daemon may well run somewhere else in the filesystem
+fn absolute(path: &str) -> Result<PathBuf> {
+    Ok(std::path::absolute(path)?)
+}
+
+// what to add and how, as the add call takes it
+pub struct Add<'a> {
+    pub path: &'a str,
+    pub output: &'a str,
+    pub paused: bool,
+    pub priority: Priority,
+    pub only: &'a [glob::Pattern],
+    pub mode: DownloadMode,
+}
+
+async fn add(client: &mut Client, add: Add<'_>) -> Result<TorrentStatus> {
+    let only: Vec<_> = add.only.iter().map(glob::Pattern::as_str).collect();
+    let params = json!({
+        "torrent": absolute(add.path)?,
+        "save_path": absolute(add.output)?,
+        "paused": add.paused,
+        "priority": add.priority,
+        "only": only,
+        "mode": add.mode,
+    });
+    client.call("add", params).await
+}
+
+pub async fn add_torrent(socket: &Path, format: Format, options: Add<'_>) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    let torrent = add(&mut client, options).await?;
+    output::print(format, &output::TorrentAction { action: "added", torrent });
+    Ok(())
+}
+
+// remove, pause, resume and anything else that takes just an info hash
+pub async fn torrent_call(socket: &Path, format: Format, method: &str, action: &'static str, info_hash: &str) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    let torrent = client.call(method, json!({ "info_hash": info_hash })).await?;
+    output::print(format, &output::TorrentAction { action, torrent });
+    Ok(())
+}
+
+pub async fn set_priority(socket: &Path, format: Format, info_hash: &str, priority: Priority) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    let params = json!({ "info_hash": info_hash, "priority": priority });
+    let torrent = client.call("set_priority", params).await?;
+    output::print(format, &output::TorrentAction { action: "updated", torrent });
+    Ok(())
+}
+
+pub async fn set_file_priority(
+    socket: &Path,
+    format: Format,
+    info_hash: &str,
+    file: usize,
+    priority: FilePriority,
+) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    let params = json!({ "info_hash": info_hash, "file": file, "priority": priority });
+    let torrent = client.call("set_file_priority", params).await?;
+    output::print(format, &output::TorrentAction { action: "updated", torrent });
+    Ok(())
+}
+
+pub async fn list(socket: &Path, format: Format) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    let torrents = client.call("list", json!({})).await?;
+    output::print(format, &output::TorrentList { torrents });
+    Ok(())
+}
+
+pub async fn files(socket: &Path, format: Format, info_hash: &str) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    let files: Vec<FileStatus> = client.call("files", json!({ "info_hash": info_hash })).await?;
+    output::print(format, &output::FileList { files });
+    Ok(())
+}
+
+pub async fn stats(socket: &Path, format: Format, info_hash: &str) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    let stats = client.call("stats", json!({ "info_hash": info_hash })).await?;
+    output::print(format, &output::Stats(stats));
+    Ok(())
+}
+
+// one line, or one JSON document, per event until the daemon goes away
+pub async fn events(socket: &Path, format: Format) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    client.subscribe().await?;
+    loop {
+        let event = client.next_event().await?;
+        output::print(format, &output::EventLine(event));
+    }
+}
+
+// `download --daemon`: the daemon does the work, we report on it until
+// it's done
+pub async fn download(
+    socket: &Path,
+    format: Format,
+    path: String,
+    output: String,
+    only: &[glob::Pattern],
+    mode: DownloadMode,
+    progress: ProgressStyle,
+) -> Result<()> {
+    let started = Instant::now();
+    let length = Torrent::load_torrent(path.clone())?.info.length();
+    let mut client = Client::connect(socket).await?;
+    // subscribed first so the torrent can't finish unseen
+    client.subscribe().await?;
+    let options = Add {
+        path: &path,
+        output: &output,
+        paused: false,
+        priority: Priority::Normal,
+        only,
+        mode,
+    };
+    let torrent = add(&mut client, options).await?;
+    let info_hash = torrent.info_hash.clone();
+    let params = json!({ "info_hash": &info_hash });
+
+    let mut reporter = progress::Reporter::new(progress);
+    let mut tick = tokio::time::interval(progress::INTERVAL);
+    // already complete means no Completed event is coming
+    let mut complete = torrent.pieces_done == torrent.piece_count;
+    while !complete {
+        tokio::select! {
+            event = client.next_event() => {
+                let event = event?;
+                if event.info_hash != info_hash {
+                    continue;
+                }
+                match event.event {
+                    Event::PieceCompleted(index) if format == Format::Text => {
+                        reporter.clear();
+                        println!("Piece {} downloaded to {}.", index, &output);
+                    }
+                    Event::Completed => complete = true,
+                    Event::Error(e) => return Err(Error::Stopped(e)),
+                    _ => {}
+                }
+            }
+            _ = tick.tick() => {
+                let stats: TorrentStats = client.call("stats", params.clone()).await?;
+                reporter.report(&stats);
+                // events are dropped if we fall behind, the stats aren't
+                if let Some(e) = stats.error {
+                    return Err(Error::Stopped(e));
+                }
+                complete = stats.pieces_done == stats.piece_count;
+            }
+        }
+    }
+    let stats: TorrentStats = client.call("stats", params).await?;
+    reporter.finish(&stats);
+
+    let summary = output::DownloadSummary {
+        torrent: path,
+        name: stats.name,
+        info_hash: stats.info_hash,
+        output,
+        length,
+        piece_count: stats.piece_count,
+        downloaded: stats.transfer.downloaded,
+        elapsed_secs: started.elapsed().as_secs_f64(),
+    };
+    output::print(format, &summary);
+    Ok(())
+}
+
+// `seed --daemon`: hands the torrent over and leaves the daemon to it
+pub async fn seed(socket: &Path, format: Format, path: String, data: String) -> Result<()> {
+    let mut client = Client::connect(socket).await?;
+    let options = Add {
+        path: &path,
+        output: &data,
+        paused: false,
+        priority: Priority::Normal,
+        only: &[],
+        mode: DownloadMode::Normal,
+    };
+    let torrent = add(&mut client, options).await?;
+    let session: SessionStatus = client.call("session", json!({})).await?;
+    let seeding = output::Seeding {
+        name: torrent.name,
+        info_hash: torrent.info_hash,
+        path: data,
+        port: session.port,
+    };
+    output::print(format, &seeding);
+    Ok(())
+}
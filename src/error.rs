@@ -0,0 +1,40 @@
+use crate::peer_protocol::HandshakeError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid bencode: {0}")]
+    Bencode(String),
+    #[error("http request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("tracker error: {0}")]
+    Tracker(String),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("timed out waiting for {0}")]
+    Timeout(&'static str),
+    #[error("piece {index} failed hash check")]
+    HashMismatch { index: usize },
+    // a torrent stopped before completing, the reason is whatever error
+    // the torrent task hit
+    #[error("torrent stopped: {0}")]
+    Stopped(String),
+    // asked for something that doesn't fit the torrent, like a file it
+    // doesn't have
+    #[error("{0}")]
+    Usage(String),
+    // an error the daemon answered a call with
+    #[error("daemon: {0}")]
+    Rpc(String),
+}
+
+impl From<serde_bencode::Error> for Error {
+    fn from(e: serde_bencode::Error) -> Self {
+        Error::Bencode(e.to_string())
+    }
+}
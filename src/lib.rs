@@ -0,0 +1,34 @@
+//! BitTorrent client library, the `your_bittorrent.sh` CLI is a thin
+//! wrapper around it. Embedders normally only need a `Session`: add
+//! torrents to it and drive them through their `TorrentHandle`.
+
+pub mod backoff;
+pub mod bandwidth;
+pub mod bencode;
+pub mod bitfield;
+pub mod choker;
+pub mod connection;
+pub mod error;
+pub mod listener;
+pub mod lsd;
+pub mod merkle;
+pub mod peer_id;
+pub mod peer_protocol;
+pub mod picker;
+pub mod queue;
+pub mod reader;
+pub mod resume;
+pub mod rpc;
+pub mod seed;
+pub mod session;
+pub mod stats;
+pub mod storage;
+pub mod torrent;
+pub mod tracker;
+pub mod webseed;
+
+pub use error::{Error, Result};
+pub use picker::{DownloadMode, FilePriority};
+pub use queue::{Priority, QueueLimits};
+pub use reader::FileReader;
+pub use session::{AddOptions, Event, Progress, Session, SessionConfig, State, TorrentHandle, TorrentStats};
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::peer_protocol::Handshake;
+
+pub const DEFAULT_PORT: u16 = 6881;
+
+// An accepted connection whose handshake has been read but not answered,
+// the torrent that picks it up replies with its own handshake.
+pub type Incoming = (TcpStream, Handshake);
+
+// Maps info hashes to the torrents willing to take connections for them.
+#[derive(Clone, Default)]
+pub struct Router {
+    torrents: Arc<Mutex<HashMap<[u8; 20], mpsc::Sender<Incoming>>>>,
+}
+
+impl Router {
+    // one torrent can go by several info hashes, connections for any of
+    // them end up on the same receiver
+    pub fn register(&self, info_hashes: &[[u8; 20]]) -> mpsc::Receiver<Incoming> {
+        let (tx, rx) = mpsc::channel(16);
+        let mut torrents = self.torrents.lock().unwrap();
+        for info_hash in info_hashes {
+            torrents.insert(*info_hash, tx.clone());
+        }
+        rx
+    }
+
+    pub fn unregister(&self, info_hashes: &[[u8; 20]]) {
+        let mut torrents = self.torrents.lock().unwrap();
+        for info_hash in info_hashes {
+            torrents.remove(info_hash);
+        }
+    }
+
+    fn route(&self, info_hash: &[u8; 20]) -> Option<mpsc::Sender<Incoming>> {
+        self.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+}
+
+pub struct Listener {
+    listener: TcpListener,
+    router: Router,
+}
+
+impl Listener {
+    pub async fn bind(port: u16) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+        Ok(Self {
+            listener,
+            router: Router::default(),
+        })
+    }
+
+    // the port actually bound, differs from the requested one for port 0
+    pub fn port(&self) -> u16 {
+        self.listener.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    pub fn router(&self) -> Router {
+        self.router.clone()
+    }
+
+    // peers that connect but never send a handshake are dropped after
+    // `handshake_timeout`
+    pub async fn run(self, handshake_timeout: Duration) {
+        loop {
+            let (mut stream, addr) = match self.listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("failed to accept: {}", e);
+                    continue;
+                }
+            };
+
+            // read the handshake off the accept loop so a slow peer
+            // can't hold up everyone else
+            let router = self.router.clone();
+            tokio::spawn(async move {
+                let handshake = match tokio::time::timeout(handshake_timeout, Handshake::read(&mut stream)).await {
+                    Ok(Ok(h)) => h,
+                    Ok(Err(e)) => {
+                        debug!(%addr, "bad handshake: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!(%addr, "no handshake, dropping");
+                        return;
+                    }
+                };
+                // a torrent that isn't keeping up with its peers gets no
+                // more, dropping the stream closes the connection
+                match router.route(handshake.info_hash()) {
+                    Some(torrent) => {
+                        debug!(%addr, info_hash = %hex::encode(handshake.info_hash()), "incoming peer");
+                        if let Err(e) = torrent.try_send((stream, handshake)) {
+                            debug!(%addr, "torrent not taking peers: {}", e);
+                        }
+                    }
+                    None => debug!(
+                        %addr,
+                        info_hash = %hex::encode(handshake.info_hash()),
+                        "rejecting peer for unknown info hash"
+                    ),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_id::PeerId;
+    use tokio::io::AsyncReadExt;
+
+    const A: [u8; 20] = [0xab; 20];
+    const B: [u8; 20] = [0x01; 20];
+    const UNKNOWN: [u8; 20] = [0x77; 20];
+
+    // a listener on a loopback port with `info_hashes` registered
+    async fn listen(info_hashes: &[[u8; 20]], handshake_timeout: Duration) -> (u16, mpsc::Receiver<Incoming>) {
+        let listener = Listener::bind(0).await.unwrap();
+        let port = listener.port();
+        let incoming = listener.router().register(info_hashes);
+        tokio::spawn(listener.run(handshake_timeout));
+        (port, incoming)
+    }
+
+    async fn connect(port: u16, info_hash: Option<[u8; 20]>) -> TcpStream {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        if let Some(info_hash) = info_hash {
+            Handshake::new(info_hash, &PeerId::generate()).write(&mut stream).await.unwrap();
+        }
+        stream
+    }
+
+    // true once the listener hangs up without sending anything
+    async fn closed(mut stream: TcpStream) -> bool {
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+        matches!(read, Ok(Ok(0)) | Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn registered_hashes_are_routed() {
+        let (port, mut incoming) = listen(&[A, B], Duration::from_secs(5)).await;
+        for info_hash in [A, B] {
+            let _stream = connect(port, Some(info_hash)).await;
+            let (_, handshake) = tokio::time::timeout(Duration::from_secs(5), incoming.recv())
+                .await
+                .expect("routed")
+                .unwrap();
+            assert_eq!(handshake.info_hash(), &info_hash);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_hashes_are_closed() {
+        let (port, mut incoming) = listen(&[A], Duration::from_secs(5)).await;
+        assert!(closed(connect(port, Some(UNKNOWN)).await).await);
+        assert!(incoming.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unregistered_hashes_are_closed() {
+        let listener = Listener::bind(0).await.unwrap();
+        let port = listener.port();
+        let router = listener.router();
+        let _incoming = router.register(&[A, B]);
+        router.unregister(&[A, B]);
+        tokio::spawn(listener.run(Duration::from_secs(5)));
+        assert!(closed(connect(port, Some(A)).await).await);
+    }
+
+    #[tokio::test]
+    async fn silent_peers_time_out() {
+        let timeout = Duration::from_millis(200);
+        let (port, mut incoming) = listen(&[A], timeout).await;
+        let started = tokio::time::Instant::now();
+        assert!(closed(connect(port, None).await).await);
+        assert!(started.elapsed() >= timeout, "dropped after {:?}", started.elapsed());
+        assert!(incoming.try_recv().is_err());
+    }
+
+    // a torrent that stops reading its queue doesn't hold up the listener,
+    // peers beyond what it can queue are closed
+    #[tokio::test]
+    async fn full_queues_close_peers() {
+        let (port, mut incoming) = listen(&[A], Duration::from_secs(5)).await;
+        let mut queued = Vec::new();
+        for _ in 0..16 {
+            queued.push(connect(port, Some(A)).await);
+        }
+        // let the queue fill before the one that doesn't fit
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(closed(connect(port, Some(A)).await).await);
+        let mut routed = 0;
+        while incoming.try_recv().is_ok() {
+            routed += 1;
+        }
+        assert_eq!(routed, 16);
+    }
+}
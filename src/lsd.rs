@@ -0,0 +1,201 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::trace;
+
+// BEP 14's multicast group, every client on the network listens on it
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const PORT: u16 = 6771;
+// how often each torrent is announced again, BEP 14 asks for no more
+// than once a minute
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+// one datagram mustn't outgrow a typical MTU, 40 hex digits a hash plus
+// the header name leaves room for this many
+const MAX_HASHES_PER_ANNOUNCE: usize = 20;
+
+// A BT-SEARCH that wasn't ours: the torrents the sender has and where it
+// takes connections for them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub info_hashes: Vec<[u8; 20]>,
+    pub peer: SocketAddr,
+}
+
+// BEP 14 local service discovery. Announces and listens on the same
+// socket, the cookie in every announce is how we tell our own apart when
+// the group loops them back to us.
+pub struct LocalDiscovery {
+    socket: UdpSocket,
+    cookie: String,
+    // the port peers connect to, not the multicast one
+    port: u16,
+}
+
+impl LocalDiscovery {
+    // Joins the group on every interface. The port is shared, other
+    // clients on this machine want the announces too.
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+        socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+        let socket = UdpSocket::from_std(socket.into())?;
+
+        // seeded from the OS like the peer id
+        let cookie = format!("{:016x}", RandomState::new().build_hasher().finish());
+        Ok(Self { socket, cookie, port })
+    }
+
+    pub async fn announce(&self, info_hashes: &[[u8; 20]]) -> io::Result<()> {
+        for chunk in info_hashes.chunks(MAX_HASHES_PER_ANNOUNCE) {
+            let msg = format_announce(self.port, chunk, &self.cookie);
+            self.socket.send_to(msg.as_bytes(), (GROUP, PORT)).await?;
+        }
+        Ok(())
+    }
+
+    // the next announce from someone else, anything that isn't a
+    // BT-SEARCH is skipped
+    pub async fn recv(&self) -> io::Result<Announce> {
+        let mut buf = [0; 1500];
+        loop {
+            let (n, from) = self.socket.recv_from(&mut buf).await?;
+            let Some(search) = Search::parse(&buf[..n]) else {
+                trace!(%from, "ignoring datagram that isn't a BT-SEARCH");
+                continue;
+            };
+            if search.cookie == Some(self.cookie.as_str()) || search.info_hashes.is_empty() {
+                continue;
+            }
+            return Ok(Announce {
+                info_hashes: search.info_hashes,
+                peer: SocketAddr::new(from.ip(), search.port),
+            });
+        }
+    }
+}
+
+fn format_announce(port: u16, info_hashes: &[[u8; 20]], cookie: &str) -> String {
+    let mut msg = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\n", GROUP, PORT, port);
+    for info_hash in info_hashes {
+        msg.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+    }
+    msg.push_str(&format!("cookie: {}\r\n\r\n\r\n", cookie));
+    msg
+}
+
+// what we care about in a BT-SEARCH
+struct Search<'a> {
+    port: u16,
+    info_hashes: Vec<[u8; 20]>,
+    cookie: Option<&'a str>,
+}
+
+impl<'a> Search<'a> {
+    // header names are case-insensitive and hashes that don't parse are
+    // dropped, no port is no use to anyone
+    fn parse(msg: &'a [u8]) -> Option<Self> {
+        let msg = std::str::from_utf8(msg).ok()?;
+        let mut lines = msg.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let (mut port, mut info_hashes, mut cookie) = (None, Vec::new(), None);
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    let mut info_hash = [0; 20];
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value),
+                _ => {}
+            }
+        }
+        Some(Self {
+            port: port.filter(|&p| p != 0)?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: [u8; 20] = [0xab; 20];
+    const B: [u8; 20] = [0x01; 20];
+
+    #[test]
+    fn announce_parses_back() {
+        let msg = format_announce(6881, &[A, B], "c00k1e");
+        assert!(msg.starts_with("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert!(msg.ends_with("\r\n\r\n\r\n"));
+        let search = Search::parse(msg.as_bytes()).unwrap();
+        assert_eq!(search.port, 6881);
+        assert_eq!(search.info_hashes, [A, B]);
+        assert_eq!(search.cookie, Some("c00k1e"));
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive() {
+        let msg = format!("BT-SEARCH * HTTP/1.1\r\nPORT: 7000\r\nINFOHASH:{}\r\nCookie:  x \r\n\r\n", hex::encode(A));
+        let search = Search::parse(msg.as_bytes()).unwrap();
+        assert_eq!((search.port, search.info_hashes, search.cookie), (7000, vec![A], Some("x")));
+    }
+
+    #[test]
+    fn bad_info_hashes_are_dropped() {
+        let msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nPort: 7000\r\nInfohash: {}\r\nInfohash: abcd\r\nInfohash: {}zz\r\nInfohash: {}\r\n\r\n",
+            &hex::encode(A)[..38],
+            &hex::encode(A)[..38],
+            hex::encode(B)
+        );
+        assert_eq!(Search::parse(msg.as_bytes()).unwrap().info_hashes, [B]);
+        // anything after the blank line isn't a header
+        let msg = format!("BT-SEARCH * HTTP/1.1\r\nPort: 7000\r\n\r\nInfohash: {}\r\n", hex::encode(A));
+        assert!(Search::parse(msg.as_bytes()).unwrap().info_hashes.is_empty());
+    }
+
+    #[test]
+    fn search_needs_a_port() {
+        let hash = hex::encode(A);
+        for msg in [
+            format!("BT-SEARCH * HTTP/1.1\r\nInfohash: {}\r\n\r\n", hash),
+            format!("BT-SEARCH * HTTP/1.1\r\nPort: 0\r\nInfohash: {}\r\n\r\n", hash),
+            format!("BT-SEARCH * HTTP/1.1\r\nPort: 70000\r\nInfohash: {}\r\n\r\n", hash),
+            format!("M-SEARCH * HTTP/1.1\r\nPort: 7000\r\nInfohash: {}\r\n\r\n", hash),
+        ] {
+            assert!(Search::parse(msg.as_bytes()).is_none(), "{:?}", msg);
+        }
+        assert!(Search::parse(&[0xff, 0xfe]).is_none());
+    }
+
+    // both ends share the group, each only hears the other
+    #[tokio::test]
+    async fn own_announces_are_filtered_out() {
+        let ours = LocalDiscovery::bind(7001).unwrap();
+        let theirs = LocalDiscovery::bind(7002).unwrap();
+        ours.announce(&[A]).await.unwrap();
+
+        let heard = tokio::time::timeout(Duration::from_secs(5), theirs.recv()).await.unwrap().unwrap();
+        assert_eq!((heard.info_hashes, heard.peer.port()), (vec![A], 7001));
+        assert!(tokio::time::timeout(Duration::from_millis(300), ours.recv()).await.is_err());
+    }
+}
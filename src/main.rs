@@ -1,57 +1,142 @@
 
 //use hex::encode;
+use bittorrent_starter_rust::{
+    bandwidth, bencode, connection, peer_id, peer_protocol, picker, torrent, tracker, AddOptions, DownloadMode,
+    Error, Event, FilePriority, Result, Session, SessionConfig,
+};
 use clap::Parser;
+use std::io::IsTerminal;
 use tokio::io::AsyncWriteExt;
-use sha1::{self, Digest};
+use tracing::{debug, info};
+use tracing_subscriber::EnvFilter;
+use tokio::sync::broadcast::error::RecvError;
 
-mod bencode;
 mod cli;
-mod peer_protocol;
-mod torrent;
+mod daemon;
+mod output;
+mod progress;
+
+// sysexits.h codes so scripts can tell a bad torrent from a dead peer
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Bencode(_) | Error::HashMismatch { .. } => 65, // EX_DATAERR
+        Error::Http(_) | Error::Tracker(_) => 69,             // EX_UNAVAILABLE
+        Error::Io(_) => 74,                                   // EX_IOERR
+        Error::Handshake(_) | Error::Protocol(_) => 76,       // EX_PROTOCOL
+        Error::Timeout(_) => 75,                              // EX_TEMPFAIL
+        Error::Usage(_) => 64,                                // EX_USAGE
+        Error::Stopped(_) | Error::Rpc(_) => 1,
+    }
+}
 
 // Usage: your_bittorrent.sh decode "<encoded_value>"
 #[tokio::main]
 async fn main() {
     let cmdline = cli::Cli::parse();
+    init_logging(cmdline.verbose, cmdline.log_json);
+    let format = cmdline.format;
+    if let Err(e) = run(cmdline).await {
+        let failure = output::Failure {
+            error: e.to_string(),
+            exit_code: exit_code(&e),
+        };
+        match format {
+            output::Format::Text => eprint!("{}", failure),
+            output::Format::Json => output::print(format, &failure),
+        }
+        std::process::exit(failure.exit_code);
+    }
+}
+
+// Quiet unless asked: warnings only, -v and up turn on our own logs but
+// leave the http stack at warn. RUST_LOG takes precedence when it's set.
+fn init_logging(verbose: u8, json: bool) {
+    let level = match verbose {
+        0 => "warn",
+        1 => "warn,bittorrent_starter_rust=info",
+        2 => "warn,bittorrent_starter_rust=debug",
+        _ => "warn,bittorrent_starter_rust=trace",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    if json {
+        logs.json().init();
+    } else {
+        logs.init();
+    }
+}
+
+// what the commands that run a session start from, all of it from the
+// global options
+fn session_config(cmdline: &cli::Cli, peer_id: peer_id::PeerId) -> SessionConfig {
+    let (max_download_rate, max_upload_rate) = cmdline.rates();
+    SessionConfig {
+        peer_id,
+        port: cmdline.port,
+        timeouts: cmdline.timeouts(),
+        max_download_rate,
+        max_upload_rate,
+        local_discovery: cmdline.local_discovery,
+        ..Default::default()
+    }
+}
+
+async fn run(cmdline: cli::Cli) -> Result<()> {
+    let peer_id = cmdline.peer_id.unwrap_or_else(peer_id::PeerId::generate);
+    let timeouts = cmdline.timeouts();
+    let config = session_config(&cmdline, peer_id);
+    let format = cmdline.format;
+    let text = format == output::Format::Text;
+    let socket = cmdline.socket;
     match cmdline.command {
         cli::Commands::Decode { encoded_value } => {
-            let decoded_value = bencode::decode_bencoded_value(&encoded_value);
-            println!("{}", decoded_value.to_string());
+            let decoded_value = bencode::decode_bencoded_value(&encoded_value)?;
+            println!("{}", decoded_value);
         }
         cli::Commands::Info { path } => {
-            let t = torrent::Torrent::load_torrent(path);
-            let h = hex::encode(t.get_info_hash());
-            println!("Tracker URL: {}\nLength: {}", t.announce, t.info.length);
-            println!("Info Hash: {}", h);
-            println!("Piece Length: {}", t.info.plen);
-            let pieces = t.info.pieces;
-            println!("Piece Hashes:");
-            for chunk in pieces.chunks(20) {
-                let h = hex::encode(chunk);
-                println!("{}", h);
-            }
+            let t = torrent::Torrent::load_torrent(path)?;
+            output::print(format, &output::TorrentInfo::new(&t));
         }
         cli::Commands::Peers { path } => {
-            let t = torrent::Torrent::load_torrent(path);
-            let tracker = t.request_tracker("00112233445566778899".to_owned()).await;
-            for peer in tracker.into_iter() {
-                println!("Peer: {}", peer.ip);
+            let t = torrent::Torrent::load_torrent(path)?;
+            let tracker = tracker::request_tracker(&t, &peer_id, cmdline.port, t.info.length()).await?;
+            let mut list = output::PeerList {
+                seeders: tracker.complete,
+                leechers: tracker.incomplete,
+                interval: tracker.interval,
+                peers: Vec::new(),
+            };
+            for peer in tracker {
+                list.peers.push(output::PeerInfo {
+                    client: peer.peer_id.as_ref().and_then(peer_id::client_name),
+                    peer_id: peer.peer_id.map(hex::encode),
+                    addr: peer.ip,
+                });
             }
+            output::print(format, &list);
         }
         cli::Commands::Handshake { path, ip_and_port } => {
-            eprintln!(
-                " torrent file='{}' , peer = {} on port {} ",
-                path, ip_and_port[0], ip_and_port[1]
-            );
-            let t = torrent::Torrent::load_torrent(path);
+            debug!(torrent = %path, peer = %ip_and_port.join(":"), "handshake");
+            let t = torrent::Torrent::load_torrent(path)?;
             let handshake =
-                peer_protocol::Handshake::new(t.get_info_hash(), "00112233445566778899");
-            let mut stream =
-                tokio::net::TcpStream::connect(format!("{}:{}", ip_and_port[0], ip_and_port[1]))
-                    .await
-                    .expect("failed to connect");
-            let h = handshake.perform_handshake(&mut stream).await;
-            println!("Peer ID: {}", hex::encode(&h.peer_id));
+                peer_protocol::Handshake::new(t.get_info_hash(), &peer_id).with_v2(t.info.is_v2());
+            let addr = ip_and_port.join(":");
+            let (_, h) =
+                connection::PeerConnection::connect(&addr, &handshake, t.info.piece_count(), timeouts).await?;
+            let result = output::HandshakeResult {
+                addr,
+                peer_id: hex::encode(h.peer_id),
+                client: peer_id::client_name(&h.peer_id),
+                capabilities: h.capabilities(),
+            };
+            if let Some(client) = &result.client {
+                info!("client: {}", client);
+            }
+            info!("capabilities: {:?}", result.capabilities);
+            output::print(format, &result);
         }
 
         cli::Commands::DownloadPiece {
@@ -59,101 +144,236 @@ async fn main() {
             path,
             index,
         } => {
-            println!("Downloading piece {} of {} to {}", index, path, output);
-            let t = torrent::Torrent::load_torrent(path);
+            if text {
+                println!("Downloading piece {} of {} to {}", index, path, output);
+            }
+            let t = torrent::Torrent::load_torrent(path)?;
+            if index >= t.info.piece_count() {
+                return Err(Error::Usage(format!("no piece {}, the torrent has {}", index, t.info.piece_count())));
+            }
             let handshake =
-                peer_protocol::Handshake::new(t.get_info_hash(), "00112233445566778899");
+                peer_protocol::Handshake::new(t.get_info_hash(), &peer_id).with_v2(t.info.is_v2());
 
             //use first peer
-            let peer = t
-                .request_tracker("00112233445566778899".to_owned())
-                .await
+            let peer = tracker::request_tracker(&t, &peer_id, cmdline.port, t.info.length())
+                .await?
                 .into_iter()
                 .next()
-                .expect("no peers");
-            eprintln!("connecting to peer: {}", peer.ip);
+                .ok_or_else(|| Error::Tracker("no peers".to_owned()))?;
+            info!("connecting to peer: {}", peer.ip);
 
-            let mut stream = tokio::net::TcpStream::connect(peer.ip)
-                .await
-                .expect("failed to connect");
+            let (mut conn, h) =
+                connection::PeerConnection::connect(&peer.ip, &handshake, t.info.piece_count(), timeouts).await?;
+            info!(peer_id = %hex::encode(h.peer_id), "connected");
+            conn.set_bandwidth(bandwidth::Bandwidth::new(config.max_download_rate, config.max_upload_rate));
 
-            let h = handshake.perform_handshake(&mut stream).await;
-            eprintln!("Peer ID: {}", hex::encode(&h.peer_id));
-
-            eprintln!("starting peer message protocol");
+            debug!("starting peer message protocol");
                 //open the "output" file for writing
             let mut output_file = tokio::fs::OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&output)
-            .await
-            .expect("failed to open file");
-            let piece = peer_protocol::download_piece(&t, &mut stream, index).await;
-
-            output_file.write_all(&piece).await.expect("failed to write to file");
+            .await?;
+            for block in conn.download_piece(&t.info, index).await? {
+                output_file.write_all(&block).await?;
+            }
 
-            println!("Piece {} downloaded to {}.", index, &output);
+            let piece = output::PieceDownload {
+                index,
+                length: t.info.piece_len(index),
+                output,
+                peer: peer.ip,
+            };
+            output::print(format, &piece);
         },
-        cli::Commands::Download { output, path } => {
-            println!("Downloading {} to {}", path, output);
-            let t = torrent::Torrent::load_torrent(path);
-            let handshake =
-                peer_protocol::Handshake::new(t.get_info_hash(), "00112233445566778899");
-
-            //use first peer
-            let peer = t
-                .request_tracker("00112233445566778899".to_owned())
-                .await
-                .into_iter()
-                .next()
-                .expect("no peers");
-            eprintln!("connecting to peer: {}", peer.ip);
-
-
-            println!("File: {}", t.info.name);
-            println!("length: {}", t.info.length);
-            println!("piece length: {}", t.info.plen);
-
-            let mut index = 0;
-            for chunk in t.info.pieces.chunks(20) {
-                let check = hex::encode(chunk);
-
-                let mut tokio_stream = tokio::net::TcpStream::connect(&peer.ip)
-                .await
-                .expect("failed to connect");
-
-                let h = handshake.perform_handshake(&mut tokio_stream).await;
-                eprintln!("Peer ID: {}", hex::encode(&h.peer_id));
-
-                let piece = peer_protocol::download_piece(&t, &mut tokio_stream, index).await;
-                
-                //calculate the piece hash
-                let piece_hash = sha1::Sha1::digest(&piece);
-                let piece_hash_hex = hex::encode(piece_hash);
-                //eprintln!("{} == {}", piece_hash_hex , check);
-                assert_eq!(piece_hash_hex, check);
+        cli::Commands::Download {
+            output,
+            path,
+            progress,
+            daemon,
+            only,
+            mode,
+        } => {
+            let started = std::time::Instant::now();
+            let t = torrent::Torrent::load_torrent(path.clone())?;
+            if text {
+                println!("Downloading {} to {}", path, output);
+                println!("File: {}", t.info.name);
+                println!("length: {}", t.info.length());
+                println!("piece length: {}", t.info.plen);
+            }
+            if daemon {
+                return daemon::download(&socket, format, path, output, &only, mode, progress).await;
+            }
+            let mut options = AddOptions {
+                mode,
+                ..Default::default()
+            };
+            if !only.is_empty() {
+                options.file_priorities = Some(picker::select_files(&t.info.files()?, &only)?);
+            }
 
-                //append the piece to the output file
-                let mut output_file = tokio::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&output)
-                .await
-                .expect("failed to open file");
-                output_file.write_all(&piece).await.expect("failed to write to file");
+            let session = Session::new(config).await?;
+            let handle = session.add_torrent_with(t, &output, options).await?;
 
-                println!("Piece {} downloaded to {}.", index, &output);
+            let mut events = handle.subscribe();
+            let mut reporter = progress::Reporter::new(progress);
+            let mut tick = tokio::time::interval(progress::INTERVAL);
+            handle.start();
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(Event::PieceCompleted(index)) if text => {
+                            reporter.clear();
+                            println!("Piece {} downloaded to {}.", index, &output)
+                        }
+                        Ok(Event::Completed) | Ok(Event::Error(_)) | Err(RecvError::Closed) => break,
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    },
+                    _ = tick.tick() => reporter.report(&handle.stats()),
+                }
+            }
+            reporter.finish(&handle.stats());
+            handle.wait_complete().await?;
 
-                output_file.flush().await.expect("failed to flush");
+            let stats = handle.stats();
+            let summary = output::DownloadSummary {
+                torrent: path,
+                name: stats.name,
+                info_hash: stats.info_hash,
+                output,
+                length: handle.torrent().info.length(),
+                piece_count: stats.piece_count,
+                downloaded: stats.transfer.downloaded,
+                elapsed_secs: started.elapsed().as_secs_f64(),
+            };
+            output::print(format, &summary);
+        }
+        cli::Commands::Stream {
+            path,
+            output,
+            file,
+            progress,
+        } => {
+            let t = torrent::Torrent::load_torrent(path)?;
+            let files = t.info.files()?;
+            if file >= files.len() {
+                return Err(Error::Usage(format!("no file {}, the torrent has {}", file, files.len())));
+            }
+            let mut priorities = vec![FilePriority::Skip; files.len()];
+            priorities[file] = FilePriority::Normal;
+            let options = AddOptions {
+                file_priorities: Some(priorities),
+                mode: DownloadMode::Streaming,
+            };
 
+            let session = Session::new(config).await?;
+            let handle = session.add_torrent_with(t, &output, options).await?;
+            let mut reader = handle.reader(file).await?;
+            handle.start();
 
-                index += 1;
-                tokio_stream.shutdown().await.expect("failed to shutdown");
+            // stdout is the data, so progress only ever goes to stderr
+            let mut reporter = progress::Reporter::new(progress);
+            let mut tick = tokio::time::interval(progress::INTERVAL);
+            let mut stdout = tokio::io::stdout();
+            let copy = tokio::io::copy(&mut reader, &mut stdout);
+            tokio::pin!(copy);
+            loop {
+                tokio::select! {
+                    copied = &mut copy => {
+                        copied?;
+                        break;
+                    }
+                    _ = tick.tick() => reporter.report(&handle.stats()),
+                }
             }
+            reporter.finish(&handle.stats());
+        }
+        cli::Commands::Seed { path, data, progress, daemon } => {
+            if daemon {
+                return daemon::seed(&socket, format, path, data).await;
+            }
+            let t = torrent::Torrent::load_torrent(path)?;
+            let session = Session::new(config).await?;
+            let handle = session.add_torrent(t, &data).await?;
 
-
+            // anything missing is downloaded first
+            let mut reporter = progress::Reporter::new(progress);
+            let mut tick = tokio::time::interval(progress::INTERVAL);
+            handle.start();
+            let complete = handle.wait_complete();
+            tokio::pin!(complete);
+            loop {
+                tokio::select! {
+                    result = &mut complete => break result?,
+                    _ = tick.tick() => reporter.report(&handle.stats()),
+                }
+            }
+            reporter.clear();
+            let seeding = output::Seeding {
+                name: handle.torrent().info.name.clone(),
+                info_hash: hex::encode(handle.info_hash()),
+                path: data,
+                port: session.port(),
+            };
+            output::print(format, &seeding);
+            loop {
+                tick.tick().await;
+                reporter.report(&handle.stats());
+            }
+        }
+        cli::Commands::Daemon {
+            state_dir,
+            max_active_downloads,
+            max_active_seeds,
+        } => {
+            let config = SessionConfig {
+                queue: daemon::queue_limits(max_active_downloads, max_active_seeds),
+                state_dir: Some(state_dir),
+                ..config
+            };
+            daemon::run(config, &socket, format).await?;
+        }
+        cli::Commands::Add {
+            path,
+            output,
+            paused,
+            priority,
+            only,
+            mode,
+        } => {
+            let options = daemon::Add {
+                path: &path,
+                output: &output,
+                paused,
+                priority,
+                only: &only,
+                mode,
+            };
+            daemon::add_torrent(&socket, format, options).await?
+        }
+        cli::Commands::Remove { info_hash } => {
+            daemon::torrent_call(&socket, format, "remove", "removed", &info_hash).await?
+        }
+        cli::Commands::Pause { info_hash } => {
+            daemon::torrent_call(&socket, format, "pause", "paused", &info_hash).await?
+        }
+        cli::Commands::Resume { info_hash } => {
+            daemon::torrent_call(&socket, format, "resume", "resumed", &info_hash).await?
+        }
+        cli::Commands::List => daemon::list(&socket, format).await?,
+        cli::Commands::Stats { info_hash } => daemon::stats(&socket, format, &info_hash).await?,
+        cli::Commands::SetPriority { info_hash, priority } => {
+            daemon::set_priority(&socket, format, &info_hash, priority).await?
         }
-            
+        cli::Commands::SetFilePriority {
+            info_hash,
+            file,
+            priority,
+        } => daemon::set_file_priority(&socket, format, &info_hash, file, priority).await?,
+        cli::Commands::Files { info_hash } => daemon::files(&socket, format, &info_hash).await?,
+        cli::Commands::Events => daemon::events(&socket, format).await?,
     }
+    Ok(())
 }
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+
+use serde_bytes::ByteBuf;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, Result};
+use crate::peer_protocol::HashRequest;
+use crate::torrent::Torrent;
+
+// v2 merkle trees have a leaf for every 16KiB of a file
+pub const BLOCK_SIZE: usize = 16 * 1024;
+// most hashes we'll send in answer to one hash request
+const MAX_HASHES: u32 = 512;
+
+pub type Hash = [u8; 32];
+
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// the root of a subtree `height` levels tall with nothing but zero leaves,
+// which is what a tree's padding beyond the end of its file is made of
+pub fn pad(height: u32) -> Hash {
+    (0..height).fold([0; 32], |hash, _| parent(&hash, &hash))
+}
+
+// every layer from `hashes`, padded out to `width` with `pad(height)`,
+// up to the root
+fn layers(hashes: &[Hash], width: usize, height: u32) -> Vec<Vec<Hash>> {
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(1), pad(height));
+    let mut layers = vec![layer];
+    while layers.last().expect("one layer").len() > 1 {
+        let below = layers.last().expect("one layer");
+        layers.push(below.chunks(2).map(|pair| parent(&pair[0], &pair[1])).collect());
+    }
+    layers
+}
+
+pub fn root(hashes: &[Hash], width: usize, height: u32) -> Hash {
+    layers(hashes, width, height).last().expect("one layer")[0]
+}
+
+// SHA-256 of each 16KiB of the first `len` bytes of `blocks`, whatever
+// size the blocks themselves are
+pub fn leaf_hashes<B: AsRef<[u8]>>(blocks: &[B], len: usize) -> Vec<Hash> {
+    let mut hashes = Vec::with_capacity(len.div_ceil(BLOCK_SIZE));
+    let mut hasher = Sha256::new();
+    let mut filled = 0;
+    let mut left = len;
+    for block in blocks {
+        let block = block.as_ref();
+        let mut block = &block[..block.len().min(left)];
+        left -= block.len();
+        while !block.is_empty() {
+            let n = (BLOCK_SIZE - filled).min(block.len());
+            hasher.update(&block[..n]);
+            filled += n;
+            block = &block[n..];
+            if filled == BLOCK_SIZE {
+                hashes.push(hasher.finalize_reset().into());
+                filled = 0;
+            }
+        }
+    }
+    if filled > 0 {
+        hashes.push(hasher.finalize().into());
+    }
+    hashes
+}
+
+// what a v2 piece's data has to hash up to
+#[derive(Debug, Clone)]
+struct PieceRoot {
+    hash: Hash,
+    // the file's bytes in the piece, less than a piece at its end
+    len: usize,
+    // the tree under `hash` has 2^height leaves
+    height: u32,
+}
+
+// What a piece is checked against: its SHA-1 from v1 metadata, the root
+// of its part of the file's merkle tree from v2, or both for a hybrid
+// torrent. The piece layers are checked against their files' roots up
+// front, so a piece that matches its layer is known good.
+#[derive(Debug, Clone, Default)]
+pub struct PieceHashes {
+    v1: Vec<u8>,
+    // one per piece, empty for v1-only torrents
+    v2: Vec<Option<PieceRoot>>,
+    // each file's piece layer by pieces root, for answering hash requests
+    layers: HashMap<Hash, Vec<Hash>>,
+    // how far above the leaves the piece layer is
+    piece_height: u32,
+}
+
+impl PieceHashes {
+    pub fn new(torrent: &Torrent) -> Result<Self> {
+        let info = &torrent.info;
+        let mut hashes = Self {
+            v1: info.pieces.clone(),
+            ..Default::default()
+        };
+        if !info.is_v2() {
+            if !info.is_v1() {
+                return Err(Error::Bencode("torrent has no piece hashes".to_owned()));
+            }
+            return Ok(hashes);
+        }
+        if !info.plen.is_power_of_two() || info.plen < BLOCK_SIZE {
+            return Err(Error::Bencode(format!("v2 piece length {} isn't a power of two of at least 16KiB", info.plen)));
+        }
+        hashes.piece_height = (info.plen / BLOCK_SIZE).trailing_zeros();
+        hashes.v2 = vec![None; info.piece_count()];
+
+        for file in info.files()? {
+            if file.length == 0 {
+                continue;
+            }
+            let name = file.path.display();
+            let root = file
+                .pieces_root
+                .ok_or_else(|| Error::Bencode(format!("{} has no pieces root", name)))?;
+            let first = file.offset / info.plen;
+            if file.length <= info.plen {
+                let leaves = file.length.div_ceil(BLOCK_SIZE).next_power_of_two();
+                hashes.v2[first] = Some(PieceRoot {
+                    hash: root,
+                    len: file.length,
+                    height: leaves.trailing_zeros(),
+                });
+                continue;
+            }
+
+            let count = file.length.div_ceil(info.plen);
+            let layer = torrent
+                .piece_layers
+                .as_ref()
+                .and_then(|layers| layers.get(&ByteBuf::from(root.to_vec())))
+                .filter(|layer| layer.len() == count * 32)
+                .ok_or_else(|| Error::Bencode(format!("{} has no piece layer", name)))?;
+            let layer: Vec<Hash> = layer.chunks(32).map(|h| h.try_into().expect("32 bytes")).collect();
+            if self::root(&layer, count.next_power_of_two(), hashes.piece_height) != root {
+                return Err(Error::Bencode(format!("piece layer of {} doesn't match its root", name)));
+            }
+            for (k, &hash) in layer.iter().enumerate() {
+                hashes.v2[first + k] = Some(PieceRoot {
+                    hash,
+                    len: (file.length - k * info.plen).min(info.plen),
+                    height: hashes.piece_height,
+                });
+            }
+            hashes.layers.insert(root, layer);
+        }
+        Ok(hashes)
+    }
+
+    // whether a piece's data, in any number of blocks, is what the
+    // torrent says it should be
+    pub fn check<B: AsRef<[u8]>>(&self, index: usize, blocks: &[B]) -> bool {
+        if !self.v1.is_empty() {
+            let mut hasher = Sha1::new();
+            for block in blocks {
+                hasher.update(block.as_ref());
+            }
+            if self.v1.get(index * 20..index * 20 + 20) != Some(hasher.finalize().as_slice()) {
+                return false;
+            }
+        }
+        if self.v2.is_empty() {
+            return true;
+        }
+        match self.v2.get(index) {
+            Some(Some(piece)) => {
+                let leaves = leaf_hashes(blocks, piece.len);
+                root(&leaves, 1 << piece.height, 0) == piece.hash
+            }
+            // a piece that's all padding, v1 has already had its say
+            _ => !self.v1.is_empty(),
+        }
+    }
+
+    // Answer to a peer's BEP 52 hash request, the hashes it asked for
+    // followed by the uncles that prove them against the root. We only
+    // keep piece layers so that's the only base layer we can serve.
+    pub fn answer(&self, request: &HashRequest) -> Option<Vec<Hash>> {
+        let layer = self.layers.get(&request.pieces_root)?;
+        let width = layer.len().next_power_of_two();
+        let (index, length) = (request.index as usize, request.length as usize);
+        let valid = request.base_layer == self.piece_height
+            && request.length.is_power_of_two()
+            && request.length <= MAX_HASHES
+            && index % length == 0
+            && index + length <= width;
+        if !valid {
+            return None;
+        }
+
+        let layers = layers(layer, width, self.piece_height);
+        let mut hashes = layers[0][index..index + length].to_vec();
+        let mut level = length.trailing_zeros() as usize;
+        let mut position = index / length;
+        // the root itself is never sent
+        while level + 1 < layers.len() && hashes.len() < length + request.proof_layers as usize {
+            hashes.push(layers[level][position ^ 1]);
+            level += 1;
+            position /= 2;
+        }
+        Some(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the fixtures' file contents, made by the same formula as the
+    // implementation their hashes were worked out with
+    fn file_data(k: usize, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + k * 7 + i / 997) as u8).collect()
+    }
+
+    fn hybrid() -> Torrent {
+        Torrent::from_bytes(include_bytes!("../tests/fixtures/hybrid.torrent")).unwrap()
+    }
+
+    // the hybrid fixture's files as v1 lays them out, padding and all
+    fn hybrid_data() -> Vec<u8> {
+        let mut data = file_data(0, 50000);
+        data.resize(2 * 32768, 0);
+        data.extend(file_data(1, 70000));
+        data.resize(5 * 32768, 0);
+        data.extend(file_data(2, 20000));
+        data
+    }
+
+    fn hash(hex: &str) -> Hash {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn pad_is_a_tree_of_zeros() {
+        assert_eq!(pad(0), [0; 32]);
+        let one: Hash = Sha256::digest([0; 64]).into();
+        assert_eq!(pad(1), one);
+        assert_eq!(pad(2), parent(&one, &one));
+    }
+
+    #[test]
+    fn leaf_hashes_ignore_how_blocks_are_split() {
+        let data = file_data(0, 40000);
+        let whole = leaf_hashes(&[&data[..]], data.len());
+        let split = leaf_hashes(&[&data[..1000], &data[1000..20000], &data[20000..]], data.len());
+        assert_eq!(whole, split);
+        assert_eq!(whole.len(), 3);
+        // the last leaf isn't padded out to 16KiB before it's hashed
+        let last: Hash = Sha256::digest(&data[2 * BLOCK_SIZE..]).into();
+        assert_eq!(whole[2], last);
+        // nothing past `len` is hashed
+        assert_eq!(leaf_hashes(&[&data[..]], BLOCK_SIZE), whole[..1]);
+    }
+
+    #[test]
+    fn root_pads_with_subtrees_of_the_right_height() {
+        let (a, b) = (hash(&"11".repeat(32)), hash(&"22".repeat(32)));
+        assert_eq!(root(&[a], 1, 0), a);
+        assert_eq!(root(&[a, b], 2, 0), parent(&a, &b));
+        assert_eq!(root(&[a], 2, 3), parent(&a, &pad(3)));
+        assert_eq!(root(&[a, b, a], 4, 0), parent(&parent(&a, &b), &parent(&a, &pad(0))));
+    }
+
+    #[test]
+    fn hybrid_pieces_check_against_both_hashes() {
+        let torrent = hybrid();
+        let hashes = PieceHashes::new(&torrent).unwrap();
+        let data = hybrid_data();
+        assert_eq!(torrent.info.piece_count(), 6);
+        for index in 0..6 {
+            let start = index * 32768;
+            let piece = &data[start..start + torrent.info.piece_len(index)];
+            assert!(hashes.check(index, &[piece]), "piece {}", index);
+
+            let mut bad = piece.to_vec();
+            bad[piece.len() / 2] ^= 1;
+            assert!(!hashes.check(index, &[bad]), "corrupt piece {}", index);
+        }
+    }
+
+    #[test]
+    fn v2_pieces_check_against_the_piece_layer() {
+        let torrent = Torrent::from_bytes(include_bytes!("../tests/fixtures/v2.torrent")).unwrap();
+        assert!(!torrent.info.is_v1());
+        let hashes = PieceHashes::new(&torrent).unwrap();
+        let data = file_data(0, 100000);
+        let pieces: Vec<&[u8]> = data.chunks(32768).collect();
+        assert_eq!(pieces.len(), torrent.info.piece_count());
+        for (index, piece) in pieces.iter().enumerate() {
+            assert!(hashes.check(index, &[piece]), "piece {}", index);
+        }
+        // right data, wrong piece
+        assert!(!hashes.check(1, &[pieces[0]]));
+    }
+
+    #[test]
+    fn piece_layer_that_does_not_match_its_root_is_refused() {
+        let mut torrent = hybrid();
+        let layers = torrent.piece_layers.as_mut().unwrap();
+        let layer = layers.values_mut().next().unwrap();
+        layer[0] ^= 1;
+        assert!(PieceHashes::new(&torrent).is_err());
+
+        torrent.piece_layers = None;
+        assert!(PieceHashes::new(&torrent).is_err());
+    }
+
+    #[test]
+    fn answer_proves_hashes_against_the_pieces_root() {
+        let hashes = PieceHashes::new(&hybrid()).unwrap();
+        let b_root = hash("bc41461fc870516f6ab46bbc1e4e911f2d1e57fa909fa8c74d0ab40a61fb0148");
+        let request = HashRequest {
+            pieces_root: b_root,
+            base_layer: 1,
+            index: 0,
+            length: 2,
+            proof_layers: 1,
+        };
+        let answer = hashes.answer(&request).unwrap();
+        assert_eq!(answer.len(), 3);
+        assert_eq!(answer[0], hash("2936b2086772354487319731ac3d677af1102a4c4562061df1abe10163dc3000"));
+        assert_eq!(answer[1], hash("8166b7f5ec5477d74d3c9f5db99a8ac30b2422f875bf21c672a276db908b296c"));
+        // the requested pair and their uncle hash up to the root
+        assert_eq!(parent(&parent(&answer[0], &answer[1]), &answer[2]), b_root);
+
+        // the root itself is never part of the proof
+        let all = HashRequest { proof_layers: 10, ..request };
+        assert_eq!(hashes.answer(&all).unwrap().len(), 3);
+        let last = HashRequest { index: 2, length: 2, proof_layers: 1, ..request };
+        let answer = hashes.answer(&last).unwrap();
+        assert_eq!(answer[1], pad(1));
+        assert_eq!(parent(&answer[2], &parent(&answer[0], &answer[1])), b_root);
+    }
+
+    #[test]
+    fn answer_rejects_what_we_cannot_serve() {
+        let hashes = PieceHashes::new(&hybrid()).unwrap();
+        let request = HashRequest {
+            pieces_root: hash("bc41461fc870516f6ab46bbc1e4e911f2d1e57fa909fa8c74d0ab40a61fb0148"),
+            base_layer: 1,
+            index: 0,
+            length: 2,
+            proof_layers: 0,
+        };
+        assert!(hashes.answer(&request).is_some());
+        assert_eq!(hashes.answer(&HashRequest { base_layer: 0, ..request }), None);
+        assert_eq!(hashes.answer(&HashRequest { length: 3, ..request }), None);
+        assert_eq!(hashes.answer(&HashRequest { index: 1, ..request }), None);
+        assert_eq!(hashes.answer(&HashRequest { index: 4, ..request }), None);
+        assert_eq!(hashes.answer(&HashRequest { pieces_root: [0; 32], ..request }), None);
+        // z.txt is a single piece so it has no layer to ask about
+        let small = hash("70e00a6ed125fa2b84b20c98e79e577a113502a64fd42c914d580b5f377a9280");
+        assert_eq!(hashes.answer(&HashRequest { pieces_root: small, length: 1, ..request }), None);
+    }
+}
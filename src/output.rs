@@ -0,0 +1,496 @@
+use std::fmt;
+
+use bittorrent_starter_rust::peer_protocol::Capabilities;
+use bittorrent_starter_rust::rpc::{FileStatus, TorrentEvent, TorrentStatus};
+use bittorrent_starter_rust::torrent::{display_path, Torrent};
+use bittorrent_starter_rust::{Event, FilePriority, Priority, State, TorrentStats};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Lines meant for people
+    Text,
+    /// A single JSON document per command, on one line
+    Json,
+}
+
+// Every command's result is one of the documents below. In text mode it's
+// printed with Display, which keeps the original line formats, in json
+// mode the field names are the stable interface scripts can rely on.
+pub fn print<T: Serialize + fmt::Display>(format: Format, doc: &T) {
+    match format {
+        Format::Text => print!("{}", doc),
+        // plain structs with string keys, serializing them can't fail
+        Format::Json => println!("{}", serde_json::to_string(doc).expect("document serializes")),
+    }
+}
+
+#[derive(Serialize)]
+pub struct TorrentInfo {
+    pub tracker: String,
+    pub name: String,
+    pub length: usize,
+    pub info_hash: String,
+    // the full SHA-256 info hash, null for v1 torrents
+    pub info_hash_v2: Option<String>,
+    // 1 for plain v1 torrents, 2 for v2 and hybrid ones
+    pub meta_version: u32,
+    pub private: bool,
+    pub piece_length: usize,
+    pub piece_hashes: Vec<String>,
+    // null for single file torrents
+    pub files: Option<Vec<FileInfo>>,
+    pub web_seeds: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct FileInfo {
+    pub path: String,
+    pub length: usize,
+}
+
+impl TorrentInfo {
+    pub fn new(torrent: &Torrent) -> Self {
+        Self {
+            tracker: torrent.announce.clone(),
+            name: torrent.info.name.clone(),
+            length: torrent.info.length(),
+            info_hash: hex::encode(torrent.get_info_hash()),
+            info_hash_v2: torrent.info_hash_v2().map(hex::encode),
+            meta_version: torrent.info.meta_version.unwrap_or(1),
+            private: torrent.info.is_private(),
+            piece_length: torrent.info.plen,
+            piece_hashes: torrent.info.pieces.chunks(20).map(hex::encode).collect(),
+            // padding left out, and v2 files laid out from the file tree
+            files: torrent
+                .info
+                .files()
+                .ok()
+                .filter(|_| torrent.info.is_multi_file())
+                .map(|files| {
+                    files
+                        .iter()
+                        .map(|f| FileInfo {
+                            path: display_path(&f.path),
+                            length: f.length,
+                        })
+                        .collect()
+                }),
+            web_seeds: torrent.url_list.clone(),
+        }
+    }
+}
+
+impl fmt::Display for TorrentInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Tracker URL: {}\nLength: {}", self.tracker, self.length)?;
+        writeln!(f, "Info Hash: {}", self.info_hash)?;
+        if let Some(hash) = &self.info_hash_v2 {
+            writeln!(f, "Info Hash v2: {}", hash)?;
+        }
+        if self.private {
+            writeln!(f, "Private: yes")?;
+        }
+        writeln!(f, "Piece Length: {}", self.piece_length)?;
+        writeln!(f, "Piece Hashes:")?;
+        for hash in &self.piece_hashes {
+            writeln!(f, "{}", hash)?;
+        }
+        if let Some(files) = &self.files {
+            writeln!(f, "Files:")?;
+            for file in files {
+                writeln!(f, "{:>12}  {}", file.length, file.path)?;
+            }
+        }
+        if !self.web_seeds.is_empty() {
+            writeln!(f, "Web Seeds:")?;
+            for url in &self.web_seeds {
+                writeln!(f, "{}", url)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct PeerInfo {
+    pub addr: String,
+    // only when the tracker sent full peer entries
+    pub peer_id: Option<String>,
+    pub client: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PeerList {
+    pub seeders: u32,
+    pub leechers: u32,
+    pub interval: u32,
+    pub peers: Vec<PeerInfo>,
+}
+
+impl fmt::Display for PeerList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for peer in &self.peers {
+            match &peer.client {
+                Some(client) => writeln!(f, "Peer: {} ({})", peer.addr, client)?,
+                None => writeln!(f, "Peer: {}", peer.addr)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct HandshakeResult {
+    pub addr: String,
+    pub peer_id: String,
+    pub client: Option<String>,
+    pub capabilities: Capabilities,
+}
+
+impl fmt::Display for HandshakeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Peer ID: {}", self.peer_id)
+    }
+}
+
+#[derive(Serialize)]
+pub struct PieceDownload {
+    pub index: usize,
+    pub length: usize,
+    pub output: String,
+    pub peer: String,
+}
+
+impl fmt::Display for PieceDownload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Piece {} downloaded to {}.", self.index, self.output)
+    }
+}
+
+#[derive(Serialize)]
+pub struct DownloadSummary {
+    pub torrent: String,
+    pub name: String,
+    pub info_hash: String,
+    pub output: String,
+    pub length: usize,
+    pub piece_count: usize,
+    // bytes actually transferred, pieces already on disk aren't counted
+    pub downloaded: u64,
+    pub elapsed_secs: f64,
+}
+
+impl fmt::Display for DownloadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Downloaded {} to {}.", self.torrent, self.output)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Seeding {
+    pub name: String,
+    pub info_hash: String,
+    pub path: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Seeding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Seeding {} on port {}.", self.name, self.port)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Failure {
+    pub error: String,
+    pub exit_code: i32,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "error: {}", self.error)
+    }
+}
+
+// what a daemon call did to a torrent, and the torrent as it is now
+#[derive(Serialize)]
+pub struct TorrentAction {
+    pub action: &'static str,
+    #[serde(flatten)]
+    pub torrent: TorrentStatus,
+}
+
+impl fmt::Display for TorrentAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut action = self.action.to_owned();
+        action[..1].make_ascii_uppercase();
+        writeln!(f, "{} {} ({}).", action, self.torrent.name, self.torrent.info_hash)
+    }
+}
+
+#[derive(Serialize)]
+pub struct TorrentList {
+    pub torrents: Vec<TorrentStatus>,
+}
+
+impl fmt::Display for TorrentList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for t in &self.torrents {
+            let done = match t.piece_count {
+                0 => 100.0,
+                count => t.pieces_done as f64 * 100.0 / count as f64,
+            };
+            let state = match t.state {
+                State::Paused => "paused",
+                State::Queued => "queued",
+                State::Running => "running",
+                State::Removed => "removed",
+            };
+            let priority = match t.priority {
+                Priority::Low => "low",
+                Priority::Normal => "normal",
+                Priority::High => "high",
+            };
+            write!(
+                f,
+                "{}  {:<7} {:>5.1}%  {:<6}  {}",
+                &t.info_hash[..8],
+                state,
+                done,
+                priority,
+                t.name
+            )?;
+            match &t.error {
+                Some(error) => writeln!(f, "  ({})", error)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub struct Stats(pub TorrentStats);
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.0;
+        writeln!(f, "Name: {}", s.name)?;
+        writeln!(f, "Info Hash: {}", s.info_hash)?;
+        writeln!(f, "Pieces: {}/{}", s.pieces_done, s.piece_count)?;
+        writeln!(f, "Left: {}", s.bytes_left)?;
+        writeln!(f, "Downloaded: {} ({} B/s)", s.transfer.downloaded, s.transfer.download_rate)?;
+        writeln!(f, "Uploaded: {} ({} B/s)", s.transfer.uploaded, s.transfer.upload_rate)?;
+        writeln!(f, "Peers: {} ({} choking)", s.transfer.peers, s.transfer.peers_choking)?;
+        match s.eta_secs {
+            Some(secs) => writeln!(f, "ETA: {}s", secs),
+            None => writeln!(f, "ETA: -"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(transparent)]
+pub struct EventLine(pub TorrentEvent);
+
+impl fmt::Display for EventLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hash = &self.0.info_hash[..8];
+        match &self.0.event {
+            Event::Started => writeln!(f, "{} started", hash),
+            Event::Paused => writeln!(f, "{} paused", hash),
+            Event::Queued => writeln!(f, "{} queued", hash),
+            Event::PieceCompleted(index) => writeln!(f, "{} piece {} completed", hash, index),
+            Event::Completed => writeln!(f, "{} completed", hash),
+            Event::Error(error) => writeln!(f, "{} error: {}", hash, error),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DaemonStarted {
+    pub socket: String,
+    pub state_dir: String,
+    pub port: u16,
+    pub torrents: usize,
+}
+
+impl fmt::Display for DaemonStarted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Daemon listening on {} with {} torrents, peers on port {}.",
+            self.socket, self.torrents, self.port
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct FileList {
+    pub files: Vec<FileStatus>,
+}
+
+impl fmt::Display for FileList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            let priority = match file.priority {
+                FilePriority::Skip => "skip",
+                FilePriority::Low => "low",
+                FilePriority::Normal => "normal",
+                FilePriority::High => "high",
+            };
+            writeln!(f, "{:>3}  {:<6}  {:>12}  {}", file.index, priority, file.length, file.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn to_json<T: Serialize>(doc: &T) -> Value {
+        serde_json::to_value(doc).unwrap()
+    }
+
+    // field names are what scripts match on, renaming one breaks them
+    #[test]
+    fn torrent_info_fields() {
+        let torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        assert_eq!(
+            to_json(&TorrentInfo::new(&torrent)),
+            json!({
+                "tracker": "http://bittorrent-test-tracker.codecrafters.io/announce",
+                "name": "sample.txt",
+                "length": 92063,
+                "info_hash": "d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+                "info_hash_v2": null,
+                "meta_version": 1,
+                "private": false,
+                "piece_length": 32768,
+                "piece_hashes": [
+                    "e876f67a2a8886e8f36b136726c30fa29703022d",
+                    "6e2275e604a0766656736e81ff10b55204ad8d35",
+                    "f00d937a0213df1982bc8d097227ad9e909acc17"
+                ],
+                "files": null,
+                "web_seeds": []
+            })
+        );
+    }
+
+    #[test]
+    fn multi_file_info_lists_files() {
+        let torrent = Torrent::from_bytes(include_bytes!("../tests/fixtures/hybrid.torrent")).unwrap();
+        let info = to_json(&TorrentInfo::new(&torrent));
+        assert_eq!(info["meta_version"], 2);
+        assert!(info["info_hash_v2"].is_string());
+        let files = info["files"].as_array().unwrap();
+        assert!(!files.is_empty());
+        for file in files {
+            let keys: Vec<_> = file.as_object().unwrap().keys().cloned().collect();
+            assert_eq!(keys, ["length", "path"]);
+        }
+    }
+
+    #[test]
+    fn peer_list_fields() {
+        let list = PeerList {
+            seeders: 3,
+            leechers: 1,
+            interval: 60,
+            peers: vec![
+                PeerInfo {
+                    addr: "10.0.0.1:6881".to_owned(),
+                    peer_id: Some("2d7142343635302d".to_owned()),
+                    client: Some("qBittorrent 4.6.5.0".to_owned()),
+                },
+                PeerInfo {
+                    addr: "10.0.0.2:6881".to_owned(),
+                    peer_id: None,
+                    client: None,
+                },
+            ],
+        };
+        assert_eq!(
+            to_json(&list),
+            json!({
+                "seeders": 3,
+                "leechers": 1,
+                "interval": 60,
+                "peers": [
+                    {"addr": "10.0.0.1:6881", "peer_id": "2d7142343635302d", "client": "qBittorrent 4.6.5.0"},
+                    {"addr": "10.0.0.2:6881", "peer_id": null, "client": null}
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn handshake_result_fields() {
+        let result = HandshakeResult {
+            addr: "10.0.0.1:6881".to_owned(),
+            peer_id: "2d4142323030302d".to_owned(),
+            client: None,
+            capabilities: Capabilities {
+                fast: true,
+                extension: true,
+                ..Capabilities::default()
+            },
+        };
+        assert_eq!(
+            to_json(&result),
+            json!({
+                "addr": "10.0.0.1:6881",
+                "peer_id": "2d4142323030302d",
+                "client": null,
+                "capabilities": {"dht": false, "fast": true, "extension": true, "v2": false}
+            })
+        );
+    }
+
+    #[test]
+    fn piece_download_fields() {
+        let piece = PieceDownload {
+            index: 2,
+            length: 26527,
+            output: "/tmp/piece".to_owned(),
+            peer: "10.0.0.1:6881".to_owned(),
+        };
+        assert_eq!(
+            to_json(&piece),
+            json!({"index": 2, "length": 26527, "output": "/tmp/piece", "peer": "10.0.0.1:6881"})
+        );
+    }
+
+    #[test]
+    fn download_summary_fields() {
+        let summary = DownloadSummary {
+            torrent: "sample.torrent".to_owned(),
+            name: "sample.txt".to_owned(),
+            info_hash: "d69f91e6b2ae4c542468d1073a71d4ea13879a7f".to_owned(),
+            output: "/tmp/sample.txt".to_owned(),
+            length: 92063,
+            piece_count: 3,
+            downloaded: 65536,
+            elapsed_secs: 1.5,
+        };
+        assert_eq!(
+            to_json(&summary),
+            json!({
+                "torrent": "sample.torrent",
+                "name": "sample.txt",
+                "info_hash": "d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+                "output": "/tmp/sample.txt",
+                "length": 92063,
+                "piece_count": 3,
+                "downloaded": 65536,
+                "elapsed_secs": 1.5
+            })
+        );
+    }
+}
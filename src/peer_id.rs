@@ -0,0 +1,126 @@
+use std::fmt;
+
+// Azureus style client code, the version digits come from Cargo.toml
+const CLIENT_CODE: &str = "CB";
+
+const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+// Each version component gets one character, 10 and up carry on into
+// letters, so the prefix is always 8 bytes.
+fn prefix([major, minor, patch]: [&str; 3]) -> [u8; 8] {
+    let digit = |v: &str| ALPHABET[v.parse::<usize>().unwrap_or(0).min(ALPHABET.len() - 1)];
+    let code = CLIENT_CODE.as_bytes();
+    [
+        b'-',
+        code[0],
+        code[1],
+        digit(major),
+        digit(minor),
+        digit(patch),
+        b'0',
+        b'-',
+    ]
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("peer id must be exactly 20 bytes, got {0}")]
+pub struct InvalidPeerId(usize);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PeerId([u8; 20]);
+
+impl PeerId {
+    // `-CBxyz0-` followed by 12 random alphanumerics. Make one per session,
+    // the tracker and every handshake have to agree on it.
+    pub fn generate() -> Self {
+        let mut id = [0; 20];
+        id[..8].copy_from_slice(&prefix([
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ]));
+
+        let mut random = [0; 12];
+        getrandom::getrandom(&mut random).expect("no randomness from the os");
+        for (byte, random) in id[8..].iter_mut().zip(random) {
+            *byte = ALPHABET[random as usize % ALPHABET.len()];
+        }
+        Self(id)
+    }
+
+    pub fn parse(id: &str) -> Result<Self, InvalidPeerId> {
+        let bytes: [u8; 20] = id
+            .as_bytes()
+            .try_into()
+            .map_err(|_| InvalidPeerId(id.len()))?;
+        Ok(Self(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId({})", String::from_utf8_lossy(&self.0))
+    }
+}
+
+// Best effort guess at the client behind a peer id, covering Azureus style
+// (`-TR2940-...`) and Shadow/Mainline style (`M7-4-3--...`) ids.
+pub fn client_name(id: &[u8; 20]) -> Option<String> {
+    if id[0] == b'-' && id[7] == b'-' {
+        let code = std::str::from_utf8(&id[1..3]).ok()?;
+        let version = std::str::from_utf8(&id[3..7]).ok()?;
+        let name = match code {
+            "AZ" => "Vuze",
+            "BC" => "BitComet",
+            "BI" => "BiglyBT",
+            "CB" => "codecrafters-bittorrent",
+            "DE" => "Deluge",
+            "KT" => "KTorrent",
+            "LT" => "libtorrent",
+            "lt" => "libTorrent (Rakshasa)",
+            "qB" => "qBittorrent",
+            "TR" => "Transmission",
+            "UM" => "µTorrent Mac",
+            "UT" => "µTorrent",
+            "WW" => "WebTorrent",
+            _ => return Some(format!("unknown ({}) {}", code, version)),
+        };
+        let version: Vec<String> = version.chars().map(|c| c.to_string()).collect();
+        return Some(format!("{} {}", name, version.join(".")));
+    }
+
+    if id[0] == b'M' && id[1].is_ascii_digit() {
+        let version = std::str::from_utf8(&id[1..8]).ok()?;
+        let version = version.trim_end_matches('-').replace('-', ".");
+        return Some(format!("Mainline {}", version));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_ids_are_azureus_style() {
+        let id = PeerId::generate();
+        let bytes = id.as_bytes();
+        assert_eq!(bytes[0], b'-');
+        assert_eq!(&bytes[1..3], CLIENT_CODE.as_bytes());
+        assert_eq!(bytes[7], b'-');
+        assert!(bytes[3..7].iter().all(u8::is_ascii_alphanumeric));
+        assert!(bytes[8..].iter().all(|b| ALPHABET.contains(b)));
+        assert!(client_name(bytes).unwrap().starts_with("codecrafters-bittorrent "));
+        assert_ne!(id, PeerId::generate());
+    }
+
+    #[test]
+    fn long_versions_keep_the_prefix_shape() {
+        assert_eq!(&prefix(["0", "1", "0"]), b"-CB0100-");
+        assert_eq!(&prefix(["1", "12", "70"]), b"-CB1cZ0-");
+    }
+}
@@ -1,12 +1,35 @@
 use bytes::BufMut;
-use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_util::bytes::{Buf, BytesMut};
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::bytes::{Buf, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use tracing::trace;
 
-use crate::torrent::Torrent;
+use crate::peer_id::PeerId;
 
-#[derive(Debug)]
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("handshake i/o failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("peer speaks an unknown protocol: {0:?}")]
+    Protocol(String),
+    #[error("peer is serving info hash {theirs}, expected {ours}")]
+    InfoHash { ours: String, theirs: String },
+    #[error("connected to ourselves")]
+    SelfConnection,
+}
+
+// Extensions a peer advertises in the reserved bytes of its handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct Capabilities {
+    pub dht: bool,       // BEP 5
+    pub fast: bool,      // BEP 6
+    pub extension: bool, // BEP 10
+    pub v2: bool,        // BEP 52
+}
+
+#[derive(Debug, Clone)]
 pub struct Handshake {
     protocol: [u8; 19],
     reserved: [u8; 8],
@@ -15,40 +38,137 @@ pub struct Handshake {
 }
 
 impl Handshake {
-    pub fn new(ih: Vec<u8> , peer_id: &str) -> Self {
-        let protocol = *b"BitTorrent protocol";
+    pub fn new(ih: [u8; 20], peer_id: &PeerId) -> Self {
+        let protocol = *PROTOCOL;
         let reserved = [0; 8];
         Self { 
             protocol, 
             reserved, 
-            info_hash : ih.try_into().ok().expect("Invalid info hash"), 
-            peer_id: peer_id.as_bytes().try_into().ok().expect("Invalid peer id")
+            info_hash : ih, 
+            peer_id: *peer_id.as_bytes()
+        }
+    }
+
+    // tells the peer we understand v2 torrents, which is only worth
+    // saying for one that has v2 metadata
+    pub fn with_v2(mut self, v2: bool) -> Self {
+        if v2 {
+            self.reserved[7] |= 0x10;
         }
+        self
+    }
+
+    pub fn info_hash(&self) -> &[u8; 20] {
+        &self.info_hash
     }
 
-    pub async fn perform_handshake(&self, tokio_stream: &mut tokio::net::TcpStream) -> Handshake {
+    pub async fn write(&self, tokio_stream: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
         let mut buf = BytesMut::with_capacity(68);
         buf.put_u8(19);
         buf.put_slice(&self.protocol);
         buf.put_slice(&self.reserved);
         buf.put_slice(&self.info_hash);
         buf.put_slice(&self.peer_id);
-        tokio_stream.write_all(&buf).await.expect("failed to write");
-
-        let mut response = [0; 68];
-        tokio_stream.read_exact(&mut response).await.expect("failed to read");
-        Handshake {
-            protocol: response[1..20].try_into().unwrap(),
-            reserved: response[20..28].try_into().unwrap(),
-            info_hash: response[28..48].try_into().unwrap(),
-            peer_id: response[48..68].try_into().unwrap(),
+        tokio_stream.write_all(&buf).await
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            dht: self.reserved[7] & 0x01 != 0,
+            fast: self.reserved[7] & 0x04 != 0,
+            extension: self.reserved[5] & 0x10 != 0,
+            v2: self.reserved[7] & 0x10 != 0,
         }
     }
-}
 
+    // Reads a handshake, giving up as soon as the protocol string is wrong
+    // rather than waiting for the full 68 bytes.
+    pub async fn read(tokio_stream: &mut (impl AsyncRead + Unpin)) -> Result<Handshake, HandshakeError> {
+        let pstrlen = tokio_stream.read_u8().await? as usize;
+        if pstrlen != PROTOCOL.len() {
+            let mut pstr = vec![0; pstrlen];
+            let _ = tokio_stream.read_exact(&mut pstr).await;
+            return Err(HandshakeError::Protocol(String::from_utf8_lossy(&pstr).into_owned()));
+        }
+
+        let mut response = [0; 67];
+        tokio_stream.read_exact(&mut response).await?;
+        if &response[0..19] != PROTOCOL {
+            return Err(HandshakeError::Protocol(
+                String::from_utf8_lossy(&response[0..19]).into_owned(),
+            ));
+        }
+        Ok(Handshake {
+            protocol: response[0..19].try_into().unwrap(),
+            reserved: response[19..27].try_into().unwrap(),
+            info_hash: response[27..47].try_into().unwrap(),
+            peer_id: response[47..67].try_into().unwrap(),
+        })
+    }
+
+    // checks the handshake a peer sent back matches the one we sent
+    pub fn validate(&self, theirs: &Handshake) -> Result<(), HandshakeError> {
+        if theirs.info_hash != self.info_hash {
+            return Err(HandshakeError::InfoHash {
+                ours: hex::encode(self.info_hash),
+                theirs: hex::encode(theirs.info_hash),
+            });
+        }
+        if theirs.peer_id == self.peer_id {
+            return Err(HandshakeError::SelfConnection);
+        }
+        Ok(())
+    }
+
+    pub async fn perform_handshake(
+        &self,
+        tokio_stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ) -> Result<Handshake, HandshakeError> {
+        self.write(tokio_stream).await?;
+        let theirs = Handshake::read(tokio_stream).await?;
+        self.validate(&theirs)?;
+        Ok(theirs)
+    }
+}
 
 // Peer messages
 
+// BEP 52: `length` hashes from `index` on in the layer `base_layer` above
+// the leaves of the file with `pieces_root`, plus up to `proof_layers`
+// uncles to check them against the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+const HASH_REQUEST_LEN: usize = 48;
+
+impl HashRequest {
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.put_slice(&self.pieces_root);
+        buf.put_u32(self.base_layer);
+        buf.put_u32(self.index);
+        buf.put_u32(self.length);
+        buf.put_u32(self.proof_layers);
+    }
+
+    fn decode(frame: &mut BytesMut) -> Self {
+        let mut pieces_root = [0; 32];
+        frame.copy_to_slice(&mut pieces_root);
+        Self {
+            pieces_root,
+            base_layer: frame.get_u32(),
+            index: frame.get_u32(),
+            length: frame.get_u32(),
+            proof_layers: frame.get_u32(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum PeerMessage {
     Choke,
@@ -56,13 +176,36 @@ pub enum PeerMessage {
     Interested,
     NotInterested,
     Have(u32),
-    Bitfield(Vec<u8>),
+    Bitfield(Bytes),
     Request { index: u32, begin: u32, length: u32 },
-    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Piece { index: u32, begin: u32, block: Bytes },
     Cancel { index: u32, begin: u32, length: u32 },
+    HashRequest(HashRequest),
+    // the requested hashes then the proof, 32 bytes each
+    Hashes { request: HashRequest, hashes: Bytes },
+    HashReject(HashRequest),
     KeepAlive,
 }
 
+impl PeerMessage {
+    // size on the wire, length prefix included
+    pub fn wire_len(&self) -> usize {
+        4 + match self {
+            PeerMessage::KeepAlive => 0,
+            PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested => 1,
+            PeerMessage::Have(_) => 5,
+            PeerMessage::Bitfield(bitfield) => 1 + bitfield.len(),
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. } => 13,
+            PeerMessage::Piece { block, .. } => 9 + block.len(),
+            PeerMessage::HashRequest(_) | PeerMessage::HashReject(_) => 1 + HASH_REQUEST_LEN,
+            PeerMessage::Hashes { hashes, .. } => 1 + HASH_REQUEST_LEN + hashes.len(),
+        }
+    }
+}
+
 pub struct PeerMessageCodec;
 
 impl Encoder<PeerMessage> for PeerMessageCodec {
@@ -120,173 +263,362 @@ impl Encoder<PeerMessage> for PeerMessageCodec {
                 buf.put_u32(begin);
                 buf.put_u32(length);
             }
+            PeerMessage::HashRequest(ref request) => {
+                buf.put_u32(1 + HASH_REQUEST_LEN as u32);
+                buf.put_u8(21);
+                request.encode(buf);
+            }
+            PeerMessage::Hashes { ref request, ref hashes } => {
+                buf.put_u32((1 + HASH_REQUEST_LEN + hashes.len()) as u32);
+                buf.put_u8(22);
+                request.encode(buf);
+                buf.put_slice(hashes);
+            }
+            PeerMessage::HashReject(ref request) => {
+                buf.put_u32(1 + HASH_REQUEST_LEN as u32);
+                buf.put_u8(23);
+                request.encode(buf);
+            }
         }
         Ok(())
     }
 }
 
 
+// Largest frame we accept from a peer, length prefix excluded. Big enough
+// for a block of the largest request we serve, or with its id byte the
+// bitfield of a torrent with up to 2^21 - 8 pieces.
+pub const MAX_FRAME_LEN: usize = 256 * 1024;
+
+fn invalid_frame(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
 impl Decoder for PeerMessageCodec {
     type Item = PeerMessage;
     type Error = std::io::Error;
 
+    // Never panics, whatever the peer sends. A frame is only taken off the
+    // buffer once all of it has arrived, and its payload has to be exactly
+    // the size its message id calls for. Bitfields and blocks share the
+    // read buffer's memory rather than being copied out of it.
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if buf.len() < 4 {
-            return Ok(None);
-        }
+        // frames with ids we don't know are skipped, extensions we
+        // never asked for are free to send them anyway
+        let (id, mut frame, expected) = loop {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
 
-        // get the value of the first 4 bytes as a u32
-        // but do not advance.
-        let peek_len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
-        if peek_len == 0 {
+            // get the value of the first 4 bytes as a u32
+            // but do not advance.
+            let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if len > MAX_FRAME_LEN {
+                return Err(invalid_frame(format!("frame of {} bytes is too large", len)));
+            }
+            if buf.len() < 4 + len {
+                buf.reserve(4 + len - buf.len());
+                return Ok(None);
+            }
             buf.advance(4);
-            return Ok(Some(PeerMessage::KeepAlive));
-        }
+            if len == 0 {
+                return Ok(Some(PeerMessage::KeepAlive));
+            }
 
-        if  buf.len() < peek_len {
-            return Ok(None);
+            let mut frame = buf.split_to(len);
+            let id = frame.get_u8();
+            let expected = match id {
+                0..=3 => Some(0),
+                4 => Some(4),
+                6 | 8 => Some(12),
+                21 | 23 => Some(HASH_REQUEST_LEN),
+                5 | 7 | 22 => None,
+                _ => {
+                    trace!(id, len, "skipping unknown message");
+                    continue;
+                }
+            };
+            break (id, frame, expected);
+        };
+        if expected.is_some_and(|expected| frame.len() != expected) {
+            return Err(invalid_frame(format!(
+                "message {} has a {} byte payload",
+                id,
+                frame.len()
+            )));
         }
-        let len = buf.get_u32() as usize;
-        let id = buf.get_u8();
-        match id {
-            0 => Ok(Some(PeerMessage::Choke)),
-            1 => Ok(Some(PeerMessage::Unchoke)),
-            2 => Ok(Some(PeerMessage::Interested)),
-            3 => Ok(Some(PeerMessage::NotInterested)),
-            4 => {
-                let piece = buf.get_u32();
-                Ok(Some(PeerMessage::Have(piece)))
-            }
+
+        let msg = match id {
+            0 => PeerMessage::Choke,
+            1 => PeerMessage::Unchoke,
+            2 => PeerMessage::Interested,
+            3 => PeerMessage::NotInterested,
+            4 => PeerMessage::Have(frame.get_u32()),
             5 => {
-                let mut bitfield = vec![0; len - 1];
-                buf.copy_to_slice(&mut bitfield);
-                Ok(Some(PeerMessage::Bitfield(bitfield)))
-            }
-            6 => {
-                let index = buf.get_u32();
-                let begin = buf.get_u32();
-                let length = buf.get_u32();
-                Ok(Some(PeerMessage::Request { index, begin, length }))
+                if frame.is_empty() {
+                    return Err(invalid_frame("empty bitfield".to_owned()));
+                }
+                PeerMessage::Bitfield(frame.freeze())
             }
+            6 => PeerMessage::Request {
+                index: frame.get_u32(),
+                begin: frame.get_u32(),
+                length: frame.get_u32(),
+            },
             7 => {
-                let index = buf.get_u32();
-                let begin = buf.get_u32();
-                let block = buf.copy_to_bytes(len - 9).to_vec();
-                Ok(Some(PeerMessage::Piece { index, begin, block }))
+                if frame.len() <= 8 {
+                    return Err(invalid_frame("piece without a block".to_owned()));
+                }
+                PeerMessage::Piece {
+                    index: frame.get_u32(),
+                    begin: frame.get_u32(),
+                    block: frame.freeze(),
+                }
             }
-            8 => {
-                let index = buf.get_u32();
-                let begin = buf.get_u32();
-                let length = buf.get_u32();
-                Ok(Some(PeerMessage::Cancel { index, begin, length }))
+            8 => PeerMessage::Cancel {
+                index: frame.get_u32(),
+                begin: frame.get_u32(),
+                length: frame.get_u32(),
+            },
+            21 => PeerMessage::HashRequest(HashRequest::decode(&mut frame)),
+            22 => {
+                if frame.len() <= HASH_REQUEST_LEN || !(frame.len() - HASH_REQUEST_LEN).is_multiple_of(32) {
+                    return Err(invalid_frame("hashes that aren't whole 32 byte hashes".to_owned()));
+                }
+                PeerMessage::Hashes {
+                    request: HashRequest::decode(&mut frame),
+                    hashes: frame.freeze(),
+                }
             }
-            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid message id")),
-        }
+            _ => PeerMessage::HashReject(HashRequest::decode(&mut frame)),
+        };
+        Ok(Some(msg))
     }
 }
 
-pub async fn download_piece(t: &Torrent, tokio_stream: &mut tokio::net::TcpStream, index: usize) -> Vec<u8> {
-    let mut piece_vector : Vec<u8> = Vec::new();
-
-    let mut peer_framer = Framed::new(tokio_stream, PeerMessageCodec);
-    while let Some(msg) = peer_framer.next().await {
-        //eprintln!("got message: {:?}", msg);
-        match msg {
-            Ok(pm) => match pm {
-                PeerMessage::Bitfield(bf) => {
-                    eprintln!("got bitfield: {:?}", bf);
-                    break;
-                }
-                _ => eprintln!("Ignoring: {:?}", pm),
-            },
-            Err(e) => eprintln!("failed to get message: {:?}", e),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    // reads back whatever `bytes` a peer sent
+    async fn read(bytes: &[u8]) -> Result<Handshake, HandshakeError> {
+        let (mut ours, mut theirs) = tokio::io::duplex(128);
+        theirs.write_all(bytes).await.unwrap();
+        drop(theirs);
+        Handshake::read(&mut ours).await
     }
 
-    // send interested
-    peer_framer
-        .send(PeerMessage::Interested)
-        .await
-        .expect("failed to send interested");
+    fn handshake_bytes(reserved: [u8; 8], info_hash: [u8; 20], peer_id: [u8; 20]) -> Vec<u8> {
+        [&[19][..], PROTOCOL, &reserved, &info_hash, &peer_id].concat()
+    }
 
+    #[tokio::test]
+    async fn handshakes_round_trip() {
+        let peer_id = PeerId::generate();
+        let sent = Handshake::new(INFO_HASH, &peer_id).with_v2(true);
+        let (mut ours, mut theirs) = tokio::io::duplex(128);
+        sent.write(&mut theirs).await.unwrap();
+        let received = Handshake::read(&mut ours).await.unwrap();
+        assert_eq!(received.info_hash(), &INFO_HASH);
+        assert_eq!(&received.peer_id, peer_id.as_bytes());
+        assert_eq!(received.reserved, sent.reserved);
+    }
 
-    while let Some(msg) = peer_framer.next().await {
-        match msg {
-            Ok(pm) => match pm {
-                PeerMessage::Unchoke => {
-                    eprintln!("got unchoke");
-                    break;
-                }
-                _ => eprintln!("Ignoring: {:?}", pm),
-            },
-            Err(e) => eprintln!("failed to get message: {:?}", e),
+    #[tokio::test]
+    async fn other_protocols_are_refused() {
+        // a different length is refused without waiting for 68 bytes
+        let result = read(b"\x0fHTTP/1.1 200 OK").await;
+        assert!(matches!(result, Err(HandshakeError::Protocol(p)) if p == "HTTP/1.1 200 OK"));
+        let mut bytes = handshake_bytes([0; 8], INFO_HASH, [1; 20]);
+        bytes[1..20].copy_from_slice(b"BitTorrent protocoL");
+        assert!(matches!(read(&bytes).await, Err(HandshakeError::Protocol(p)) if p == "BitTorrent protocoL"));
+        // cut short
+        let bytes = handshake_bytes([0; 8], INFO_HASH, [1; 20]);
+        assert!(matches!(read(&bytes[..40]).await, Err(HandshakeError::Io(_))));
+        assert!(matches!(read(&[]).await, Err(HandshakeError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn replies_must_match_what_we_sent() {
+        let peer_id = PeerId::generate();
+        let ours = Handshake::new(INFO_HASH, &peer_id);
+
+        let other = read(&handshake_bytes([0; 8], [0xcd; 20], [1; 20])).await.unwrap();
+        match ours.validate(&other) {
+            Err(HandshakeError::InfoHash { ours, theirs }) => {
+                assert_eq!((ours, theirs), (hex::encode(INFO_HASH), hex::encode([0xcd; 20])));
+            }
+            result => panic!("expected an info hash mismatch, got {:?}", result),
         }
+        let us = read(&handshake_bytes([0; 8], INFO_HASH, *peer_id.as_bytes())).await.unwrap();
+        assert!(matches!(ours.validate(&us), Err(HandshakeError::SelfConnection)));
+        let them = read(&handshake_bytes([0; 8], INFO_HASH, [1; 20])).await.unwrap();
+        assert!(ours.validate(&them).is_ok());
+    }
+
+    #[tokio::test]
+    async fn perform_handshake_checks_the_reply() {
+        let ours = Handshake::new(INFO_HASH, &PeerId::generate());
+        let (mut stream, mut peer) = tokio::io::duplex(256);
+        peer.write_all(&handshake_bytes([0; 8], [0xcd; 20], [1; 20])).await.unwrap();
+        let result = ours.perform_handshake(&mut stream).await;
+        assert!(matches!(result, Err(HandshakeError::InfoHash { .. })));
+        // ours went out first regardless
+        let sent = Handshake::read(&mut peer).await.unwrap();
+        assert_eq!(sent.info_hash(), &INFO_HASH);
+    }
+
+    #[tokio::test]
+    async fn capabilities_come_from_the_reserved_bits() {
+        let capabilities = |reserved| async move {
+            read(&handshake_bytes(reserved, INFO_HASH, [1; 20])).await.unwrap().capabilities()
+        };
+        assert_eq!(capabilities([0; 8]).await, Capabilities::default());
+        assert_eq!(
+            capabilities([0, 0, 0, 0, 0, 0x10, 0, 0x15]).await,
+            Capabilities { dht: true, fast: true, extension: true, v2: true }
+        );
+        assert_eq!(capabilities([0, 0, 0, 0, 0, 0, 0, 0x04]).await, Capabilities { fast: true, ..Default::default() });
+        // bits we don't know about don't turn anything on
+        assert_eq!(capabilities([0xff, 0xff, 0xff, 0xff, 0xff, 0xef, 0xff, 0xea]).await, Capabilities::default());
+        // we only claim v2 when asked to
+        let peer_id = PeerId::generate();
+        assert!(!Handshake::new(INFO_HASH, &peer_id).with_v2(false).capabilities().v2);
+        assert!(Handshake::new(INFO_HASH, &peer_id).with_v2(true).capabilities().v2);
     }
 
-    // we want to get 1..n pieces
-    let piece_index = index as usize;
-    let mut offset = 0;
-    let block_size = 16384;
-    let mut left = t.info.plen; // length of piece
-    
-    //if the piece is the last piece, the length may be less than the piece length
-    if (piece_index + 1)  * t.info.plen > t.info.length {
-        left = (t.info.length).rem_euclid(t.info.plen) as usize;
+    fn encode(msg: PeerMessage) -> BytesMut {
+        let mut buf = BytesMut::new();
+        PeerMessageCodec.encode(msg, &mut buf).unwrap();
+        buf
     }
 
-    //we want to keep tabs on the number of bytes left to download
-    while left > 0 
-    {
+    // decodes exactly one frame out of `buf`
+    fn decode(mut buf: BytesMut) -> std::io::Result<PeerMessage> {
+        let msg = PeerMessageCodec.decode(&mut buf)?.expect("a whole frame");
+        assert!(buf.is_empty(), "{} bytes left over", buf.len());
+        Ok(msg)
+    }
 
-        let block_len =  left.min(block_size) as u32; //either block size or remainder of piece
-        //eprintln!("left: {} next: {}", left, block_len);
+    const REQUEST: HashRequest = HashRequest {
+        pieces_root: [7; 32],
+        base_layer: 1,
+        index: 4,
+        length: 2,
+        proof_layers: 3,
+    };
 
-        //request piece
-        let request = PeerMessage::Request {
-            index: piece_index as u32,
-            begin: offset as u32,
-            length: block_len,
-        };
-        eprintln!("requesting piece: {} {} {}", piece_index, offset, block_len);
-        match peer_framer.send(request).await {
-            Ok(_) => eprintln!("sent request"),
-            Err(e) => {
-                eprintln!("failed to send request: {:?}", e);
-                panic!("failed to send request");
+    #[test]
+    fn hash_messages_round_trip() {
+        let buf = encode(PeerMessage::HashRequest(REQUEST));
+        assert_eq!(buf.len(), 4 + 1 + HASH_REQUEST_LEN);
+        assert_eq!(buf[4], 21);
+        assert!(matches!(decode(buf).unwrap(), PeerMessage::HashRequest(r) if r == REQUEST));
+
+        let hashes = Bytes::from(vec![9; 3 * 32]);
+        let buf = encode(PeerMessage::Hashes { request: REQUEST, hashes: hashes.clone() });
+        assert_eq!(buf[4], 22);
+        match decode(buf).unwrap() {
+            PeerMessage::Hashes { request, hashes: got } => {
+                assert_eq!(request, REQUEST);
+                assert_eq!(got, hashes);
             }
+            msg => panic!("decoded {:?}", msg),
         }
 
-        match peer_framer.next().await {
-            Some(Ok(PeerMessage::Piece {
-                index,
-                begin,
-                block,
-            })) => {
-                eprintln!("got piece: {} {} {}", index, begin, block.len());
-                left -= block_len as usize;
-                offset += block_len as usize;
-                //piece_index += 1;
+        let buf = encode(PeerMessage::HashReject(REQUEST));
+        assert_eq!(buf[4], 23);
+        assert!(matches!(decode(buf).unwrap(), PeerMessage::HashReject(r) if r == REQUEST));
+    }
 
-                //append block to piece vector
-                piece_vector.extend_from_slice(&block);
+    #[test]
+    fn hashes_must_be_whole() {
+        let mut buf = encode(PeerMessage::Hashes { request: REQUEST, hashes: Bytes::from(vec![9; 32]) });
+        // one byte short of a second hash
+        buf.truncate(buf.len() - 1);
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        assert!(decode(buf).is_err());
 
-            }
-            Some(Ok(v)) => {
-                eprintln!("Ignoring: {:?}", v);
-                //panic!("failed to get piece");
-            }
-            Some(Err(e)) => {
-                eprintln!("failed to get message: {:?}", e);
-                panic!("failed to get piece");
-            }
-            None => {
-                eprintln!("no message ");
-                break;
-            }
-        };
+        // a request with no hashes after it
+        let mut buf = encode(PeerMessage::HashReject(REQUEST));
+        buf[4] = 22;
+        assert!(decode(buf).is_err());
+
+        let mut buf = encode(PeerMessage::HashRequest(REQUEST));
+        buf.extend_from_slice(&[0; 4]);
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        assert!(decode(buf).is_err());
     }
-    piece_vector
-}
 
+    fn frame(id: u8, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u32(1 + payload.len() as u32);
+        buf.put_u8(id);
+        buf.put_slice(payload);
+        buf
+    }
 
+    fn error(buf: BytesMut) -> String {
+        let err = decode(buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        err.to_string()
+    }
+
+    // turned away on the length prefix alone, before any of it is buffered
+    #[test]
+    fn oversize_frames_are_rejected() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_FRAME_LEN as u32 + 1);
+        assert!(error(buf).contains("too large"));
+        let mut buf = BytesMut::new();
+        buf.put_u32(u32::MAX);
+        assert!(PeerMessageCodec.decode(&mut buf).is_err());
+
+        // the largest frame allowed waits for the rest, then decodes
+        let block = vec![1; MAX_FRAME_LEN - 9];
+        let mut buf = frame(7, &[[0; 8].as_slice(), &block].concat());
+        let mut partial = buf.split_to(1000);
+        assert!(PeerMessageCodec.decode(&mut partial).unwrap().is_none());
+        assert_eq!(partial.len(), 1000);
+        partial.unsplit(buf);
+        assert!(matches!(decode(partial).unwrap(), PeerMessage::Piece { block: b, .. } if b == block));
+    }
 
+    #[test]
+    fn fixed_size_messages_must_be_exactly_that_size() {
+        for (id, len) in [(0, 0), (1, 0), (2, 0), (3, 0), (4, 4), (6, 12), (8, 12), (21, 48), (23, 48)] {
+            assert!(decode(frame(id, &vec![0; len])).is_ok(), "message {}", id);
+            let short = len.checked_sub(1).map(|len| frame(id, &vec![0; len]));
+            for buf in short.into_iter().chain([frame(id, &vec![0; len + 1])]) {
+                assert!(error(buf).contains(&format!("message {} has a", id)));
+            }
+        }
+    }
+
+    #[test]
+    fn variable_size_messages_need_a_payload() {
+        assert!(error(frame(5, &[])).contains("empty bitfield"));
+        assert!(matches!(decode(frame(5, &[0x80])).unwrap(), PeerMessage::Bitfield(b) if b[..] == [0x80]));
+        assert!(error(frame(7, &[0; 8])).contains("without a block"));
+        assert!(error(frame(7, &[0; 3])).contains("without a block"));
+    }
+
+    // whatever follows an unknown message still decodes
+    #[test]
+    fn unknown_ids_are_skipped() {
+        for id in [9, 10, 13, 17, 20, 24, 255] {
+            let mut buf = frame(id, &[0; 4]);
+            buf.extend_from_slice(&frame(9, &[]));
+            buf.extend_from_slice(&frame(4, &[0, 0, 0, 7]));
+            assert!(matches!(decode(buf).unwrap(), PeerMessage::Have(7)), "after id {}", id);
+        }
+        // an unknown message on its own is consumed, waiting for the next
+        let mut buf = frame(20, &[0; 4]);
+        assert!(PeerMessageCodec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+}
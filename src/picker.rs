@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bitfield::Bitfield;
+use crate::error::{Error, Result};
+use crate::torrent::{display_path, File, Info};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    // not downloaded, and not created on disk
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+// The order pieces are fetched in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadMode {
+    // most important files first, in no particular order within them
+    #[default]
+    Normal,
+    // front to back, so a file can be used as soon as its start is in
+    Sequential,
+    // front to back, but the pieces just ahead of every reader go first
+    Streaming,
+}
+
+// how many pieces ahead of a reader get deadlines
+pub const STREAM_WINDOW: usize = 8;
+// the time a reader is assumed to take to get through a piece
+const DEADLINE_STEP: Duration = Duration::from_millis(500);
+// pieces due this soon are only given to the faster half of the peers
+const URGENT: Duration = Duration::from_secs(2);
+
+// When pieces are needed by, earliest first.
+#[derive(Debug, Clone, Default)]
+pub struct Deadlines {
+    pieces: BTreeMap<usize, Instant>,
+}
+
+impl Deadlines {
+    // A reader at `piece` since `at` needs it straight away and each of
+    // the next few a little later. Overlapping readers keep the earlier
+    // deadline.
+    pub fn reader_at(&mut self, piece: usize, at: Instant, piece_count: usize) {
+        for (k, index) in (piece..piece_count).take(STREAM_WINDOW).enumerate() {
+            let due = at + DEADLINE_STEP * k as u32;
+            self.pieces
+                .entry(index)
+                .and_modify(|d| *d = (*d).min(due))
+                .or_insert(due);
+        }
+    }
+
+    pub fn get(&self, piece: usize) -> Option<Instant> {
+        self.pieces.get(&piece).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+}
+
+// Priorities for the files whose paths match any of `patterns`, the rest
+// are skipped. Matching nothing at all is an error, it's almost certainly
+// a typo.
+pub fn select_files(files: &[File], patterns: &[glob::Pattern]) -> Result<Vec<FilePriority>> {
+    let priorities: Vec<_> = files
+        .iter()
+        .map(|file| {
+            let path = display_path(&file.path);
+            match patterns.iter().any(|p| p.matches(&path)) {
+                true => FilePriority::Normal,
+                false => FilePriority::Skip,
+            }
+        })
+        .collect();
+    if priorities.iter().all(|p| *p == FilePriority::Skip) {
+        let patterns: Vec<_> = patterns.iter().map(glob::Pattern::as_str).collect();
+        return Err(Error::Usage(format!("no files match {}", patterns.join(", "))));
+    }
+    Ok(priorities)
+}
+
+// Chooses what to ask a peer for next. A piece is worth as much as the
+// most important file it holds data for, so a piece on the edge of a
+// wanted and a skipped file is still fetched, and a piece only skipped
+// files touch never is.
+#[derive(Debug, Clone)]
+pub struct Picker {
+    pieces: Vec<FilePriority>,
+    mode: DownloadMode,
+}
+
+impl Picker {
+    pub fn new(info: &Info, files: &[File], priorities: &[FilePriority], mode: DownloadMode) -> Self {
+        let mut pieces = vec![FilePriority::Skip; info.piece_count()];
+        for (file, &priority) in files.iter().zip(priorities) {
+            for index in file.pieces(info.plen) {
+                pieces[index] = pieces[index].max(priority);
+            }
+        }
+        Self { pieces, mode }
+    }
+
+    pub fn mode(&self) -> DownloadMode {
+        self.mode
+    }
+
+    pub fn wanted(&self) -> Bitfield {
+        let mut wanted = Bitfield::new(self.pieces.len());
+        for (index, &priority) in self.pieces.iter().enumerate() {
+            wanted.set(index, priority != FilePriority::Skip);
+        }
+        wanted
+    }
+
+    // The next piece we still need that `available` says the peer has.
+    // When streaming, the piece due soonest goes first, unless it's
+    // urgent and the peer isn't `fast`, a slow peer holding it up would
+    // stall the reader. Otherwise it's the most important piece, lowest
+    // index first among equals, or just the lowest index when going in
+    // order.
+    pub fn pick(
+        &self,
+        have: &Bitfield,
+        available: impl Fn(usize) -> bool,
+        deadlines: &Deadlines,
+        fast: bool,
+    ) -> Option<usize> {
+        let streaming = self.mode == DownloadMode::Streaming && !deadlines.is_empty();
+        let urgent = Instant::now() + URGENT;
+        let wanted = |index: usize| {
+            self.pieces[index] != FilePriority::Skip
+                && !have.get(index)
+                && available(index)
+                && (fast || !streaming || deadlines.get(index).is_none_or(|due| due > urgent))
+        };
+
+        if streaming {
+            let due = deadlines
+                .pieces
+                .iter()
+                .filter(|(&index, _)| index < self.pieces.len() && wanted(index))
+                .min_by_key(|(_, &due)| due);
+            if let Some((&index, _)) = due {
+                return Some(index);
+            }
+        }
+
+        let mut best: Option<usize> = None;
+        for (index, &priority) in self.pieces.iter().enumerate() {
+            if !wanted(index) {
+                continue;
+            }
+            if self.mode != DownloadMode::Normal {
+                return Some(index);
+            }
+            if best.is_none_or(|best| priority > self.pieces[best]) {
+                best = Some(index);
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::tests::multi_file;
+
+    // pieces of 16: a is 0..10, b 10..40, c 40..64, d 64..70
+    fn files() -> (Info, Vec<File>) {
+        let torrent = multi_file(&[("a", 10), ("dir/b", 30), ("c", 24), ("d", 6)], 16);
+        let files = torrent.info.files().unwrap();
+        (torrent.info, files)
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<glob::Pattern> {
+        patterns.iter().map(|p| glob::Pattern::new(p).unwrap()).collect()
+    }
+
+    fn pick_all(picker: &Picker, piece_count: usize) -> Vec<usize> {
+        let mut have = Bitfield::new(piece_count);
+        let mut picked = Vec::new();
+        while let Some(index) = picker.pick(&have, |_| true, &Deadlines::default(), true) {
+            have.set(index, true);
+            picked.push(index);
+        }
+        picked
+    }
+
+    #[test]
+    fn select_files_matches_paths_with_forward_slashes() {
+        let (_, files) = files();
+        use FilePriority::{Normal, Skip};
+        assert_eq!(select_files(&files, &patterns(&["dir/*"])).unwrap(), [Skip, Normal, Skip, Skip]);
+        assert_eq!(select_files(&files, &patterns(&["a", "d"])).unwrap(), [Normal, Skip, Skip, Normal]);
+        assert!(matches!(select_files(&files, &patterns(&["nope"])), Err(Error::Usage(_))));
+    }
+
+    // b's first and last pieces are shared with a and c, they're fetched
+    // even though a and c aren't wanted
+    #[test]
+    fn edge_pieces_of_wanted_files_are_wanted() {
+        let (info, files) = files();
+        use FilePriority::{Normal, Skip};
+        let picker = Picker::new(&info, &files, &[Skip, Normal, Skip, Skip], DownloadMode::Normal);
+        assert_eq!(picker.wanted().ones().collect::<Vec<_>>(), [0, 1, 2]);
+
+        let picker = Picker::new(&info, &files, &[Skip, Skip, Skip, Normal], DownloadMode::Normal);
+        assert_eq!(picker.wanted().ones().collect::<Vec<_>>(), [4]);
+
+        let picker = Picker::new(&info, &files, &[Skip; 4], DownloadMode::Normal);
+        assert!(picker.wanted().none());
+        assert_eq!(picker.pick(&Bitfield::new(5), |_| true, &Deadlines::default(), true), None);
+    }
+
+    #[test]
+    fn normal_mode_goes_by_priority_then_index() {
+        let (info, files) = files();
+        use FilePriority::{High, Low, Normal};
+        let picker = Picker::new(&info, &files, &[Low, Normal, Low, High], DownloadMode::Normal);
+        // the piece c shares with b is worth as much as b
+        assert_eq!(pick_all(&picker, 5), [4, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn pick_skips_what_we_have_and_the_peer_lacks() {
+        let (info, files) = files();
+        let picker = Picker::new(&info, &files, &[FilePriority::Normal; 4], DownloadMode::Normal);
+        let mut have = Bitfield::new(5);
+        have.set(0, true);
+        assert_eq!(picker.pick(&have, |i| i != 1, &Deadlines::default(), true), Some(2));
+        assert_eq!(picker.pick(&have, |i| i == 0, &Deadlines::default(), true), None);
+    }
+
+    #[test]
+    fn reader_deadlines_step_through_the_window() {
+        let now = Instant::now();
+        let mut deadlines = Deadlines::default();
+        deadlines.reader_at(2, now, 100);
+        assert_eq!(deadlines.get(1), None);
+        assert_eq!(deadlines.get(2), Some(now));
+        assert_eq!(deadlines.get(5), Some(now + DEADLINE_STEP * 3));
+        assert_eq!(deadlines.get(2 + STREAM_WINDOW - 1), Some(now + DEADLINE_STEP * (STREAM_WINDOW as u32 - 1)));
+        assert_eq!(deadlines.get(2 + STREAM_WINDOW), None);
+
+        // a second reader further on, the overlap keeps the earlier due
+        deadlines.reader_at(4, now, 100);
+        assert_eq!(deadlines.get(4), Some(now));
+        assert_eq!(deadlines.get(6), Some(now + DEADLINE_STEP * 2));
+        // a later one doesn't push anything back
+        deadlines.reader_at(2, now + Duration::from_secs(60), 100);
+        assert_eq!(deadlines.get(2), Some(now));
+    }
+
+    #[test]
+    fn reader_deadlines_stop_at_the_last_piece() {
+        let now = Instant::now();
+        let mut deadlines = Deadlines::default();
+        deadlines.reader_at(3, now, 5);
+        assert_eq!(deadlines.pieces.keys().copied().collect::<Vec<_>>(), [3, 4]);
+    }
+
+    #[test]
+    fn sequential_mode_ignores_priority() {
+        let (info, files) = files();
+        use FilePriority::{High, Low, Normal};
+        let picker = Picker::new(&info, &files, &[Low, Normal, Low, High], DownloadMode::Sequential);
+        assert_eq!(pick_all(&picker, 5), [0, 1, 2, 3, 4]);
+        // deadlines only count when streaming
+        let mut deadlines = Deadlines::default();
+        deadlines.reader_at(3, Instant::now(), 5);
+        assert_eq!(picker.pick(&Bitfield::new(5), |_| true, &deadlines, true), Some(0));
+    }
+
+    #[test]
+    fn streaming_fetches_what_readers_need_first() {
+        let (info, files) = files();
+        let picker = Picker::new(&info, &files, &[FilePriority::Normal; 4], DownloadMode::Streaming);
+        // without a reader it's front to back
+        assert_eq!(picker.pick(&Bitfield::new(5), |_| true, &Deadlines::default(), true), Some(0));
+
+        let later = Instant::now() + URGENT * 10;
+        let mut deadlines = Deadlines::default();
+        deadlines.reader_at(3, later, 5);
+        let mut have = Bitfield::new(5);
+        assert_eq!(picker.pick(&have, |_| true, &deadlines, false), Some(3));
+        have.set(3, true);
+        assert_eq!(picker.pick(&have, |_| true, &deadlines, false), Some(4));
+        // nothing due the peer has, so front to back again
+        assert_eq!(picker.pick(&have, |i| i < 3, &deadlines, false), Some(0));
+    }
+
+    // a slow peer would hold up the reader, it gets something else
+    #[test]
+    fn urgent_pieces_only_go_to_fast_peers() {
+        let (info, files) = files();
+        let picker = Picker::new(&info, &files, &[FilePriority::Normal; 4], DownloadMode::Streaming);
+        let mut deadlines = Deadlines::default();
+        deadlines.reader_at(3, Instant::now(), 5);
+        let have = Bitfield::new(5);
+        assert_eq!(picker.pick(&have, |_| true, &deadlines, true), Some(3));
+        assert_eq!(picker.pick(&have, |_| true, &deadlines, false), Some(0));
+        assert_eq!(picker.pick(&have, |i| i >= 3, &deadlines, false), None);
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{IsTerminal, Write};
+use std::time::Duration;
+
+use bittorrent_starter_rust::TorrentStats;
+
+// how often the bar is redrawn or a status line written
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+const BAR_WIDTH: usize = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressStyle {
+    /// A progress bar on stderr, when it's a terminal
+    Bar,
+    /// One JSON object per line on stderr
+    Json,
+    Off,
+}
+
+// Draws torrent stats on stderr in whichever style was asked for.
+pub struct Reporter {
+    style: ProgressStyle,
+    // whether the bar is on screen and has to be cleared before anything
+    // else is printed
+    drawn: bool,
+}
+
+impl Reporter {
+    pub fn new(style: ProgressStyle) -> Self {
+        // a bar is just noise in a log file
+        let style = match style {
+            ProgressStyle::Bar if !std::io::stderr().is_terminal() => ProgressStyle::Off,
+            style => style,
+        };
+        Self { style, drawn: false }
+    }
+
+    pub fn report(&mut self, stats: &TorrentStats) {
+        let mut stderr = std::io::stderr().lock();
+        let _ = match self.style {
+            ProgressStyle::Bar => {
+                self.drawn = true;
+                write!(stderr, "\r\x1b[K{}", bar(stats))
+            }
+            ProgressStyle::Json => match serde_json::to_string(stats) {
+                Ok(line) => writeln!(stderr, "{}", line),
+                Err(_) => Ok(()),
+            },
+            ProgressStyle::Off => Ok(()),
+        };
+        let _ = stderr.flush();
+    }
+
+    // a last report that stays on screen
+    pub fn finish(mut self, stats: &TorrentStats) {
+        self.report(stats);
+        if self.drawn {
+            eprintln!();
+            self.drawn = false;
+        }
+    }
+
+    // takes the bar off the screen until the next report
+    pub fn clear(&mut self) {
+        if self.drawn {
+            eprint!("\r\x1b[K");
+            self.drawn = false;
+        }
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+fn bar(stats: &TorrentStats) -> String {
+    let done = match stats.piece_count {
+        0 => 1.0,
+        count => stats.pieces_done as f64 / count as f64,
+    };
+    let filled = (done * BAR_WIDTH as f64) as usize;
+    let eta = match stats.eta_secs {
+        Some(secs) => format!("{}:{:02}", secs / 60, secs % 60),
+        None => "-".to_owned(),
+    };
+    format!(
+        "{} [{}{}] {:5.1}% {}/{} pieces  {} down  {} up  {} peers ({} choking)  ETA {}",
+        stats.name,
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        done * 100.0,
+        stats.pieces_done,
+        stats.piece_count,
+        rate(stats.transfer.download_rate),
+        rate(stats.transfer.upload_rate),
+        stats.transfer.peers,
+        stats.transfer.peers_choking,
+        eta,
+    )
+}
+
+fn rate(bytes_per_sec: u64) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+    let mut rate = bytes_per_sec as f64;
+    let mut unit = 0;
+    while rate >= 1024.0 && unit < UNITS.len() - 1 {
+        rate /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", rate, UNITS[unit])
+}
@@ -0,0 +1,138 @@
+use std::cmp::Reverse;
+
+use serde::{Deserialize, Serialize};
+
+use crate::session::{State, TorrentHandle};
+
+pub const DEFAULT_ACTIVE_DOWNLOADS: usize = 3;
+pub const DEFAULT_ACTIVE_SEEDS: usize = 5;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+// How many torrents may run at once, None for no limit. Downloads and
+// seeds are counted separately so finished torrents don't hold up new
+// ones.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueLimits {
+    pub downloads: Option<usize>,
+    pub seeds: Option<usize>,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            downloads: Some(DEFAULT_ACTIVE_DOWNLOADS),
+            seeds: Some(DEFAULT_ACTIVE_SEEDS),
+        }
+    }
+}
+
+// Decides which of the started torrents get to run. The highest priority
+// goes first, then whichever was added earliest, until each kind is at
+// its limit. Everything else waits in the queue, which can mean pushing a
+// running torrent back into it when something more important turns up.
+pub fn reschedule(torrents: &[TorrentHandle], limits: QueueLimits) {
+    let mut started: Vec<&TorrentHandle> = torrents
+        .iter()
+        .filter(|t| matches!(t.state(), State::Queued | State::Running))
+        .collect();
+    started.sort_by_key(|t| (Reverse(t.priority()), t.added()));
+
+    let (mut downloads, mut seeds) = (0, 0);
+    for torrent in started {
+        let (active, limit) = if torrent.progress().is_complete() {
+            (&mut seeds, limits.seeds)
+        } else {
+            (&mut downloads, limits.downloads)
+        };
+        let run = limit.is_none_or(|limit| *active < limit);
+        if run {
+            *active += 1;
+        }
+        torrent.set_queued(!run);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::session::{Session, SessionConfig};
+    use crate::torrent::tests::single_file;
+
+    // Four finished torrents, so they all count against the seed limit and
+    // stay running without a tracker.
+    async fn seeds(session: &Session, dir: &std::path::Path) -> Vec<TorrentHandle> {
+        let mut handles = Vec::new();
+        for i in 0..4 {
+            let name = format!("seed{}", i);
+            let data = vec![i as u8; 1000];
+            std::fs::write(dir.join(&name), &data).unwrap();
+            let torrent = single_file(&name, &data, 16 * 1024);
+            let handle = session.add_torrent(torrent, dir.join(&name)).await.unwrap();
+            assert!(handle.progress().is_complete());
+            handles.push(handle);
+        }
+        handles
+    }
+
+    async fn settles_on(handles: &[TorrentHandle], running: &[usize]) {
+        let expected: Vec<State> = (0..handles.len())
+            .map(|i| if running.contains(&i) { State::Running } else { State::Queued })
+            .collect();
+        for _ in 0..200 {
+            if handles.iter().map(TorrentHandle::state).eq(expected.iter().copied()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let states: Vec<State> = handles.iter().map(TorrentHandle::state).collect();
+        panic!("expected {:?}, got {:?}", expected, states);
+    }
+
+    #[tokio::test]
+    async fn runs_by_priority_then_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(SessionConfig {
+            port: 0,
+            queue: QueueLimits {
+                downloads: None,
+                seeds: Some(2),
+            },
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap();
+        let handles = seeds(&session, dir.path()).await;
+        handles[1].set_priority(Priority::Low);
+        handles[2].set_priority(Priority::High);
+        for handle in &handles {
+            handle.start();
+        }
+        settles_on(&handles, &[0, 2]).await;
+
+        // something more important pushes the youngest normal one back
+        handles[3].set_priority(Priority::High);
+        settles_on(&handles, &[2, 3]).await;
+
+        // and a paused torrent frees its slot for the next in line
+        handles[2].pause();
+        for _ in 0..200 {
+            if handles[0].state() == State::Running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(handles[0].state(), State::Running);
+        assert_eq!(handles[1].state(), State::Queued);
+        assert_eq!(handles[2].state(), State::Paused);
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::session::TorrentHandle;
+use crate::torrent;
+
+// One file of a torrent as a regular async file, readable while it's still
+// downloading. A read waits for the piece it's in, and where the reader is
+// decides which pieces a streaming torrent fetches first.
+pub struct FileReader {
+    handle: TorrentHandle,
+    id: u64,
+    file: torrent::File,
+    // position in the file
+    pos: u64,
+    // what's been read from the piece `pos` is in, starting at `chunk_at`
+    chunk: Vec<u8>,
+    chunk_at: u64,
+    // the piece being waited on, with the file position it starts at
+    read: Option<(u64, BoxFuture<'static, io::Result<Vec<u8>>>)>,
+}
+
+impl FileReader {
+    pub(crate) fn new(handle: TorrentHandle, id: u64, file: torrent::File) -> Self {
+        let mut reader = Self {
+            handle,
+            id,
+            file,
+            pos: 0,
+            chunk: Vec::new(),
+            chunk_at: 0,
+            read: None,
+        };
+        reader.moved();
+        reader
+    }
+
+    pub fn len(&self) -> u64 {
+        self.file.length as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file.length == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    // the piece holding `pos`, clamped to the last one of the file so
+    // sitting at the end still counts as reading it
+    fn piece(&self) -> usize {
+        let offset = self.file.offset + (self.pos as usize).min(self.file.length.saturating_sub(1));
+        offset / self.handle.torrent().info.plen
+    }
+
+    fn moved(&mut self) {
+        self.handle.reader_moved(self.id, self.piece());
+    }
+
+    // from `pos` up to the end of its piece, or of the file if that's sooner
+    fn start_read(&mut self) {
+        let info = &self.handle.torrent().info;
+        let offset = self.file.offset + self.pos as usize;
+        let index = offset / info.plen;
+        let begin = offset % info.plen;
+        let len = (info.piece_len(index) - begin).min(self.file.length - self.pos as usize);
+        let handle = self.handle.clone();
+        let read = async move { handle.read_piece(index, begin, len).await }.boxed();
+        self.read = Some((self.pos, read));
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos >= this.len() || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let chunk_end = this.chunk_at + this.chunk.len() as u64;
+            if (this.chunk_at..chunk_end).contains(&this.pos) {
+                let start = (this.pos - this.chunk_at) as usize;
+                let n = buf.remaining().min(this.chunk.len() - start);
+                buf.put_slice(&this.chunk[start..start + n]);
+                this.pos += n as u64;
+                this.moved();
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.read.is_none() {
+                this.start_read();
+            }
+            let (at, read) = this.read.as_mut().expect("started");
+            let chunk = ready!(read.as_mut().poll(cx));
+            let at = *at;
+            this.read = None;
+            this.chunk = chunk?;
+            this.chunk_at = at;
+        }
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => this.len().checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.pos.checked_add_signed(delta),
+        };
+        let pos = pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file"))?;
+        if pos != this.pos {
+            this.pos = pos;
+            this.read = None;
+            this.moved();
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+impl Drop for FileReader {
+    fn drop(&mut self) {
+        self.handle.reader_dropped(self.id);
+    }
+}
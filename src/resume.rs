@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::picker::{DownloadMode, FilePriority};
+use crate::queue::Priority;
+use crate::session::{State, TorrentHandle};
+use crate::torrent::Torrent;
+
+const STATE_FILE: &str = "session.json";
+// how long pieces coming in can go unsaved, a crash loses at most this
+// much progress and otherwise the pieces are rechecked
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// What's kept of a torrent between runs, next to a copy of its metainfo
+// in `<info hash>.torrent`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedTorrent {
+    pub info_hash: String,
+    pub save_path: PathBuf,
+    pub priority: Priority,
+    pub paused: bool,
+    // position in the queue
+    pub added: u64,
+    // wire format bitfield of the pieces verified on disk
+    pub have: String,
+    // empty when every file is wanted
+    #[serde(default)]
+    pub file_priorities: Vec<FilePriority>,
+    #[serde(default)]
+    pub mode: DownloadMode,
+}
+
+// Everything `save` writes, taken in one go so the scheduler can tell
+// pieces coming in from changes worth saving straight away.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SavedSession {
+    torrents: Vec<SavedTorrent>,
+}
+
+impl SavedSession {
+    pub fn of(torrents: &[TorrentHandle]) -> Self {
+        let mut torrents: Vec<SavedTorrent> = torrents
+            .iter()
+            .map(|torrent| SavedTorrent {
+                info_hash: hex::encode(torrent.info_hash()),
+                save_path: torrent.save_path().to_owned(),
+                priority: torrent.priority(),
+                paused: torrent.state() == State::Paused,
+                added: torrent.added(),
+                have: hex::encode(torrent.have().as_bytes()),
+                file_priorities: torrent.file_priorities(),
+                mode: torrent.mode(),
+            })
+            .collect();
+        torrents.sort_by_key(|t| t.added);
+        Self { torrents }
+    }
+
+    // the same torrents in the same state, whatever pieces they have
+    pub fn same_but_pieces(&self, other: &Self) -> bool {
+        let without_pieces = |t: &SavedTorrent| SavedTorrent {
+            have: String::new(),
+            ..t.clone()
+        };
+        self.torrents.len() == other.torrents.len()
+            && self
+                .torrents
+                .iter()
+                .zip(&other.torrents)
+                .all(|(a, b)| without_pieces(a) == without_pieces(b))
+    }
+
+    // Writes the whole session out, through a temporary file so a crash
+    // part way leaves the previous state in place.
+    pub async fn save(&self, dir: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(std::io::Error::other)?;
+        let tmp = dir.join(format!("{}.tmp", STATE_FILE));
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(&tmp, json).await?;
+        tokio::fs::rename(&tmp, dir.join(STATE_FILE)).await?;
+        Ok(())
+    }
+}
+
+fn metainfo_path(dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    dir.join(format!("{}.torrent", hex::encode(info_hash)))
+}
+
+pub async fn save_metainfo(dir: &Path, info_hash: &[u8; 20], torrent: &Torrent) -> Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    tokio::fs::write(metainfo_path(dir, info_hash), torrent.to_bytes()).await?;
+    Ok(())
+}
+
+pub fn remove_metainfo(dir: &Path, info_hash: &[u8; 20]) {
+    // already gone is fine
+    let _ = std::fs::remove_file(metainfo_path(dir, info_hash));
+}
+
+// Every torrent saved in `dir` with its metainfo, in queue order. Nothing
+// saved yet is an empty list.
+pub async fn load(dir: &Path) -> Result<Vec<(SavedTorrent, Result<Torrent>)>> {
+    let json = match tokio::fs::read(dir.join(STATE_FILE)).await {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let saved: SavedSession =
+        serde_json::from_slice(&json).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let mut torrents = Vec::new();
+    for torrent in saved.torrents {
+        let metainfo = match tokio::fs::read(dir.join(format!("{}.torrent", torrent.info_hash))).await {
+            Ok(encoded) => Torrent::from_bytes(&encoded),
+            Err(e) => Err(e.into()),
+        };
+        torrents.push((torrent, metainfo));
+    }
+    Ok(torrents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{Session, SessionConfig};
+    use crate::torrent::tests::{multi_file, single_file};
+
+    fn config(state_dir: &Path) -> SessionConfig {
+        SessionConfig {
+            port: 0,
+            state_dir: Some(state_dir.to_owned()),
+            ..SessionConfig::default()
+        }
+    }
+
+    // what a torrent was left with comes back after a restart, pieces
+    // and all
+    #[tokio::test]
+    async fn session_round_trips() {
+        let state = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..40000u32).map(|i| (i % 251) as u8).collect();
+        let mut on_disk = data.clone();
+        // the second piece fails its hash
+        on_disk[20000] ^= 0xff;
+        std::fs::write(dir.path().join("partial.bin"), &on_disk).unwrap();
+        std::fs::write(dir.path().join("seed.bin"), &data[..1000]).unwrap();
+
+        let session = Session::new(config(state.path())).await.unwrap();
+        let partial = session
+            .add_torrent(single_file("partial.bin", &data, 16 * 1024), dir.path().join("partial.bin"))
+            .await
+            .unwrap();
+        assert_eq!(partial.have().ones().collect::<Vec<_>>(), [0, 2]);
+        partial.set_priority(Priority::High);
+        partial.set_mode(DownloadMode::Sequential);
+        let multi = session
+            .add_torrent(multi_file(&[("a", 10), ("b", 20)], 16), dir.path().join("multi"))
+            .await
+            .unwrap();
+        multi.set_file_priority(1, FilePriority::Skip).await.unwrap();
+        let seed = session
+            .add_torrent(single_file("seed.bin", &data[..1000], 16 * 1024), dir.path().join("seed.bin"))
+            .await
+            .unwrap();
+        seed.start();
+
+        let before = SavedSession::of(&session.torrents());
+        assert_eq!(before.torrents.iter().filter(|t| t.paused).count(), 2);
+        session.save().await.unwrap();
+        drop(session);
+
+        let loaded = load(state.path()).await.unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert!(loaded.iter().all(|(_, torrent)| torrent.is_ok()));
+        let restored = Session::new(config(state.path())).await.unwrap();
+        assert_eq!(SavedSession::of(&restored.torrents()), before);
+    }
+
+    #[tokio::test]
+    async fn nothing_saved_is_an_empty_session() {
+        let state = tempfile::tempdir().unwrap();
+        assert!(load(state.path()).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,685 @@
+//! JSON-RPC 2.0 control API for a running Session, one JSON object per
+//! line over a Unix socket. Methods take named params:
+//!
+//! - `add {torrent, save_path, paused?, priority?, only?, mode?}` adds a
+//!   .torrent file, both paths as the daemon sees them, `only` being glob
+//!   patterns for the files to download and `mode` normal, sequential or
+//!   streaming
+//! - `remove`, `pause`, `resume`, `stats {info_hash}`, where an info hash
+//!   can be shortened to any prefix that's unique in the session
+//! - `set_priority {info_hash, priority}`
+//! - `set_rate {info_hash?, download?, upload?}`, limits in bytes per
+//!   second, left out or null for none. Without an info hash they're the
+//!   session's, which every torrent's limits count against
+//! - `set_file_priority {info_hash, file, priority}`, `files {info_hash}`
+//! - `list`, `session`
+//! - `subscribe`, after which the connection also gets an `event`
+//!   notification `{info_hash, event}` for everything any torrent emits
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, warn};
+
+use crate::error::{Error, Result};
+use crate::picker::{self, DownloadMode, FilePriority};
+use crate::queue::Priority;
+use crate::session::{AddOptions, Event, Session, State, TorrentHandle};
+use crate::torrent::{display_path, Torrent};
+
+// where the daemon listens unless told otherwise
+pub fn default_socket() -> PathBuf {
+    std::env::temp_dir().join("your_bittorrent.sock")
+}
+
+// the error codes JSON-RPC reserves, anything else going wrong in a call
+// is reported as SERVER_ERROR
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        Self::new(SERVER_ERROR, e)
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    // absent for notifications, which get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    // only set on notifications from the daemon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    params: Option<Value>,
+}
+
+// What `list` and the torrent calls return about a torrent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    pub state: State,
+    pub priority: Priority,
+    pub mode: DownloadMode,
+    pub save_path: PathBuf,
+    pub pieces_done: usize,
+    pub piece_count: usize,
+    pub bytes_left: usize,
+    pub error: Option<String>,
+}
+
+impl TorrentStatus {
+    pub fn new(handle: &TorrentHandle) -> Self {
+        let progress = handle.progress();
+        Self {
+            info_hash: hex::encode(handle.info_hash()),
+            name: handle.torrent().info.name.clone(),
+            state: handle.state(),
+            priority: handle.priority(),
+            mode: handle.mode(),
+            save_path: handle.save_path().to_owned(),
+            pieces_done: progress.pieces_done,
+            piece_count: progress.piece_count,
+            bytes_left: progress.bytes_left,
+            error: progress.error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileStatus {
+    pub index: usize,
+    // relative to the torrent's save path
+    pub path: String,
+    pub length: usize,
+    pub priority: FilePriority,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionStatus {
+    pub peer_id: String,
+    pub port: u16,
+    pub torrents: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentEvent {
+    pub info_hash: String,
+    pub event: Event,
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    torrent: PathBuf,
+    save_path: PathBuf,
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    only: Vec<String>,
+    #[serde(default)]
+    mode: DownloadMode,
+}
+
+#[derive(Deserialize)]
+struct TorrentParams {
+    info_hash: String,
+}
+
+#[derive(Deserialize)]
+struct PriorityParams {
+    info_hash: String,
+    priority: Priority,
+}
+
+#[derive(Deserialize)]
+struct RateParams {
+    #[serde(default)]
+    info_hash: Option<String>,
+    #[serde(default)]
+    download: Option<u64>,
+    #[serde(default)]
+    upload: Option<u64>,
+}
+
+// the limits in effect after a set_rate, bytes per second
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rates {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct FilePriorityParams {
+    info_hash: String,
+    file: usize,
+    priority: FilePriority,
+}
+
+// A socket file left behind by a daemon that's no longer running is
+// replaced, one that still answers is an error.
+pub async fn bind(path: &Path) -> Result<UnixListener> {
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("a daemon is already listening on {}", path.display()),
+        )
+        .into());
+    }
+    let _ = std::fs::remove_file(path);
+    Ok(UnixListener::bind(path)?)
+}
+
+// serves `session` to everyone connecting until the task is dropped
+pub async fn serve(session: Arc<Session>, listener: UnixListener) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(serve_client(session.clone(), stream));
+    }
+}
+
+async fn serve_client(session: Arc<Session>, stream: UnixStream) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    // events are forwarded by a task of their own and written out here,
+    // between responses
+    let (tx, mut rx) = mpsc::channel::<String>(64);
+    let mut subscription = None;
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("rpc client went away: {}", e);
+                        break;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let (response, subscribe) = handle_line(&session, &line).await;
+                // subscribed before the client hears back, so nothing it
+                // does after that can go unseen
+                if subscribe && subscription.is_none() {
+                    subscription = Some(tokio::spawn(forward_events(session.subscribe(), tx.clone())));
+                }
+                if let Some(response) = response {
+                    if writer.write_all(format!("{}\n", response).as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Some(event) = rx.recv() => {
+                if writer.write_all(format!("{}\n", event).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    if let Some(subscription) = subscription {
+        subscription.abort();
+    }
+}
+
+async fn forward_events(mut events: broadcast::Receiver<([u8; 20], Event)>, tx: mpsc::Sender<String>) {
+    loop {
+        let (info_hash, event) = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                warn!("rpc subscriber fell behind, dropped {} events", n);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let params = TorrentEvent {
+            info_hash: hex::encode(info_hash),
+            event,
+        };
+        let notification = json!({"jsonrpc": "2.0", "method": "event", "params": params});
+        if tx.send(notification.to_string()).await.is_err() {
+            return;
+        }
+    }
+}
+
+// The response to one line, None for notifications, and whether the
+// client asked for events.
+async fn handle_line(session: &Session, line: &str) -> (Option<String>, bool) {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => {
+            let code = match serde_json::from_str::<Value>(line) {
+                Ok(_) => INVALID_REQUEST,
+                Err(_) => PARSE_ERROR,
+            };
+            return (Some(respond(Value::Null, Err(RpcError::new(code, e)))), false);
+        }
+    };
+    if request.jsonrpc != "2.0" {
+        let error = RpcError::new(INVALID_REQUEST, "only jsonrpc 2.0 is supported");
+        return (Some(respond(request.id.unwrap_or_default(), Err(error))), false);
+    }
+
+    debug!(method = %request.method, "rpc call");
+    let subscribe = request.method == "subscribe";
+    let result = call(session, &request.method, request.params).await;
+    (request.id.map(|id| respond(id, result)), subscribe)
+}
+
+fn respond(id: Value, result: std::result::Result<Value, RpcError>) -> String {
+    let (result, error) = match result {
+        Ok(result) => (Some(result), None),
+        Err(error) => (None, Some(error)),
+    };
+    let response = Response {
+        jsonrpc: "2.0".to_owned(),
+        id,
+        result,
+        error,
+        method: None,
+        params: None,
+    };
+    serde_json::to_string(&response).expect("response serializes")
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> std::result::Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn to_value<T: Serialize>(value: T) -> std::result::Result<Value, RpcError> {
+    Ok(serde_json::to_value(value).expect("result serializes"))
+}
+
+// an info hash or a prefix of one that only one torrent has
+fn find(session: &Session, info_hash: &str) -> std::result::Result<TorrentHandle, RpcError> {
+    let info_hash = info_hash.to_ascii_lowercase();
+    let mut found = session
+        .torrents()
+        .into_iter()
+        .filter(|t| hex::encode(t.info_hash()).starts_with(&info_hash));
+    match (found.next(), found.next()) {
+        (Some(handle), None) if !info_hash.is_empty() => Ok(handle),
+        (Some(_), _) => Err(RpcError::new(INVALID_PARAMS, format!("{} matches more than one torrent", info_hash))),
+        (None, _) => Err(RpcError::new(INVALID_PARAMS, format!("no torrent {}", info_hash))),
+    }
+}
+
+async fn call(session: &Session, method: &str, raw: Value) -> std::result::Result<Value, RpcError> {
+    match method {
+        "add" => {
+            let p: AddParams = params(raw)?;
+            let torrent = Torrent::load_torrent(p.torrent.to_string_lossy().into_owned())?;
+            let mut options = AddOptions {
+                mode: p.mode,
+                ..Default::default()
+            };
+            if !p.only.is_empty() {
+                let patterns = p
+                    .only
+                    .iter()
+                    .map(|p| glob::Pattern::new(p))
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+                options.file_priorities = Some(picker::select_files(&torrent.info.files()?, &patterns)?);
+            }
+            let handle = session.add_torrent_with(torrent, p.save_path, options).await?;
+            handle.set_priority(p.priority);
+            if !p.paused {
+                handle.start();
+            }
+            to_value(TorrentStatus::new(&handle))
+        }
+        "remove" => {
+            let p: TorrentParams = params(raw)?;
+            let handle = find(session, &p.info_hash)?;
+            session.remove_torrent(handle.info_hash());
+            to_value(TorrentStatus::new(&handle))
+        }
+        "pause" => {
+            let handle = find(session, &params::<TorrentParams>(raw)?.info_hash)?;
+            handle.pause();
+            to_value(TorrentStatus::new(&handle))
+        }
+        "resume" => {
+            let handle = find(session, &params::<TorrentParams>(raw)?.info_hash)?;
+            handle.start();
+            to_value(TorrentStatus::new(&handle))
+        }
+        "set_priority" => {
+            let p: PriorityParams = params(raw)?;
+            let handle = find(session, &p.info_hash)?;
+            handle.set_priority(p.priority);
+            to_value(TorrentStatus::new(&handle))
+        }
+        "set_rate" => {
+            let p: RateParams = params(raw)?;
+            let bandwidth = match &p.info_hash {
+                Some(info_hash) => find(session, info_hash)?.bandwidth().clone(),
+                None => session.bandwidth().clone(),
+            };
+            bandwidth.download.set_rate(p.download);
+            bandwidth.upload.set_rate(p.upload);
+            to_value(Rates {
+                download: bandwidth.download.rate(),
+                upload: bandwidth.upload.rate(),
+            })
+        }
+        "set_file_priority" => {
+            let p: FilePriorityParams = params(raw)?;
+            let handle = find(session, &p.info_hash)?;
+            handle.set_file_priority(p.file, p.priority).await?;
+            to_value(TorrentStatus::new(&handle))
+        }
+        "files" => {
+            let handle = find(session, &params::<TorrentParams>(raw)?.info_hash)?;
+            let files: Vec<_> = handle
+                .files()
+                .iter()
+                .zip(handle.file_priorities())
+                .enumerate()
+                .map(|(index, (file, priority))| FileStatus {
+                    index,
+                    path: display_path(&file.path),
+                    length: file.length,
+                    priority,
+                })
+                .collect();
+            to_value(files)
+        }
+        "list" => to_value(session.torrents().iter().map(TorrentStatus::new).collect::<Vec<_>>()),
+        "stats" => {
+            let handle = find(session, &params::<TorrentParams>(raw)?.info_hash)?;
+            to_value(handle.stats())
+        }
+        "session" => to_value(SessionStatus {
+            peer_id: hex::encode(session.peer_id().as_bytes()),
+            port: session.port(),
+            torrents: session.torrents().len(),
+        }),
+        "subscribe" => Ok(Value::Bool(true)),
+        method => Err(RpcError::new(METHOD_NOT_FOUND, format!("no method {}", method))),
+    }
+}
+
+// A connection to a daemon. Events the daemon sends while waiting for a
+// response are kept for `next_event`.
+pub struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+    events: VecDeque<TorrentEvent>,
+}
+
+impl Client {
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path).await.map_err(|e| {
+            std::io::Error::new(e.kind(), format!("no daemon at {}: {}", path.display(), e))
+        })?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 0,
+            events: VecDeque::new(),
+        })
+    }
+
+    pub async fn call<T: DeserializeOwned>(&mut self, method: &str, params: Value) -> Result<T> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        self.writer.write_all(format!("{}\n", request).as_bytes()).await?;
+
+        loop {
+            let response = self.read().await?;
+            if response.method.is_some() {
+                self.queue_event(response);
+                continue;
+            }
+            if response.id != json!(id) {
+                continue;
+            }
+            if let Some(error) = response.error {
+                return Err(Error::Rpc(error.message));
+            }
+            let result = response.result.unwrap_or_default();
+            return serde_json::from_value(result).map_err(|e| Error::Rpc(format!("unexpected result: {}", e)));
+        }
+    }
+
+    // asks for events, then hands them out one at a time
+    pub async fn subscribe(&mut self) -> Result<()> {
+        self.call::<bool>("subscribe", Value::Null).await?;
+        Ok(())
+    }
+
+    pub async fn next_event(&mut self) -> Result<TorrentEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            let response = self.read().await?;
+            self.queue_event(response);
+        }
+    }
+
+    async fn read(&mut self) -> Result<Response> {
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .ok_or_else(|| Error::Rpc("daemon closed the connection".to_owned()))?;
+        serde_json::from_str(&line).map_err(|e| Error::Rpc(format!("bad response: {}", e)))
+    }
+
+    fn queue_event(&mut self, notification: Response) {
+        if notification.method.as_deref() != Some("event") {
+            return;
+        }
+        match notification.params.map(serde_json::from_value) {
+            Some(Ok(event)) => self.events.push_back(event),
+            _ => debug!("ignoring malformed event"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionConfig;
+    use crate::torrent::tests::multi_file;
+
+    async fn session() -> Session {
+        Session::new(SessionConfig {
+            port: 0,
+            ..SessionConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    // the error code a line gets back, None for a result
+    async fn code(session: &Session, line: &str) -> Option<i64> {
+        let (response, _) = handle_line(session, line).await;
+        let response: Response = serde_json::from_str(&response.expect("a response")).unwrap();
+        response.error.map(|e| e.code)
+    }
+
+    #[tokio::test]
+    async fn bad_calls_get_their_error_codes() {
+        let session = session().await;
+        assert_eq!(code(&session, "{not json").await, Some(PARSE_ERROR));
+        assert_eq!(code(&session, "[1, 2]").await, Some(INVALID_REQUEST));
+        assert_eq!(code(&session, r#"{"jsonrpc": "2.0", "id": 1}"#).await, Some(INVALID_REQUEST));
+        assert_eq!(code(&session, r#"{"jsonrpc": "1.0", "id": 1, "method": "list"}"#).await, Some(INVALID_REQUEST));
+        assert_eq!(code(&session, r#"{"jsonrpc": "2.0", "id": 1, "method": "nope"}"#).await, Some(METHOD_NOT_FOUND));
+        assert_eq!(code(&session, r#"{"jsonrpc": "2.0", "id": 1, "method": "pause"}"#).await, Some(INVALID_PARAMS));
+        let add = r#"{"jsonrpc": "2.0", "id": 1, "method": "add", "params": {"torrent": "/nonexistent", "save_path": "/tmp"}}"#;
+        assert_eq!(code(&session, add).await, Some(SERVER_ERROR));
+        assert_eq!(code(&session, r#"{"jsonrpc": "2.0", "id": 1, "method": "list"}"#).await, None);
+    }
+
+    #[tokio::test]
+    async fn notifications_get_no_response() {
+        let session = session().await;
+        let (response, subscribe) = handle_line(&session, r#"{"jsonrpc": "2.0", "method": "list"}"#).await;
+        assert!(response.is_none() && !subscribe);
+        let (response, subscribe) = handle_line(&session, r#"{"jsonrpc": "2.0", "id": "x", "method": "subscribe"}"#).await;
+        assert!(response.unwrap().contains(r#""id":"x""#) && subscribe);
+    }
+
+    #[tokio::test]
+    async fn info_hashes_can_be_shortened_to_a_unique_prefix() {
+        let session = session().await;
+        // two torrents whose hashes start with the same digit
+        let mut by_digit = std::collections::HashMap::new();
+        let lens = (1..)
+            .find_map(|len: usize| {
+                let hash = hex::encode(multi_file(&[("a", len)], 16).get_info_hash());
+                by_digit.insert(hash.chars().next().unwrap(), len).map(|other| [other, len])
+            })
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut hashes = Vec::new();
+        for len in lens {
+            let handle = session.add_torrent(multi_file(&[("a", len)], 16), dir.path().join(len.to_string())).await.unwrap();
+            hashes.push(hex::encode(handle.info_hash()));
+        }
+        let (a, b) = (&hashes[0], &hashes[1]);
+
+        let found = |prefix: &str| find(&session, prefix).map(|t| hex::encode(t.info_hash())).map_err(|e| e.message);
+        assert_eq!(found(a).as_ref(), Ok(a));
+        assert_eq!(found(&b.to_ascii_uppercase()).as_ref(), Ok(b));
+        let shared = a.chars().zip(b.chars()).take_while(|(a, b)| a == b).count();
+        assert_eq!(found(&a[..shared + 1]).as_ref(), Ok(a));
+        assert!(found(&a[..shared]).unwrap_err().contains("more than one"));
+        assert!(found("").is_err());
+        assert!(found("xyz").unwrap_err().contains("no torrent"));
+    }
+
+    #[tokio::test]
+    async fn set_rate_limits_the_session_or_one_torrent() {
+        let session = session().await;
+        let dir = tempfile::tempdir().unwrap();
+        let handle = session.add_torrent(multi_file(&[("a", 1)], 16), dir.path().join("data")).await.unwrap();
+        let info_hash = hex::encode(handle.info_hash());
+        let set_rate = |params: Value| call(&session, "set_rate", params);
+
+        let rates = set_rate(json!({"download": 1000, "upload": 2000})).await.unwrap();
+        assert_eq!(rates, json!({"download": 1000, "upload": 2000}));
+        assert_eq!(session.bandwidth().upload.rate(), Some(2000));
+        assert_eq!(handle.bandwidth().upload.rate(), None);
+
+        let rates = set_rate(json!({"info_hash": &info_hash[..8], "upload": 500})).await.unwrap();
+        assert_eq!(rates, json!({"download": null, "upload": 500}));
+        assert_eq!(handle.bandwidth().upload.rate(), Some(500));
+        assert_eq!(session.bandwidth().upload.rate(), Some(2000));
+
+        // null lifts a limit
+        set_rate(json!({"download": null})).await.unwrap();
+        assert_eq!((session.bandwidth().download.rate(), session.bandwidth().upload.rate()), (None, None));
+
+        let error = set_rate(json!({"info_hash": "xyz", "upload": 1})).await.unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+        let error = set_rate(json!({"upload": -1})).await.unwrap_err();
+        assert_eq!(error.code, INVALID_PARAMS);
+    }
+
+    // A daemon that sends an event ahead of every response, the client
+    // keeps it for next_event rather than dropping it.
+    #[tokio::test]
+    async fn client_queues_events_that_come_before_a_response() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rpc.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut n = 0;
+            while let Ok(Some(line)) = lines.next_line().await {
+                let request: Request = serde_json::from_str(&line).unwrap();
+                n += 1;
+                let event = json!({"jsonrpc": "2.0", "method": "event", "params": {"info_hash": "ab", "event": {"piece_completed": n}}});
+                let response = json!({"jsonrpc": "2.0", "id": request.id, "result": n});
+                writer.write_all(format!("{}\n{}\n", event, response).as_bytes()).await.unwrap();
+            }
+        });
+
+        let mut client = Client::connect(&path).await.unwrap();
+        assert_eq!(client.call::<u64>("list", Value::Null).await.unwrap(), 1);
+        assert_eq!(client.call::<u64>("list", Value::Null).await.unwrap(), 2);
+        for n in 1..=2 {
+            let event = client.next_event().await.unwrap();
+            assert_eq!(event.info_hash, "ab");
+            assert!(matches!(event.event, Event::PieceCompleted(i) if i == n));
+        }
+    }
+
+    // anything that happens once subscribe has returned reaches the client
+    #[tokio::test]
+    async fn events_after_subscribing_are_never_missed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rpc.sock");
+        let session = Arc::new(session().await);
+        tokio::spawn(serve(session.clone(), bind(&path).await.unwrap()));
+        let handle = session.add_torrent(multi_file(&[("a", 1)], 16), dir.path().join("data")).await.unwrap();
+
+        let mut client = Client::connect(&path).await.unwrap();
+        client.subscribe().await.unwrap();
+        handle.start();
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), client.next_event())
+            .await
+            .expect("event arrived")
+            .unwrap();
+        assert_eq!(event.info_hash, hex::encode(handle.info_hash()));
+        assert!(matches!(event.event, Event::Started | Event::Queued), "{:?}", event.event);
+    }
+}
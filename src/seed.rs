@@ -0,0 +1,374 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tracing::{debug, info, trace};
+
+use crate::bandwidth::Bandwidth;
+use crate::bitfield::Bitfield;
+use crate::choker::{PeerKey, SharedChoker};
+use crate::connection::{PeerConnection, Timeouts};
+use crate::error::{Error, Result};
+use crate::listener::Incoming;
+use crate::merkle::PieceHashes;
+use crate::peer_protocol::{Handshake, PeerMessage};
+use crate::stats::Stats;
+use crate::storage::Storage;
+use crate::torrent::Info;
+
+// the largest block we will serve, anything bigger is treated as abuse
+// and the peer is dropped (most clients ask for 16KiB)
+pub const MAX_REQUEST_LEN: u32 = 128 * 1024;
+
+fn invalid_request(reason: &str) -> Error {
+    Error::Protocol(reason.to_owned())
+}
+
+// Upload side of the peer protocol. We advertise what we have, and keep
+// the peer posted as more comes in, tell the choker whether the peer is
+// interested and answer its requests from storage while the choker has
+// it unchoked.
+pub async fn serve_peer(
+    conn: PeerConnection,
+    info: &Info,
+    storage: Arc<Storage>,
+    hashes: &PieceHashes,
+    have: watch::Receiver<Bitfield>,
+    choker: &SharedChoker,
+) -> Result<()> {
+    let (key, unchoked) = choker.join(conn.peer_addr().map(|a| a.ip()));
+    let result = upload(conn, info, storage, hashes, have, choker, key, unchoked).await;
+    choker.leave(key);
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upload(
+    mut conn: PeerConnection,
+    info: &Info,
+    storage: Arc<Storage>,
+    hashes: &PieceHashes,
+    mut have: watch::Receiver<Bitfield>,
+    choker: &SharedChoker,
+    key: PeerKey,
+    mut unchoked: watch::Receiver<bool>,
+) -> Result<()> {
+    // what the peer has been told we have, a bitfield is optional when
+    // we have nothing
+    let mut told = have.borrow_and_update().clone();
+    if !told.none() {
+        conn.send(PeerMessage::Bitfield(told.to_bytes())).await?;
+    }
+
+    loop {
+        let msg = tokio::select! {
+            msg = conn.recv() => match msg? {
+                Some(msg) => msg,
+                None => break,
+            },
+            Ok(()) = unchoked.changed() => {
+                let unchoke = *unchoked.borrow_and_update();
+                if unchoke == conn.am_choking() {
+                    let msg = if unchoke { PeerMessage::Unchoke } else { PeerMessage::Choke };
+                    conn.send(msg).await?;
+                }
+                continue;
+            }
+            Ok(()) = have.changed() => {
+                let now = have.borrow_and_update().clone();
+                for index in now.difference(&told).ones() {
+                    conn.send(PeerMessage::Have(index as u32)).await?;
+                }
+                told = now;
+                continue;
+            }
+        };
+
+        match msg {
+            PeerMessage::Interested | PeerMessage::NotInterested => {
+                choker.set_interested(key, conn.peer_interested())
+            }
+            PeerMessage::Request { index, begin, length } => {
+                // requests that cross a choke are discarded, the peer
+                // has to ask again once we unchoke it
+                if conn.am_choking() {
+                    debug!(index, begin, length, "ignoring request while choking");
+                    continue;
+                }
+                if length == 0 || length > MAX_REQUEST_LEN {
+                    return Err(invalid_request("bad request length"));
+                }
+                let (index, begin, length) = (index as usize, begin as usize, length as usize);
+                if index >= info.piece_count() || begin + length > info.piece_len(index) {
+                    return Err(invalid_request("request outside of piece"));
+                }
+                if !told.get(index) {
+                    debug!(index, "ignoring request for piece we do not have");
+                    continue;
+                }
+
+                let block = storage.read_block(index, begin, length).await?;
+                conn
+                    .send(PeerMessage::Piece {
+                        index: index as u32,
+                        begin: begin as u32,
+                        block: block.into(),
+                    })
+                    .await?;
+                choker.record_upload(key, length as u64);
+            }
+            // hashes aren't data, choked or not the peer can have them
+            PeerMessage::HashRequest(request) => {
+                let msg = match hashes.answer(&request) {
+                    Some(hashes) => PeerMessage::Hashes {
+                        request,
+                        hashes: hashes.concat().into(),
+                    },
+                    None => PeerMessage::HashReject(request),
+                };
+                conn.send(msg).await?;
+            }
+            // requests are answered as they arrive so there is never
+            // anything queued to cancel
+            PeerMessage::Cancel { .. } | PeerMessage::KeepAlive => {}
+            // the connection keeps track of what the peer has
+            PeerMessage::Have(_) | PeerMessage::Bitfield(_) => {}
+            pm => trace!("ignoring {:?}", pm),
+        }
+    }
+    Ok(())
+}
+
+// Everything needed to upload one torrent, cloned into each peer task.
+#[derive(Clone)]
+pub struct Seeder {
+    // one per info hash the torrent goes by, primary first
+    pub handshakes: Vec<Handshake>,
+    pub info: Arc<Info>,
+    pub storage: Arc<Storage>,
+    pub hashes: Arc<PieceHashes>,
+    // verified pieces, peers hear about new ones as they come in
+    pub have: watch::Receiver<Bitfield>,
+    pub choker: SharedChoker,
+    pub timeouts: Timeouts,
+    pub bandwidth: Bandwidth,
+    pub stats: Stats,
+}
+
+impl Seeder {
+    // a peer the listener routed to us, its handshake is read but not
+    // answered yet
+    #[tracing::instrument(name = "peer", skip_all, fields(addr))]
+    pub async fn serve_incoming(self, incoming: Incoming) {
+        let (mut stream, theirs) = incoming;
+        if let Ok(addr) = stream.peer_addr() {
+            tracing::Span::current().record("addr", tracing::field::display(addr));
+        }
+        // answered under whichever hash they asked for. The listener only
+        // routes matching info hashes, but it may be one of our own
+        // outgoing connections looping back
+        let ours = self.handshakes.iter().find(|h| h.info_hash() == theirs.info_hash()).unwrap_or(&self.handshakes[0]);
+        if let Err(e) = ours.validate(&theirs) {
+            debug!("rejecting incoming peer: {}", e);
+            return;
+        }
+        if let Err(e) = ours.write(&mut stream).await {
+            debug!("failed to answer handshake: {}", e);
+            return;
+        }
+        info!(peer_id = %hex::encode(theirs.peer_id), "seeding to incoming peer");
+        let mut conn = PeerConnection::new(stream, self.info.piece_count());
+        conn.set_bandwidth(self.bandwidth.clone());
+        conn.set_stats(&self.stats);
+        if let Err(e) = serve_peer(conn, &self.info, self.storage, &self.hashes, self.have, &self.choker).await {
+            info!("peer dropped: {}", e);
+        }
+    }
+
+    #[tracing::instrument(name = "peer", skip_all, fields(%addr))]
+    pub async fn serve_outgoing(self, addr: String, handshake: Handshake) {
+        let piece_count = self.info.piece_count();
+        let (mut conn, h) = match PeerConnection::connect(&addr, &handshake, piece_count, self.timeouts).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                debug!("failed to connect: {}", e);
+                return;
+            }
+        };
+        info!(peer_id = %hex::encode(h.peer_id), "seeding to peer");
+        conn.set_bandwidth(self.bandwidth.clone());
+        conn.set_stats(&self.stats);
+        if let Err(e) = serve_peer(conn, &self.info, self.storage, &self.hashes, self.have, &self.choker).await {
+            info!("peer dropped: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::choker::Choker;
+    use crate::peer_protocol::PeerMessageCodec;
+    use crate::picker::FilePriority;
+    use crate::torrent::tests::single_file;
+
+    const PLEN: usize = 32 * 1024;
+    const BLOCK: u32 = 16 * 1024;
+
+    // two and a half pieces
+    fn data() -> Vec<u8> {
+        (0..PLEN * 5 / 2).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    // The far end of a connection we're uploading on, and what we tell
+    // it we have.
+    struct Peer {
+        framed: Framed<TcpStream, PeerMessageCodec>,
+        have: watch::Sender<Bitfield>,
+        upload: JoinHandle<Result<()>>,
+        _dir: tempfile::TempDir,
+    }
+
+    impl Peer {
+        async fn send(&mut self, msg: PeerMessage) {
+            self.framed.send(msg).await.unwrap();
+        }
+
+        async fn next(&mut self) -> PeerMessage {
+            tokio::time::timeout(Duration::from_secs(5), self.framed.next())
+                .await
+                .expect("a message")
+                .expect("still connected")
+                .unwrap()
+        }
+
+        async fn unchoked(&mut self) {
+            self.send(PeerMessage::Interested).await;
+            assert!(matches!(self.next().await, PeerMessage::Unchoke));
+        }
+
+        async fn result(self) -> Result<()> {
+            tokio::time::timeout(Duration::from_secs(5), self.upload).await.expect("upload ended").unwrap()
+        }
+    }
+
+    async fn upload_with(have: &[usize]) -> Peer {
+        let data = data();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+        let torrent = single_file("data.bin", &data, PLEN);
+        let info = torrent.info.clone();
+        let storage = Arc::new(Storage::open(&info, &path, &[FilePriority::Normal]).await.unwrap());
+        let hashes = PieceHashes::new(&torrent).unwrap();
+        let mut bits = Bitfield::new(info.piece_count());
+        for &index in have {
+            bits.set(index, true);
+        }
+        let (have, have_rx) = watch::channel(bits);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let theirs = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (ours, _) = listener.accept().await.unwrap();
+        let conn = PeerConnection::new(ours, info.piece_count());
+        let upload = tokio::spawn(async move {
+            let choker = SharedChoker::new(Choker::new(1, true));
+            let rechoke = tokio::spawn(choker.clone().run());
+            let result = serve_peer(conn, &info, storage, &hashes, have_rx, &choker).await;
+            rechoke.abort();
+            result
+        });
+        Peer {
+            framed: Framed::new(theirs, PeerMessageCodec),
+            have,
+            upload,
+            _dir: dir,
+        }
+    }
+
+    async fn upload() -> Peer {
+        upload_with(&[0, 1, 2]).await
+    }
+
+    #[tokio::test]
+    async fn bitfield_is_sent_first() {
+        let mut peer = upload_with(&[0, 2]).await;
+        assert!(matches!(peer.next().await, PeerMessage::Bitfield(b) if b[..] == [0b1010_0000]));
+
+        // with nothing to tell there's no bitfield, the choker speaks first
+        let mut peer = upload_with(&[]).await;
+        peer.unchoked().await;
+    }
+
+    #[tokio::test]
+    async fn requests_are_served() {
+        let data = data();
+        let mut peer = upload().await;
+        assert!(matches!(peer.next().await, PeerMessage::Bitfield(_)));
+        peer.unchoked().await;
+        // the last piece is shorter than the rest
+        for (index, begin) in [(0, 0), (1, BLOCK), (2, 0)] {
+            peer.send(PeerMessage::Request { index, begin, length: BLOCK }).await;
+            let start = index as usize * PLEN + begin as usize;
+            match peer.next().await {
+                PeerMessage::Piece { index: i, begin: b, block } => {
+                    assert_eq!((i, b), (index, begin));
+                    assert_eq!(block[..], data[start..start + BLOCK as usize]);
+                }
+                msg => panic!("expected a block, got {:?}", msg),
+            }
+        }
+    }
+
+    // the request made while choked is dropped, the first block back is
+    // the one asked for after the unchoke
+    #[tokio::test]
+    async fn requests_are_ignored_while_choking() {
+        let mut peer = upload().await;
+        assert!(matches!(peer.next().await, PeerMessage::Bitfield(_)));
+        peer.send(PeerMessage::Request { index: 0, begin: 0, length: BLOCK }).await;
+        peer.unchoked().await;
+        peer.send(PeerMessage::Request { index: 1, begin: 0, length: BLOCK }).await;
+        assert!(matches!(peer.next().await, PeerMessage::Piece { index: 1, .. }));
+    }
+
+    // pieces we don't have yet aren't served, until we tell the peer
+    // we've got them
+    #[tokio::test]
+    async fn new_pieces_are_announced() {
+        let mut peer = upload_with(&[0]).await;
+        assert!(matches!(peer.next().await, PeerMessage::Bitfield(b) if b[..] == [0b1000_0000]));
+        peer.unchoked().await;
+        peer.send(PeerMessage::Request { index: 2, begin: 0, length: BLOCK }).await;
+        peer.have.send_modify(|have| have.set(2, true));
+        assert!(matches!(peer.next().await, PeerMessage::Have(2)));
+        peer.send(PeerMessage::Request { index: 2, begin: 0, length: BLOCK }).await;
+        assert!(matches!(peer.next().await, PeerMessage::Piece { index: 2, .. }));
+    }
+
+    #[tokio::test]
+    async fn bad_requests_drop_the_peer() {
+        for (index, begin, length, reason) in [
+            (0, 0, MAX_REQUEST_LEN + 1, "bad request length"),
+            (0, 0, 0, "bad request length"),
+            (3, 0, BLOCK, "request outside of piece"),
+            (2, BLOCK, 1, "request outside of piece"),
+            (0, PLEN as u32 - 1, 2, "request outside of piece"),
+        ] {
+            let mut peer = upload().await;
+            assert!(matches!(peer.next().await, PeerMessage::Bitfield(_)));
+            peer.unchoked().await;
+            peer.send(PeerMessage::Request { index, begin, length }).await;
+            match peer.result().await {
+                Err(Error::Protocol(e)) => assert_eq!(e, reason, "{} {} {}", index, begin, length),
+                result => panic!("{} {} {}: {:?}", index, begin, length, result),
+            }
+        }
+    }
+}
@@ -0,0 +1,1327 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+use tokio::task::JoinSet;
+use tokio_util::bytes::Bytes;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::backoff::PeerBackoff;
+use crate::bandwidth::Bandwidth;
+use crate::bitfield::Bitfield;
+use crate::choker::{self, Choker, SharedChoker};
+use crate::connection::{PeerConnection, Timeouts};
+use crate::error::{Error, Result};
+use crate::listener::{self, Incoming, Listener, Router};
+use crate::lsd::{self, LocalDiscovery};
+use crate::merkle::PieceHashes;
+use crate::peer_id::PeerId;
+use crate::peer_protocol::Handshake;
+use crate::picker::{Deadlines, DownloadMode, FilePriority, Picker};
+use crate::queue::{self, Priority, QueueLimits};
+use crate::reader::FileReader;
+use crate::resume::{self, SavedSession, SavedTorrent, SAVE_INTERVAL};
+use crate::seed::Seeder;
+use crate::stats::{Snapshot, Stats};
+use crate::storage::Storage;
+use crate::torrent::{self, Info, Torrent};
+use crate::tracker;
+use crate::webseed::{self, WebSeed};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Paused,
+    // started, but waiting for a free slot in the session's queue
+    Queued,
+    Running,
+    Removed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Started,
+    Paused,
+    // stopped to make room for another torrent, it carries on by itself
+    // once there's a slot for it again
+    Queued,
+    PieceCompleted(usize),
+    Completed,
+    Error(String),
+}
+
+// Only counts the pieces the file priorities want, which is all of them
+// unless some files are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub pieces_done: usize,
+    pub piece_count: usize,
+    pub bytes_left: usize,
+    // the reason the last run stopped, cleared when started again
+    pub error: Option<String>,
+}
+
+impl Progress {
+    fn new(info: &Info, have: &Bitfield, wanted: &Bitfield) -> Self {
+        let missing = wanted.difference(have);
+        Self {
+            pieces_done: wanted.intersection(have).count(),
+            piece_count: wanted.count(),
+            bytes_left: missing.ones().map(|i| info.piece_len(i)).sum(),
+            error: None,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces_done == self.piece_count
+    }
+}
+
+// How a torrent is added, beyond where it goes.
+#[derive(Debug, Clone, Default)]
+pub struct AddOptions {
+    // one per file, every file is downloaded when it's None
+    pub file_priorities: Option<Vec<FilePriority>>,
+    pub mode: DownloadMode,
+}
+
+// Everything there is to report about a torrent's transfers, rates in
+// bytes per second.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentStats {
+    pub name: String,
+    pub info_hash: String,
+    pub pieces_done: usize,
+    pub piece_count: usize,
+    pub bytes_left: usize,
+    #[serde(flatten)]
+    pub transfer: Snapshot,
+    // None while nothing is coming in
+    pub eta_secs: Option<u64>,
+    // why the last run stopped, same as the Error event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub struct SessionConfig {
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub upload_slots: usize,
+    pub timeouts: Timeouts,
+    // bytes per second across every torrent, None for unlimited
+    pub max_download_rate: Option<u64>,
+    pub max_upload_rate: Option<u64>,
+    pub queue: QueueLimits,
+    // where the torrents and their progress are saved, to be picked up
+    // again by the next session given the same directory
+    pub state_dir: Option<PathBuf>,
+    // find peers on the local network with BEP 14 multicast, private
+    // torrents are left out of it
+    pub local_discovery: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            peer_id: PeerId::generate(),
+            port: listener::DEFAULT_PORT,
+            upload_slots: choker::DEFAULT_UPLOAD_SLOTS,
+            timeouts: Timeouts::default(),
+            max_download_rate: None,
+            max_upload_rate: None,
+            queue: QueueLimits::default(),
+            state_dir: None,
+            local_discovery: false,
+        }
+    }
+}
+
+// What every torrent task needs to know about the session it runs in.
+#[derive(Clone)]
+struct Context {
+    peer_id: PeerId,
+    port: u16,
+    upload_slots: usize,
+    timeouts: Timeouts,
+    bandwidth: Bandwidth,
+    local_discovery: bool,
+}
+
+type Torrents = Arc<Mutex<HashMap<[u8; 20], TorrentHandle>>>;
+
+// Owns the peer listener and the torrents added to it. Each torrent runs
+// in its own task and is controlled through its TorrentHandle, which
+// torrents get to run at any one time is up to the session's queue.
+pub struct Session {
+    ctx: Context,
+    router: Router,
+    torrents: Torrents,
+    // every torrent's events, tagged with its info hash
+    events: broadcast::Sender<([u8; 20], Event)>,
+    // wakes the scheduler whenever a torrent changes in a way that may
+    // change the queue or needs saving
+    queue: Arc<Notify>,
+    state_dir: Option<PathBuf>,
+    // held from taking a snapshot until it's written, so the scheduler and
+    // `save` can't leave an older one on disk
+    saving: Arc<tokio::sync::Mutex<()>>,
+    next_added: AtomicU64,
+}
+
+impl Session {
+    pub async fn new(config: SessionConfig) -> Result<Self> {
+        // a busy port shouldn't stop us downloading, the tracker is told
+        // whichever port we actually got
+        let listener = match Listener::bind(config.port).await {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Listener::bind(0).await?,
+            listener => listener?,
+        };
+        let ctx = Context {
+            peer_id: config.peer_id,
+            port: listener.port(),
+            upload_slots: config.upload_slots,
+            timeouts: config.timeouts,
+            bandwidth: Bandwidth::new(config.max_download_rate, config.max_upload_rate),
+            local_discovery: config.local_discovery,
+        };
+        let router = listener.router();
+        tokio::spawn(listener.run(config.timeouts.handshake));
+
+        let session = Self {
+            ctx,
+            router,
+            torrents: Torrents::default(),
+            events: broadcast::channel(256).0,
+            queue: Arc::new(Notify::new()),
+            state_dir: config.state_dir,
+            saving: Arc::default(),
+            next_added: AtomicU64::new(0),
+        };
+        tokio::spawn(schedule(
+            session.torrents.clone(),
+            session.queue.clone(),
+            config.queue,
+            session.state_dir.clone(),
+            session.saving.clone(),
+        ));
+        // like a busy port, no multicast just means fewer peers
+        if config.local_discovery {
+            match LocalDiscovery::bind(session.ctx.port) {
+                Ok(lsd) => {
+                    tokio::spawn(discover(lsd, session.torrents.clone(), session.events.subscribe()));
+                }
+                Err(e) => warn!("local discovery unavailable: {}", e),
+            }
+        }
+        session.restore().await?;
+        Ok(session)
+    }
+
+    // brings back whatever the last session in state_dir left behind, a
+    // torrent that can't be loaded any more is skipped rather than
+    // holding up the rest
+    async fn restore(&self) -> Result<()> {
+        let Some(dir) = &self.state_dir else {
+            return Ok(());
+        };
+        for (saved, torrent) in resume::load(dir).await? {
+            let torrent = match torrent {
+                Ok(torrent) => torrent,
+                Err(e) => {
+                    warn!(info_hash = %saved.info_hash, "can't restore torrent: {}", e);
+                    continue;
+                }
+            };
+            self.next_added.fetch_max(saved.added + 1, Ordering::Relaxed);
+            let paused = saved.paused;
+            let options = AddOptions {
+                file_priorities: Some(saved.file_priorities.clone()).filter(|p| !p.is_empty()),
+                mode: saved.mode,
+            };
+            match self.insert(torrent, saved.save_path.clone(), options, Some(saved)).await {
+                Ok(handle) if !paused => handle.start(),
+                Ok(_) => {}
+                Err(e) => warn!("can't restore torrent: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.ctx.peer_id
+    }
+
+    pub fn port(&self) -> u16 {
+        self.ctx.port
+    }
+
+    // the session wide limits, shared by every torrent and changeable
+    // while they run
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.ctx.bandwidth
+    }
+
+    // Checks whatever is already at `save_path` and adds the torrent in
+    // the paused state, at the back of the queue. Adding a torrent twice
+    // returns the existing handle.
+    pub async fn add_torrent(
+        &self,
+        torrent: Torrent,
+        save_path: impl Into<PathBuf>,
+    ) -> Result<TorrentHandle> {
+        self.insert(torrent, save_path.into(), AddOptions::default(), None).await
+    }
+
+    // `save_path` is the directory the files go in for multi-file
+    // torrents, skipped files aren't created in it
+    pub async fn add_torrent_with(
+        &self,
+        torrent: Torrent,
+        save_path: impl Into<PathBuf>,
+        options: AddOptions,
+    ) -> Result<TorrentHandle> {
+        self.insert(torrent, save_path.into(), options, None).await
+    }
+
+    async fn insert(
+        &self,
+        torrent: Torrent,
+        save_path: PathBuf,
+        options: AddOptions,
+        saved: Option<SavedTorrent>,
+    ) -> Result<TorrentHandle> {
+        let info_hash = torrent.get_info_hash();
+        if let Some(handle) = self.torrents.lock().unwrap().get(&info_hash) {
+            return Ok(handle.clone());
+        }
+
+        let files = torrent.info.files()?;
+        let hashes = Arc::new(PieceHashes::new(&torrent)?);
+        let file_priorities = match options.file_priorities {
+            Some(priorities) if priorities.len() != files.len() => {
+                return Err(Error::Usage(format!(
+                    "{} file priorities for {} files",
+                    priorities.len(),
+                    files.len()
+                )));
+            }
+            Some(priorities) => priorities,
+            None => vec![FilePriority::Normal; files.len()],
+        };
+        let storage = Arc::new(Storage::open(&torrent.info, &save_path, &file_priorities).await?);
+        let resumed = match &saved {
+            Some(saved) => resume_have(&torrent.info, &storage, &saved.have).await,
+            None => None,
+        };
+        let have = match resumed {
+            Some(have) => have,
+            None => storage.verify(&torrent.info, &hashes).await,
+        };
+        if let (Some(dir), None) = (&self.state_dir, &saved) {
+            resume::save_metainfo(dir, &info_hash, &torrent).await?;
+        }
+        let picker = Picker::new(&torrent.info, &files, &file_priorities, options.mode);
+        let progress = Progress::new(&torrent.info, &have, &picker.wanted());
+
+        let handle = TorrentHandle {
+            inner: Arc::new(TorrentInner {
+                info_hashes: torrent.info_hashes(),
+                torrent,
+                info_hash,
+                save_path,
+                state: watch::channel(State::Paused).0,
+                progress: watch::channel(progress).0,
+                events: broadcast::channel(64).0,
+                bandwidth: self.ctx.bandwidth.child(None, None),
+                stats: Stats::default(),
+                priority: Mutex::new(saved.as_ref().map_or(Priority::Normal, |s| s.priority)),
+                added: match &saved {
+                    Some(saved) => saved.added,
+                    None => self.next_added.fetch_add(1, Ordering::Relaxed),
+                },
+                have: watch::channel(have).0,
+                files,
+                file_priorities: Mutex::new(file_priorities),
+                picker: Mutex::new(picker),
+                storage,
+                hashes,
+                readers: Mutex::new(HashMap::new()),
+                next_reader: AtomicU64::new(0),
+                queue: self.queue.clone(),
+                session_events: self.events.clone(),
+                local_peers: watch::channel(Vec::new()).0,
+            }),
+        };
+        let incoming = self.router.register(handle.info_hashes());
+        let span = info_span!("torrent", torrent = %handle.torrent().info.name);
+        tokio::spawn(run_torrent(handle.clone(), self.ctx.clone(), incoming).instrument(span));
+
+        self.torrents.lock().unwrap().insert(info_hash, handle.clone());
+        self.queue.notify_one();
+        Ok(handle)
+    }
+
+    // in queue order
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        let mut torrents: Vec<_> = self.torrents.lock().unwrap().values().cloned().collect();
+        torrents.sort_by_key(|t| t.added());
+        torrents
+    }
+
+    // Writes the session out now, pieces the scheduler is holding back
+    // included. For shutting down, it saves as it goes otherwise.
+    pub async fn save(&self) -> Result<()> {
+        match &self.state_dir {
+            Some(dir) => {
+                let _saving = self.saving.lock().await;
+                SavedSession::of(&self.torrents()).save(dir).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    // events from all the torrents at once, including ones added later
+    pub fn subscribe(&self) -> broadcast::Receiver<([u8; 20], Event)> {
+        self.events.subscribe()
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+
+    // stops the torrent for good, the data on disk is left alone
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        let handle = self.torrents.lock().unwrap().remove(info_hash)?;
+        self.router.unregister(handle.info_hashes());
+        handle.inner.state.send_replace(State::Removed);
+        if let Some(dir) = &self.state_dir {
+            resume::remove_metainfo(dir, info_hash);
+        }
+        self.queue.notify_one();
+        Some(handle)
+    }
+}
+
+// Runs for as long as the session, rebalancing the queue and saving the
+// session whenever something's changed. Notify keeps a single wakeup, so
+// a burst of changes only costs one pass. Pieces coming in are only saved
+// every SAVE_INTERVAL, anything else is saved straight away.
+async fn schedule(
+    torrents: Torrents,
+    queue: Arc<Notify>,
+    limits: QueueLimits,
+    state_dir: Option<PathBuf>,
+    saving: Arc<tokio::sync::Mutex<()>>,
+) {
+    let mut saved: Option<SavedSession> = None;
+    let mut save_at: Option<Instant> = None;
+    loop {
+        let due = tokio::select! {
+            _ = queue.notified() => false,
+            _ = sleep_until_some(save_at) => true,
+        };
+        let torrents: Vec<_> = torrents.lock().unwrap().values().cloned().collect();
+        queue::reschedule(&torrents, limits);
+        let Some(dir) = &state_dir else {
+            continue;
+        };
+        let _saving = saving.lock().await;
+        let session = SavedSession::of(&torrents);
+        if saved.as_ref() == Some(&session) {
+            save_at = None;
+            continue;
+        }
+        let only_pieces = saved.as_ref().is_some_and(|s| s.same_but_pieces(&session));
+        if only_pieces && !due {
+            save_at.get_or_insert(Instant::now() + SAVE_INTERVAL);
+            continue;
+        }
+        if let Err(e) = session.save(dir).await {
+            warn!("can't save session: {}", e);
+        }
+        saved = Some(session);
+        save_at = None;
+    }
+}
+
+async fn sleep_until_some(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Announces the running torrents on the local network, when each starts
+// and every ANNOUNCE_INTERVAL after, and hands the peers announcing ours
+// to their torrents. A peer we haven't seen before gets an announce back
+// straight away so it doesn't have to wait for our next one, anything
+// else is held to one announce a torrent per MIN_INTERVAL. A hybrid goes
+// out under both its info hashes, and peers announcing either find it.
+async fn discover(lsd: LocalDiscovery, torrents: Torrents, mut events: broadcast::Receiver<([u8; 20], Event)>) {
+    let mut announced: HashMap<[u8; 20], Instant> = HashMap::new();
+    let mut tick = tokio::time::interval(lsd::ANNOUNCE_INTERVAL);
+    loop {
+        // and whether it's an answer to a new peer
+        let due: Vec<([u8; 20], bool)> = tokio::select! {
+            _ = tick.tick() => torrents.lock().unwrap().keys().map(|info_hash| (*info_hash, false)).collect(),
+            event = events.recv() => match event {
+                Ok((info_hash, Event::Started)) => vec![(info_hash, false)],
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            },
+            announce = lsd.recv() => match announce {
+                Ok(announce) => {
+                    let torrents = torrents.lock().unwrap();
+                    announce
+                        .info_hashes
+                        .iter()
+                        .filter_map(|info_hash| {
+                            let handle = torrents.values().find(|handle| handle.info_hashes().contains(info_hash))?;
+                            Some((handle, *info_hash))
+                        })
+                        .filter(|(handle, _)| !handle.is_private())
+                        .map(|(handle, info_hash)| {
+                            let new = handle.add_local_peer(announce.peer.to_string(), info_hash);
+                            if new {
+                                debug!(peer = %announce.peer, torrent = %handle.torrent().info.name, "found local peer");
+                            }
+                            (*handle.info_hash(), new)
+                        })
+                        .collect()
+                }
+                Err(e) => {
+                    warn!("local discovery failed: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        let now = Instant::now();
+        let mut due: Vec<TorrentHandle> = {
+            let torrents = torrents.lock().unwrap();
+            due.into_iter()
+                .filter(|(info_hash, new_peer)| {
+                    *new_peer || announced.get(info_hash).is_none_or(|at| now - *at >= lsd::MIN_INTERVAL)
+                })
+                .filter_map(|(info_hash, _)| torrents.get(&info_hash))
+                .filter(|handle| handle.state() == State::Running && !handle.is_private())
+                .cloned()
+                .collect()
+        };
+        due.sort_by_key(|handle| *handle.info_hash());
+        due.dedup_by_key(|handle| *handle.info_hash());
+        if due.is_empty() {
+            continue;
+        }
+        let info_hashes: Vec<[u8; 20]> = due.iter().flat_map(|handle| handle.info_hashes()).copied().collect();
+        if let Err(e) = lsd.announce(&info_hashes).await {
+            debug!("local announce failed: {}", e);
+            continue;
+        }
+        for handle in due {
+            announced.insert(*handle.info_hash(), now);
+        }
+    }
+}
+
+// A saved bitfield is trusted as long as the files still reach the end
+// of the pieces in it, otherwise everything is hashed again like a newly
+// added torrent.
+async fn resume_have(info: &Info, storage: &Storage, saved: &str) -> Option<Bitfield> {
+    let bytes = hex::decode(saved).ok()?;
+    let have = Bitfield::from_bytes(&bytes, info.piece_count()).ok()?;
+    storage.covers(&have).await.then_some(have)
+}
+
+struct TorrentInner {
+    torrent: Torrent,
+    info_hash: [u8; 20],
+    // every swarm it's in, info_hash first
+    info_hashes: Vec<[u8; 20]>,
+    save_path: PathBuf,
+    state: watch::Sender<State>,
+    progress: watch::Sender<Progress>,
+    events: broadcast::Sender<Event>,
+    bandwidth: Bandwidth,
+    stats: Stats,
+    priority: Mutex<Priority>,
+    // when it was added to the session, for its place in the queue
+    added: u64,
+    // verified pieces, readers wait on it for the ones they need
+    have: watch::Sender<Bitfield>,
+    files: Vec<torrent::File>,
+    file_priorities: Mutex<Vec<FilePriority>>,
+    // kept in step with file_priorities
+    picker: Mutex<Picker>,
+    storage: Arc<Storage>,
+    hashes: Arc<PieceHashes>,
+    // where each reader is, as the piece it's in and since when
+    readers: Mutex<HashMap<u64, (usize, Instant)>>,
+    next_reader: AtomicU64,
+    queue: Arc<Notify>,
+    session_events: broadcast::Sender<([u8; 20], Event)>,
+    // peers local discovery found, and the info hash they announced,
+    // kept across runs
+    local_peers: watch::Sender<Vec<(String, [u8; 20])>>,
+}
+
+#[derive(Clone)]
+pub struct TorrentHandle {
+    inner: Arc<TorrentInner>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> &[u8; 20] {
+        &self.inner.info_hash
+    }
+
+    pub fn info_hashes(&self) -> &[[u8; 20]] {
+        &self.inner.info_hashes
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.inner.torrent
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.inner.torrent.info.is_private()
+    }
+
+    pub fn save_path(&self) -> &Path {
+        &self.inner.save_path
+    }
+
+    pub fn state(&self) -> State {
+        *self.inner.state.borrow()
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.inner.progress.borrow().clone()
+    }
+
+    pub fn stats(&self) -> TorrentStats {
+        let progress = self.progress();
+        let transfer = self.inner.stats.snapshot();
+        let eta_secs = transfer.eta_secs(progress.bytes_left);
+        TorrentStats {
+            name: self.torrent().info.name.clone(),
+            info_hash: hex::encode(self.info_hash()),
+            pieces_done: progress.pieces_done,
+            piece_count: progress.piece_count,
+            bytes_left: progress.bytes_left,
+            transfer,
+            eta_secs,
+            error: progress.error,
+        }
+    }
+
+    // this torrent's own limits, it's held to the session's as well
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.inner.bandwidth
+    }
+
+    pub fn priority(&self) -> Priority {
+        *self.inner.priority.lock().unwrap()
+    }
+
+    // moves the torrent up or down the queue
+    pub fn set_priority(&self, priority: Priority) {
+        *self.inner.priority.lock().unwrap() = priority;
+        self.inner.queue.notify_one();
+    }
+
+    // Downloads whatever is missing, then seeds. The torrent is queued
+    // until the session has a slot for it.
+    pub fn start(&self) {
+        self.inner.progress.send_if_modified(|p| p.error.take().is_some());
+        self.transition(&[State::Paused], State::Queued);
+    }
+
+    pub fn pause(&self) {
+        self.transition(&[State::Running, State::Queued], State::Paused);
+    }
+
+    // relative to the save path, in the torrent's order
+    pub fn files(&self) -> &[torrent::File] {
+        &self.inner.files
+    }
+
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        self.inner.file_priorities.lock().unwrap().clone()
+    }
+
+    // Changes what's downloaded from here on. A file that's no longer
+    // skipped is created, a skipped one is left on disk if it's there.
+    pub async fn set_file_priority(&self, file: usize, priority: FilePriority) -> Result<()> {
+        if file >= self.inner.files.len() {
+            return Err(Error::Usage(format!("no file {}", file)));
+        }
+        if priority != FilePriority::Skip {
+            self.inner.storage.want_file(file).await?;
+        }
+        let wanted = {
+            let mut priorities = self.inner.file_priorities.lock().unwrap();
+            priorities[file] = priority;
+            self.rebuild_picker(&priorities, self.mode())
+        };
+        let have = self.have();
+        self.inner.progress.send_modify(|p| {
+            *p = Progress {
+                error: p.error.take(),
+                ..Progress::new(&self.torrent().info, &have, &wanted)
+            }
+        });
+        self.inner.queue.notify_one();
+        Ok(())
+    }
+
+    pub fn mode(&self) -> DownloadMode {
+        self.inner.picker.lock().unwrap().mode()
+    }
+
+    pub fn set_mode(&self, mode: DownloadMode) {
+        let priorities = self.inner.file_priorities.lock().unwrap();
+        self.rebuild_picker(&priorities, mode);
+    }
+
+    // Reads one of the torrent's files while it downloads, waiting for
+    // pieces that aren't in yet. A skipped file is downloaded after all.
+    pub async fn reader(&self, file: usize) -> Result<FileReader> {
+        match self.file_priorities().get(file) {
+            None => return Err(Error::Usage(format!("no file {}", file))),
+            Some(FilePriority::Skip) => self.set_file_priority(file, FilePriority::Normal).await?,
+            Some(_) => {}
+        }
+        let id = self.inner.next_reader.fetch_add(1, Ordering::Relaxed);
+        Ok(FileReader::new(self.clone(), id, self.inner.files[file].clone()))
+    }
+
+    // the wanted pieces of the new picker
+    fn rebuild_picker(&self, priorities: &[FilePriority], mode: DownloadMode) -> Bitfield {
+        let picker = Picker::new(&self.torrent().info, &self.inner.files, priorities, mode);
+        let wanted = picker.wanted();
+        *self.inner.picker.lock().unwrap() = picker;
+        wanted
+    }
+
+    fn picker(&self) -> Picker {
+        self.inner.picker.lock().unwrap().clone()
+    }
+
+    fn deadlines(&self) -> Deadlines {
+        let mut deadlines = Deadlines::default();
+        let piece_count = self.torrent().info.piece_count();
+        for &(piece, since) in self.inner.readers.lock().unwrap().values() {
+            deadlines.reader_at(piece, since, piece_count);
+        }
+        deadlines
+    }
+
+    pub(crate) fn storage(&self) -> &Arc<Storage> {
+        &self.inner.storage
+    }
+
+    // Part of a piece once it's been verified, waiting for it to come in
+    // if it hasn't. Pausing or removing the torrent gives up on it, nothing
+    // would ever fetch it.
+    pub(crate) async fn read_piece(&self, index: usize, begin: usize, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut have = self.inner.have.subscribe();
+        let mut state = self.inner.state.subscribe();
+        tokio::select! {
+            biased;
+            _ = have.wait_for(|have| have.get(index)) => {}
+            state = state.wait_for(|s| matches!(s, State::Paused | State::Removed)) => {
+                // a run that failed paused it, that's the more useful error
+                let stopped = match state.map(|s| *s) {
+                    Ok(State::Paused) => self.progress().error.unwrap_or_else(|| "torrent paused".to_owned()),
+                    _ => "torrent removed".to_owned(),
+                };
+                return Err(std::io::Error::other(stopped));
+            }
+        }
+        self.inner.storage.read_block(index, begin, len).await
+    }
+
+    // Moving a reader only resets its deadlines when it lands in another
+    // piece, reading through one shouldn't keep pushing them back.
+    pub(crate) fn reader_moved(&self, id: u64, piece: usize) {
+        let mut readers = self.inner.readers.lock().unwrap();
+        if readers.get(&id).is_none_or(|&(at, _)| at != piece) {
+            readers.insert(id, (piece, Instant::now()));
+        }
+    }
+
+    pub(crate) fn reader_dropped(&self, id: u64) {
+        self.inner.readers.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn added(&self) -> u64 {
+        self.inner.added
+    }
+
+    pub(crate) fn have(&self) -> Bitfield {
+        self.inner.have.borrow().clone()
+    }
+
+    // the scheduler's say in the state, it only ever moves a started
+    // torrent between running and waiting in the queue
+    pub(crate) fn set_queued(&self, queued: bool) {
+        let (from, to) = match queued {
+            true => (State::Running, State::Queued),
+            false => (State::Queued, State::Running),
+        };
+        self.inner.state.send_if_modified(|state| {
+            let changed = *state == from;
+            if changed {
+                *state = to;
+            }
+            changed
+        });
+    }
+
+    fn transition(&self, from: &[State], to: State) {
+        let changed = self.inner.state.send_if_modified(|state| {
+            let changed = from.contains(state);
+            if changed {
+                *state = to;
+            }
+            changed
+        });
+        if changed {
+            self.inner.queue.notify_one();
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.inner.events.subscribe()
+    }
+
+    // resolves once every piece is verified on disk, or with the error
+    // that stopped the download
+    pub async fn wait_complete(&self) -> Result<()> {
+        let mut progress = self.inner.progress.subscribe();
+        let mut state = self.inner.state.subscribe();
+        loop {
+            {
+                let progress = progress.borrow_and_update();
+                if progress.is_complete() {
+                    return Ok(());
+                }
+                if let Some(e) = &progress.error {
+                    return Err(Error::Stopped(e.clone()));
+                }
+            }
+            tokio::select! {
+                _ = progress.changed() => {}
+                Ok(_) = state.wait_for(|s| *s == State::Removed) => {
+                    return Err(Error::Stopped("torrent removed".to_owned()));
+                }
+            }
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        // a finished download goes from counting against the download
+        // limit to the seed one
+        if let Event::Completed = event {
+            self.inner.queue.notify_one();
+        }
+        // nobody listening is fine
+        let _ = self.inner.session_events.send((self.inner.info_hash, event.clone()));
+        let _ = self.inner.events.send(event);
+    }
+
+    // whether it's one we didn't know about
+    fn add_local_peer(&self, addr: String, info_hash: [u8; 20]) -> bool {
+        self.inner.local_peers.send_if_modified(|peers| {
+            let new = !peers.iter().any(|(a, _)| *a == addr);
+            if new {
+                peers.push((addr, info_hash));
+            }
+            new
+        })
+    }
+
+    fn piece_completed(&self, index: usize) {
+        let len = self.torrent().info.piece_len(index);
+        self.inner.have.send_modify(|have| have.set(index, true));
+        if self.picker().wanted().get(index) {
+            self.inner.progress.send_modify(|p| {
+                p.pieces_done += 1;
+                p.bytes_left -= len;
+            });
+        }
+        self.inner.queue.notify_one();
+        self.emit(Event::PieceCompleted(index));
+    }
+
+    fn fail(&self, error: String) {
+        self.inner.progress.send_modify(|p| p.error = Some(error.clone()));
+        self.inner.state.send_replace(State::Paused);
+        self.inner.queue.notify_one();
+        self.emit(Event::Error(error));
+    }
+
+    // what to tell subscribers when a run was cut short
+    fn stopped_event(&self) -> Event {
+        match self.state() {
+            State::Queued => Event::Queued,
+            _ => Event::Paused,
+        }
+    }
+}
+
+async fn run_torrent(handle: TorrentHandle, ctx: Context, mut incoming: mpsc::Receiver<Incoming>) {
+    let mut state = handle.inner.state.subscribe();
+    let handshakes: Vec<Handshake> = handle
+        .torrent()
+        .info_hashes()
+        .into_iter()
+        .map(|info_hash| Handshake::new(info_hash, &ctx.peer_id).with_v2(handle.torrent().info.is_v2()))
+        .collect();
+
+    loop {
+        // anyone connecting while we're paused or queued is turned away
+        let running = loop {
+            tokio::select! {
+                s = state.wait_for(|s| matches!(s, State::Running | State::Removed)) => break s.map(|s| *s),
+                Some((stream, _)) = incoming.recv() => {
+                    debug!(addr = ?stream.peer_addr().ok(), "not running, closing incoming peer");
+                }
+            }
+        };
+        match running {
+            Ok(State::Running) => {}
+            _ => return,
+        }
+        handle.emit(Event::Started);
+
+        // both phases run until they finish or we're paused, queued or
+        // removed
+        let stopped = state.clone();
+        let stopped = async move {
+            let mut stopped = stopped;
+            let _ = stopped.wait_for(|s| *s != State::Running).await;
+        };
+        tokio::pin!(stopped);
+
+        // peers that connect while we download get what we have so far,
+        // the ones we're getting pieces from first
+        if !handle.progress().is_complete() {
+            let seeder = seeder(&handle, &ctx, &handshakes, false);
+            let result = tokio::select! {
+                result = download(&handle, &ctx, &handshakes, &seeder.choker) => Some(result),
+                _ = accept(&seeder, &mut incoming) => unreachable!("accepts until dropped"),
+                _ = &mut stopped => None,
+            };
+            match result {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    handle.fail(e.to_string());
+                    continue;
+                }
+                None => {
+                    handle.emit(handle.stopped_event());
+                    continue;
+                }
+            }
+        }
+        handle.emit(Event::Completed);
+
+        // a file that stops being skipped sends us back to downloading
+        let mut progress = handle.inner.progress.subscribe();
+        tokio::select! {
+            _ = seed(&handle, &ctx, &handshakes, &mut incoming) => {}
+            _ = &mut stopped => {}
+            _ = progress.wait_for(|p| !p.is_complete()) => {}
+        }
+        handle.emit(handle.stopped_event());
+    }
+}
+
+// how many peers we download from at once
+const MAX_PEERS: usize = 4;
+// weight of the latest piece in a peer's download rate
+const RATE_SMOOTHING: f64 = 0.3;
+
+// What the peers downloading a torrent share: the pieces being fetched
+// right now, so no two ask for the same one, and how fast each peer has
+// been, so the pieces a reader is about to need go to the quick ones.
+#[derive(Default)]
+struct Swarm {
+    in_flight: Mutex<HashSet<usize>>,
+    // bytes a second, per peer address
+    rates: Mutex<HashMap<String, f64>>,
+    // a piece came in or was given up on
+    changed: Notify,
+}
+
+impl Swarm {
+    // in the faster half of the peers we've timed, everyone is until
+    // we've timed someone
+    fn is_fast(&self, addr: &str) -> bool {
+        let rates = self.rates.lock().unwrap();
+        let Some(&rate) = rates.get(addr) else {
+            return rates.is_empty();
+        };
+        let mut all: Vec<f64> = rates.values().copied().collect();
+        all.sort_by(f64::total_cmp);
+        rate >= all[all.len() / 2]
+    }
+
+    // Claims what the source at `addr` should fetch next, of the pieces
+    // `available` says it has. With nothing to claim it's what's still
+    // missing instead, whoever is fetching it.
+    fn claim(&self, handle: &TorrentHandle, addr: &str, available: impl Fn(usize) -> bool) -> std::result::Result<Claim<'_>, Bitfield> {
+        let have = handle.have();
+        let picker = handle.picker();
+        let deadlines = handle.deadlines();
+        let fast = self.is_fast(addr);
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let index = picker.pick(&have, |i| !in_flight.contains(&i) && available(i), &deadlines, fast);
+        match index {
+            Some(index) => {
+                in_flight.insert(index);
+                Ok(Claim { swarm: self, index })
+            }
+            None => Err(picker.wanted().difference(&have)),
+        }
+    }
+
+    // until someone's claim is dropped, or a second has gone by in case a
+    // reader moving has made something else urgent
+    async fn changed(&self) {
+        let _ = tokio::time::timeout(Duration::from_secs(1), self.changed.notified()).await;
+    }
+
+    fn record(&self, addr: &str, bytes: usize, elapsed: Duration) {
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        self.rates
+            .lock()
+            .unwrap()
+            .entry(addr.to_owned())
+            .and_modify(|rate| *rate += RATE_SMOOTHING * (sample - *rate))
+            .or_insert(sample);
+    }
+}
+
+// A piece one peer is fetching, handed back to the others when dropped
+// whether it came in or not.
+struct Claim<'a> {
+    swarm: &'a Swarm,
+    index: usize,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.swarm.in_flight.lock().unwrap().remove(&self.index);
+        self.swarm.changed.notify_waiters();
+    }
+}
+
+// Announces under each of the torrent's info hashes, pairing every peer
+// with the handshake for the swarm it turned up in. Only an error if no
+// announce got through.
+async fn announce(handle: &TorrentHandle, ctx: &Context, handshakes: &[Handshake], left: usize) -> Result<Vec<(String, Handshake)>> {
+    let mut peers: Vec<(String, Handshake)> = Vec::new();
+    let mut error = None;
+    let mut announced = false;
+    let transfer = handle.inner.stats.snapshot();
+    let transferred = (transfer.uploaded, transfer.downloaded);
+    for handshake in handshakes {
+        match tracker::request_tracker_as(handle.torrent(), handshake.info_hash(), &ctx.peer_id, ctx.port, transferred, left).await {
+            Ok(tracker) => {
+                announced = true;
+                for peer in tracker {
+                    if !peers.iter().any(|(addr, _)| *addr == peer.ip) {
+                        peers.push((peer.ip, handshake.clone()));
+                    }
+                }
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+    match error {
+        Some(e) if !announced => Err(e),
+        _ => Ok(peers),
+    }
+}
+
+async fn download(handle: &TorrentHandle, ctx: &Context, handshakes: &[Handshake], choker: &SharedChoker) -> Result<()> {
+    let torrent = handle.torrent();
+    let web_seeds: Vec<String> = torrent.url_list.iter().filter(|url| webseed::is_supported(url)).cloned().collect();
+    let left = handle.progress().bytes_left;
+    // the tracker is the only peer source a private torrent is allowed,
+    // local discovery never hands it any peers
+    let local = ctx.local_discovery && !handle.is_private();
+    let peers = match announce(handle, ctx, handshakes, left).await {
+        Ok(peers) => peers,
+        Err(e) if !web_seeds.is_empty() || local => {
+            warn!("announce failed, carrying on without the tracker's peers: {}", e);
+            Vec::new()
+        }
+        Err(e) => return Err(e),
+    };
+    // web seeds first, they're always there and usually quick
+    let mut sources: Vec<String> = web_seeds.iter().chain(peers.iter().map(|(addr, _)| addr)).cloned().collect();
+    // anyone not found through a hybrid's v1 swarm gets the primary handshake
+    let mut swarms: HashMap<String, Handshake> = peers.into_iter().collect();
+    let mut local_peers = handle.inner.local_peers.subscribe();
+
+    // keep up to MAX_PEERS sources going, each failure pushes that
+    // source's next attempt further out until it's given up on entirely
+    let swarm = Arc::new(Swarm::default());
+    let mut backoff = PeerBackoff::default();
+    let mut busy: HashSet<String> = HashSet::new();
+    let mut tasks = JoinSet::new();
+    // when we'll stop waiting on local discovery to find someone
+    let mut out_of_peers: Option<Instant> = None;
+    loop {
+        if handle.progress().is_complete() {
+            return Ok(());
+        }
+
+        for (addr, info_hash) in local_peers.borrow_and_update().iter() {
+            if !sources.contains(addr) {
+                sources.push(addr.clone());
+                if let Some(handshake) = handshakes.iter().find(|h| h.info_hash() == info_hash) {
+                    swarms.insert(addr.clone(), handshake.clone());
+                }
+            }
+        }
+
+        let now = Instant::now();
+        while tasks.len() < MAX_PEERS {
+            let Some(addr) = sources.iter().find(|a| !busy.contains(*a) && backoff.is_ready(a, now)) else {
+                break;
+            };
+            busy.insert(addr.to_string());
+            let web = web_seeds.contains(addr);
+            let handshake = swarms.get(addr).unwrap_or(&handshakes[0]).clone();
+            let (handle, ctx, swarm, choker, addr) = (handle.clone(), ctx.clone(), swarm.clone(), choker.clone(), addr.to_string());
+            tasks.spawn(
+                async move {
+                    let mut fetched = 0;
+                    let result = match web {
+                        true => download_from_web(&handle, &ctx, &swarm, &addr, &mut fetched).await,
+                        false => download_from(&handle, &ctx, &handshake, &swarm, &choker, &addr, &mut fetched).await,
+                    };
+                    (addr, fetched, result)
+                }
+                .in_current_span(),
+            );
+        }
+
+        let mut retry = backoff.next_retry(sources.iter().filter(|a| !busy.contains(*a)).map(|a| a.as_str()));
+        if tasks.is_empty() && retry.is_none() {
+            if !local {
+                return Err(Error::Tracker("ran out of peers".to_owned()));
+            }
+            // there may yet be someone on the local network, for a while
+            let deadline = *out_of_peers.get_or_insert(now + ctx.timeouts.discovery);
+            if now >= deadline {
+                return Err(Error::Timeout("local peers"));
+            }
+            retry = Some(deadline);
+        } else {
+            out_of_peers = None;
+        }
+        let retry = retry.filter(|_| tasks.len() < MAX_PEERS);
+        let sleep = async {
+            match retry {
+                Some(at) => tokio::time::sleep_until(at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            Some(joined) = tasks.join_next() => match joined {
+                Ok((addr, fetched, result)) => {
+                    busy.remove(&addr);
+                    if fetched > 0 {
+                        backoff.record_success(&addr);
+                    }
+                    if let Err(e) = result {
+                        info!(%addr, "peer failed: {}", e);
+                        backoff.record_failure(&addr, Instant::now());
+                    }
+                }
+                Err(e) => warn!("peer task failed: {}", e),
+            },
+            _ = sleep => {}
+            Ok(()) = local_peers.changed(), if local => {}
+        }
+    }
+}
+
+#[tracing::instrument(name = "peer", skip_all, fields(%addr))]
+async fn download_from(
+    handle: &TorrentHandle,
+    ctx: &Context,
+    handshake: &Handshake,
+    swarm: &Swarm,
+    choker: &SharedChoker,
+    addr: &str,
+    fetched: &mut usize,
+) -> Result<()> {
+    let info = &handle.torrent().info;
+    let (mut conn, h) = PeerConnection::connect(addr, handshake, info.piece_count(), ctx.timeouts).await?;
+    info!(peer_id = %hex::encode(h.peer_id), "downloading");
+    conn.set_bandwidth(handle.bandwidth().clone());
+    conn.set_stats(&handle.inner.stats);
+
+    // pieces the peer doesn't have are left for someone else, its
+    // bitfield may only turn up while we're asking for the first one. The
+    // picker is looked at afresh for every piece so priority changes and
+    // readers moving take effect straight away.
+    loop {
+        let claim = match swarm.claim(handle, addr, |i| conn.has_piece(i) != Some(false)) {
+            Ok(claim) => claim,
+            Err(missing) if missing.none() => return Ok(()),
+            // what's left that this peer has is someone else's for now,
+            // but they may drop it or a reader may want it from us
+            // Meanwhile the peer keeps hearing whether we still want
+            // anything from it, and its haves keep coming in.
+            Err(missing) if missing.ones().any(|i| conn.has_piece(i) != Some(false)) => {
+                conn.update_interest(&missing).await?;
+                tokio::select! {
+                    _ = swarm.changed() => {}
+                    msg = conn.recv() => if msg?.is_none() {
+                        return Err(Error::Protocol("peer hung up".to_owned()));
+                    },
+                }
+                continue;
+            }
+            Err(missing) => {
+                conn.update_interest(&missing).await?;
+                return Err(Error::Protocol("peer has none of the pieces we still need".to_owned()));
+            }
+        };
+
+        let index = claim.index;
+        let started = Instant::now();
+        let blocks = match conn.download_piece(info, index).await {
+            Ok(blocks) => blocks,
+            Err(_) if conn.has_piece(index) == Some(false) => continue,
+            Err(e) => return Err(e),
+        };
+        store_piece(handle, swarm, addr, index, &blocks, started).await?;
+        if let Some(peer) = conn.peer_addr() {
+            choker.record_download(peer.ip(), info.piece_len(index) as u64);
+        }
+        *fetched += 1;
+    }
+}
+
+// Same as download_from but for a web seed, which has every piece.
+#[tracing::instrument(name = "web_seed", skip_all, fields(%url))]
+async fn download_from_web(
+    handle: &TorrentHandle,
+    ctx: &Context,
+    swarm: &Swarm,
+    url: &str,
+    fetched: &mut usize,
+) -> Result<()> {
+    let info = &handle.torrent().info;
+    let mut seed = WebSeed::new(url, ctx.timeouts, handle.bandwidth().clone(), &handle.inner.stats)?;
+    info!("downloading");
+    loop {
+        let claim = match swarm.claim(handle, url, |_| true) {
+            Ok(claim) => claim,
+            Err(missing) if missing.none() => return Ok(()),
+            Err(_) => {
+                swarm.changed().await;
+                continue;
+            }
+        };
+        let started = Instant::now();
+        let blocks = seed.fetch_piece(info, handle.files(), claim.index).await?;
+        store_piece(handle, swarm, url, claim.index, &blocks, started).await?;
+        *fetched += 1;
+    }
+}
+
+// checks a piece from `addr` against the torrent and writes it out
+async fn store_piece(
+    handle: &TorrentHandle,
+    swarm: &Swarm,
+    addr: &str,
+    index: usize,
+    blocks: &[Bytes],
+    started: Instant,
+) -> Result<()> {
+    let info = &handle.torrent().info;
+    if !handle.inner.hashes.check(index, blocks) {
+        return Err(Error::HashMismatch { index });
+    }
+    handle.storage().write_piece(index, blocks).await?;
+    swarm.record(addr, info.piece_len(index), started.elapsed());
+    handle.piece_completed(index);
+    Ok(())
+}
+
+// Everything uploading to the torrent's peers needs, shared by all of them.
+// Until we're `seeding` upload slots go to the peers we download fastest
+// from.
+fn seeder(handle: &TorrentHandle, ctx: &Context, handshakes: &[Handshake], seeding: bool) -> Seeder {
+    Seeder {
+        handshakes: handshakes.to_vec(),
+        info: Arc::new(handle.torrent().info.clone()),
+        storage: handle.storage().clone(),
+        hashes: handle.inner.hashes.clone(),
+        have: handle.inner.have.subscribe(),
+        choker: SharedChoker::new(Choker::new(ctx.upload_slots, seeding)),
+        timeouts: ctx.timeouts,
+        bandwidth: handle.bandwidth().clone(),
+        stats: handle.inner.stats.clone(),
+    }
+}
+
+// Answers whoever connects to us for as long as it's polled, dropping it
+// disconnects them all again.
+async fn accept(seeder: &Seeder, incoming: &mut mpsc::Receiver<Incoming>) {
+    let mut peers = JoinSet::new();
+    peers.spawn(seeder.choker.clone().run());
+    loop {
+        tokio::select! {
+            Some(incoming) = incoming.recv() => {
+                peers.spawn(seeder.clone().serve_incoming(incoming).in_current_span());
+            }
+            Some(_) = peers.join_next() => {}
+            else => std::future::pending::<()>().await,
+        }
+    }
+}
+
+async fn seed(
+    handle: &TorrentHandle,
+    ctx: &Context,
+    handshakes: &[Handshake],
+    incoming: &mut mpsc::Receiver<Incoming>,
+) {
+    let seeder = seeder(handle, ctx, handshakes, true);
+
+    // without a tracker we can still serve whoever finds us
+    let announced = match announce(handle, ctx, handshakes, 0).await {
+        Ok(peers) => peers,
+        Err(e) => {
+            warn!("announce failed, waiting for incoming peers: {}", e);
+            Vec::new()
+        }
+    };
+    // dropping the set when we're paused aborts every peer task with it
+    let mut peers = JoinSet::new();
+    for (addr, handshake) in announced {
+        peers.spawn(seeder.clone().serve_outgoing(addr, handshake).in_current_span());
+    }
+    let outgoing = async {
+        while peers.join_next().await.is_some() {}
+        std::future::pending::<()>().await
+    };
+    tokio::join!(accept(&seeder, incoming), outgoing);
+}
@@ -0,0 +1,281 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+// rates are averaged over this many seconds
+pub const RATE_WINDOW_SECS: u64 = 5;
+
+// Bytes per second over the last few seconds, kept as one bucket per
+// second so a busy connection doesn't grow it.
+struct RateWindow {
+    start: Instant,
+    // (seconds since start, bytes in that second)
+    buckets: VecDeque<(u64, u64)>,
+}
+
+impl Default for RateWindow {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            buckets: VecDeque::new(),
+        }
+    }
+}
+
+impl RateWindow {
+    fn record(&mut self, bytes: u64, now: Instant) {
+        let second = now.duration_since(self.start).as_secs();
+        match self.buckets.back_mut() {
+            Some((s, total)) if *s == second => *total += bytes,
+            _ => self.buckets.push_back((second, bytes)),
+        }
+        while self
+            .buckets
+            .front()
+            .is_some_and(|(s, _)| s + RATE_WINDOW_SECS <= second)
+        {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn rate(&self, now: Instant) -> f64 {
+        let second = now.duration_since(self.start).as_secs();
+        let total: u64 = self
+            .buckets
+            .iter()
+            .filter(|(s, _)| s + RATE_WINDOW_SECS > second)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        // a window younger than RATE_WINDOW_SECS is averaged over what
+        // there is of it
+        let span = now
+            .duration_since(self.start)
+            .as_secs_f64()
+            .clamp(1.0, RATE_WINDOW_SECS as f64);
+        total as f64 / span
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    downloaded: u64,
+    uploaded: u64,
+    download_rate: RateWindow,
+    upload_rate: RateWindow,
+    peers: usize,
+    peers_choking: usize,
+    pieces_in_flight: usize,
+}
+
+// Point in time view of a Stats, rates in bytes per second.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub peers: usize,
+    // connected peers that have us choked
+    pub peers_choking: usize,
+    pub pieces_in_flight: usize,
+}
+
+impl Snapshot {
+    // seconds until `bytes_left` is in at the current rate, None when
+    // nothing is coming in to go by
+    pub fn eta_secs(&self, bytes_left: usize) -> Option<u64> {
+        match self.download_rate {
+            0 if bytes_left > 0 => None,
+            0 => Some(0),
+            rate => Some(bytes_left as u64 / rate),
+        }
+    }
+}
+
+// Transfer counters shared by all of a torrent's connections. Each
+// connection reports through the PeerStats it gets from `peer`.
+#[derive(Clone, Default)]
+pub struct Stats {
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl Stats {
+    // a newly connected peer, which like every peer starts out choking us
+    pub fn peer(&self) -> PeerStats {
+        let mut counters = self.counters.lock().unwrap();
+        counters.peers += 1;
+        counters.peers_choking += 1;
+        PeerStats {
+            stats: self.clone(),
+            choked: true,
+            in_flight: 0,
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let now = Instant::now();
+        let counters = self.counters.lock().unwrap();
+        Snapshot {
+            downloaded: counters.downloaded,
+            uploaded: counters.uploaded,
+            download_rate: counters.download_rate.rate(now) as u64,
+            upload_rate: counters.upload_rate.rate(now) as u64,
+            peers: counters.peers,
+            peers_choking: counters.peers_choking,
+            pieces_in_flight: counters.pieces_in_flight,
+        }
+    }
+}
+
+// One connection's share of a Stats. Dropping it takes the peer, and
+// whatever it was still downloading, back out of the counts.
+pub struct PeerStats {
+    stats: Stats,
+    choked: bool,
+    in_flight: usize,
+}
+
+impl PeerStats {
+    pub fn set_choked(&mut self, choked: bool) {
+        if choked != self.choked {
+            self.choked = choked;
+            let mut counters = self.stats.counters.lock().unwrap();
+            if choked {
+                counters.peers_choking += 1;
+            } else {
+                counters.peers_choking -= 1;
+            }
+        }
+    }
+
+    pub fn downloaded(&self, bytes: usize) {
+        let mut counters = self.stats.counters.lock().unwrap();
+        counters.downloaded += bytes as u64;
+        counters.download_rate.record(bytes as u64, Instant::now());
+    }
+
+    pub fn uploaded(&self, bytes: usize) {
+        let mut counters = self.stats.counters.lock().unwrap();
+        counters.uploaded += bytes as u64;
+        counters.upload_rate.record(bytes as u64, Instant::now());
+    }
+
+    pub fn piece_started(&mut self) {
+        self.in_flight += 1;
+        self.stats.counters.lock().unwrap().pieces_in_flight += 1;
+    }
+
+    pub fn piece_finished(&mut self) {
+        self.in_flight -= 1;
+        self.stats.counters.lock().unwrap().pieces_in_flight -= 1;
+    }
+}
+
+impl Drop for PeerStats {
+    fn drop(&mut self) {
+        let mut counters = self.stats.counters.lock().unwrap();
+        counters.peers -= 1;
+        if self.choked {
+            counters.peers_choking -= 1;
+        }
+        counters.pieces_in_flight -= self.in_flight;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn secs(s: f64) -> Duration {
+        Duration::from_secs_f64(s)
+    }
+
+    #[test]
+    fn rate_is_averaged_over_the_window() {
+        let mut window = RateWindow::default();
+        let t0 = window.start;
+        // a young window is averaged over what there is of it, but never
+        // less than a second
+        window.record(1000, t0);
+        assert_eq!(window.rate(t0), 1000.0);
+        window.record(1000, t0 + secs(1.5));
+        assert_eq!(window.rate(t0 + secs(2.0)), 1000.0);
+        window.record(3000, t0 + secs(4.0));
+        assert_eq!(window.rate(t0 + secs(4.0)), 5000.0 / 4.0);
+        // from then on, the last RATE_WINDOW_SECS whole seconds
+        assert_eq!(window.rate(t0 + secs(5.0)), 4000.0 / 5.0);
+        assert_eq!(window.rate(t0 + secs(6.5)), 3000.0 / 5.0);
+        assert_eq!(window.rate(t0 + secs(9.0)), 0.0);
+    }
+
+    #[test]
+    fn old_buckets_are_dropped() {
+        let mut window = RateWindow::default();
+        let t0 = window.start;
+        for second in 0..100 {
+            window.record(10, t0 + secs(second as f64));
+            window.record(10, t0 + secs(second as f64 + 0.5));
+        }
+        assert_eq!(window.buckets.len(), RATE_WINDOW_SECS as usize);
+        assert_eq!(window.rate(t0 + secs(99.5)), 20.0);
+    }
+
+    #[test]
+    fn eta_needs_something_coming_in() {
+        let snapshot = |download_rate| Snapshot {
+            download_rate,
+            ..Snapshot::default()
+        };
+        assert_eq!(snapshot(0).eta_secs(1000), None);
+        assert_eq!(snapshot(0).eta_secs(0), Some(0));
+        assert_eq!(snapshot(100).eta_secs(1050), Some(10));
+        assert_eq!(snapshot(100).eta_secs(0), Some(0));
+    }
+
+    #[test]
+    fn peers_count_until_dropped() {
+        let stats = Stats::default();
+        let mut a = stats.peer();
+        let mut b = stats.peer();
+        let counts = |s: &Stats| {
+            let s = s.snapshot();
+            (s.peers, s.peers_choking, s.pieces_in_flight)
+        };
+        assert_eq!(counts(&stats), (2, 2, 0));
+
+        a.set_choked(false);
+        a.set_choked(false);
+        a.piece_started();
+        a.piece_started();
+        b.piece_started();
+        assert_eq!(counts(&stats), (2, 1, 3));
+        a.piece_finished();
+        b.set_choked(false);
+        b.set_choked(true);
+        assert_eq!(counts(&stats), (2, 1, 2));
+
+        // whatever a peer was in the middle of goes with it
+        drop(a);
+        assert_eq!(counts(&stats), (1, 1, 1));
+        drop(b);
+        assert_eq!(counts(&stats), (0, 0, 0));
+    }
+
+    // totals stay after the peers that moved them are gone
+    #[test]
+    fn transfers_add_up() {
+        let stats = Stats::default();
+        let peer = stats.peer();
+        peer.downloaded(1000);
+        peer.uploaded(300);
+        stats.peer().downloaded(500);
+        drop(peer);
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.downloaded, snapshot.uploaded), (1500, 300));
+        assert_eq!((snapshot.download_rate, snapshot.upload_rate), (1500, 300));
+    }
+}
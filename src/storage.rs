@@ -0,0 +1,345 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tokio_util::bytes::Bytes;
+use tracing::debug;
+
+use crate::bitfield::Bitfield;
+use crate::merkle::PieceHashes;
+use crate::picker::FilePriority;
+use crate::torrent::{self, Info};
+
+// where the bits of edge pieces that belong to skipped files are kept,
+// inside a multi-file torrent's directory
+const PARTS_FILE: &str = ".parts";
+
+// Local data for a torrent, a single file or a directory of them. Reads
+// and writes are positioned so the file handles are shared behind a lock
+// between all the peers we serve.
+//
+// Skipped files aren't created. Whatever a wanted piece holds for one
+// goes to the parts file instead, at its offset in the torrent, so the
+// piece can still be verified and served. Padding between files isn't
+// stored at all, it reads back as zeros.
+pub struct Storage {
+    files: Vec<torrent::File>,
+    // None for files that don't exist on disk yet
+    handles: Mutex<Handles>,
+    root: PathBuf,
+    length: usize,
+    plen: usize,
+}
+
+struct Handles {
+    files: Vec<Option<File>>,
+    // opened the first time a skipped file needs somewhere to go
+    parts: Option<File>,
+}
+
+// read/write so downloads can fill it in, data we're only allowed to
+// read is still good for seeding
+async fn open_file(path: &Path, create: bool) -> std::io::Result<Option<File>> {
+    if create {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+    }
+    let opened = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .truncate(false)
+        .open(path)
+        .await;
+    match opened {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !create => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Ok(Some(File::open(path).await?)),
+        Err(e) => Err(e),
+    }
+}
+
+fn invalid_input(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_owned())
+}
+
+impl Storage {
+    // `path` is the file itself for single file torrents and the
+    // directory holding the files otherwise. Files already on disk are
+    // opened whatever their priority, missing ones only created if
+    // they're wanted.
+    pub async fn open(info: &Info, path: impl AsRef<Path>, priorities: &[FilePriority]) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let files = info.files()?;
+        let root = match info.is_multi_file() {
+            true => path.to_owned(),
+            false => path.parent().map(Path::to_owned).unwrap_or_default(),
+        };
+
+        let mut handles = Vec::with_capacity(files.len());
+        for (index, file) in files.iter().enumerate() {
+            let wanted = priorities.get(index).is_none_or(|p| *p != FilePriority::Skip);
+            let file_path = match info.is_multi_file() {
+                true => root.join(&file.path),
+                false => path.to_owned(),
+            };
+            handles.push(open_file(&file_path, wanted).await?);
+        }
+        let parts = match info.is_multi_file() {
+            true => open_file(&root.join(PARTS_FILE), false).await?,
+            false => None,
+        };
+
+        let files = files
+            .into_iter()
+            .map(|file| match info.is_multi_file() {
+                true => torrent::File {
+                    path: root.join(&file.path),
+                    ..file
+                },
+                false => torrent::File {
+                    path: path.to_owned(),
+                    ..file
+                },
+            })
+            .collect();
+        Ok(Self {
+            files,
+            handles: Mutex::new(Handles { files: handles, parts }),
+            root,
+            length: info.length(),
+            plen: info.plen,
+        })
+    }
+
+    // Makes sure a file that's become wanted exists, moving over whatever
+    // of its data earlier edge pieces left in the parts file.
+    pub async fn want_file(&self, index: usize) -> std::io::Result<()> {
+        let mut handles = self.handles.lock().await;
+        if handles.files[index].is_some() {
+            return Ok(());
+        }
+        let file = &self.files[index];
+        let mut real = open_file(&file.path, true).await?.expect("created");
+        if let Some(parts) = &mut handles.parts {
+            let on_disk = parts.metadata().await?.len() as usize;
+            let end = file.end().min(on_disk);
+            if end > file.offset {
+                let mut data = vec![0; end - file.offset];
+                parts.seek(SeekFrom::Start(file.offset as u64)).await?;
+                parts.read_exact(&mut data).await?;
+                real.write_all(&data).await?;
+                real.flush().await?;
+            }
+        }
+        handles.files[index] = Some(real);
+        Ok(())
+    }
+
+    // a piece's blocks, in order, as they came from the peer
+    pub async fn write_piece(&self, index: usize, blocks: &[Bytes]) -> std::io::Result<()> {
+        let mut handles = self.handles.lock().await;
+        let mut offset = index * self.plen;
+        for block in blocks {
+            self.write_at(&mut handles, offset, block).await?;
+            offset += block.len();
+        }
+        Ok(())
+    }
+
+    pub async fn read_block(&self, index: usize, begin: usize, length: usize) -> std::io::Result<Vec<u8>> {
+        let offset = index * self.plen + begin;
+        if offset + length > self.length {
+            return Err(invalid_input("block past end of torrent"));
+        }
+
+        let mut block = vec![0; length];
+        let mut handles = self.handles.lock().await;
+        self.read_at(&mut handles, offset, &mut block).await?;
+        Ok(block)
+    }
+
+    // the files, or the parts file, that bytes from `offset` on live in,
+    // with the offset into each and how much of the range it holds
+    fn spans(&self, offset: usize, len: usize) -> impl Iterator<Item = (usize, u64, usize)> + '_ {
+        let end = offset + len;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(move |(_, f)| f.offset < end && f.end() > offset)
+            .map(move |(i, f)| {
+                let start = offset.max(f.offset);
+                (i, start, end.min(f.end()) - start)
+            })
+            .map(|(i, start, len)| (i, start as u64, len))
+    }
+
+    async fn write_at(&self, handles: &mut Handles, offset: usize, data: &[u8]) -> std::io::Result<()> {
+        for (index, start, len) in self.spans(offset, data.len()).collect::<Vec<_>>() {
+            let from = start as usize - offset;
+            let chunk = &data[from..from + len];
+            let file = match &mut handles.files[index] {
+                Some(file) => {
+                    file.seek(SeekFrom::Start(start - self.files[index].offset as u64)).await?;
+                    file
+                }
+                None => {
+                    if handles.parts.is_none() {
+                        handles.parts = open_file(&self.root.join(PARTS_FILE), true).await?;
+                    }
+                    let parts = handles.parts.as_mut().expect("created");
+                    parts.seek(SeekFrom::Start(start)).await?;
+                    parts
+                }
+            };
+            file.write_all(chunk).await?;
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn read_at(&self, handles: &mut Handles, offset: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        for (index, start, len) in self.spans(offset, buf.len()).collect::<Vec<_>>() {
+            let from = start as usize - offset;
+            let chunk = &mut buf[from..from + len];
+            let file = match (&mut handles.files[index], &mut handles.parts) {
+                (Some(file), _) => {
+                    file.seek(SeekFrom::Start(start - self.files[index].offset as u64)).await?;
+                    file
+                }
+                (None, Some(parts)) => {
+                    parts.seek(SeekFrom::Start(start)).await?;
+                    parts
+                }
+                (None, None) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "file not on disk"));
+                }
+            };
+            file.read_exact(chunk).await?;
+        }
+        Ok(())
+    }
+
+    // whether everything on disk reaches far enough to hold `have`, a
+    // cheap check that saved progress still holds before trusting it
+    pub async fn covers(&self, have: &Bitfield) -> bool {
+        let mut needed = vec![0u64; self.files.len()];
+        let mut parts_needed = 0u64;
+        let handles = self.handles.lock().await;
+        for index in have.ones() {
+            let piece_len = (self.length - index * self.plen).min(self.plen);
+            for (file, start, len) in self.spans(index * self.plen, piece_len) {
+                let end = start + len as u64;
+                match handles.files[file] {
+                    Some(_) => needed[file] = needed[file].max(end - self.files[file].offset as u64),
+                    None => parts_needed = parts_needed.max(end),
+                }
+            }
+        }
+        for (file, needed) in handles.files.iter().zip(needed) {
+            let len = match file {
+                Some(file) => file.metadata().await.map_or(0, |m| m.len()),
+                None => 0,
+            };
+            if len < needed {
+                return false;
+            }
+        }
+        let parts_len = match &handles.parts {
+            Some(parts) => parts.metadata().await.map_or(0, |m| m.len()),
+            None => 0,
+        };
+        parts_len >= parts_needed
+    }
+
+    // hash every piece we hold and return which of them match the
+    // torrent, a short or missing file just means the pieces it should
+    // have held are not set
+    pub async fn verify(&self, info: &Info, hashes: &PieceHashes) -> Bitfield {
+        let mut bitfield = Bitfield::new(info.piece_count());
+        for index in 0..info.piece_count() {
+            let piece = match self.read_block(index, 0, info.piece_len(index)).await {
+                Ok(piece) => piece,
+                Err(_) => continue,
+            };
+            if hashes.check(index, &[piece]) {
+                bitfield.set(index, true);
+            } else {
+                debug!(index, "piece failed verification");
+            }
+        }
+        bitfield
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::tests::multi_file;
+
+    // pieces of 16: a is 0..10, b 10..40, c 40..64
+    async fn storage(dir: &Path, priorities: &[FilePriority]) -> Storage {
+        let torrent = multi_file(&[("a", 10), ("dir/b", 30), ("c", 24)], 16);
+        Storage::open(&torrent.info, dir, priorities).await.unwrap()
+    }
+
+    fn piece(index: usize) -> Vec<u8> {
+        (index * 16..index * 16 + 16).map(|i| i as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn spans_split_ranges_at_file_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), &[FilePriority::Normal; 3]).await;
+        assert_eq!(storage.spans(0, 10).collect::<Vec<_>>(), [(0, 0, 10)]);
+        assert_eq!(storage.spans(8, 16).collect::<Vec<_>>(), [(0, 8, 2), (1, 10, 14)]);
+        assert_eq!(storage.spans(32, 32).collect::<Vec<_>>(), [(1, 32, 8), (2, 40, 24)]);
+        assert_eq!(storage.spans(0, 64).count(), 3);
+    }
+
+    // the edge of a skipped file is kept in the parts file until it's
+    // wanted, then moved into the file itself
+    #[tokio::test]
+    async fn skipped_edges_go_to_the_parts_file() {
+        let dir = tempfile::tempdir().unwrap();
+        use FilePriority::{Normal, Skip};
+        let storage = storage(dir.path(), &[Skip, Normal, Skip]).await;
+        assert!(!dir.path().join("a").exists());
+        assert!(dir.path().join("dir/b").exists());
+
+        for index in 0..3 {
+            storage.write_piece(index, &[Bytes::from(piece(index))]).await.unwrap();
+        }
+        assert!(!dir.path().join("a").exists());
+        assert!(!dir.path().join("c").exists());
+        let parts = std::fs::read(dir.path().join(PARTS_FILE)).unwrap();
+        assert_eq!(parts.len(), 48);
+        assert_eq!(parts[..10], piece(0)[..10]);
+        assert_eq!(parts[40..], piece(2)[8..]);
+        assert_eq!(std::fs::read(dir.path().join("dir/b")).unwrap(), [&piece(0)[10..], &piece(1), &piece(2)[..8]].concat());
+        for index in 0..3 {
+            assert_eq!(storage.read_block(index, 0, 16).await.unwrap(), piece(index));
+        }
+
+        storage.want_file(0).await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("a")).unwrap(), piece(0)[..10]);
+        storage.want_file(2).await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("c")).unwrap(), piece(2)[8..]);
+        // reads now come from the files, not the parts file
+        std::fs::remove_file(dir.path().join(PARTS_FILE)).unwrap();
+        assert_eq!(storage.read_block(0, 0, 16).await.unwrap(), piece(0));
+        assert_eq!(storage.read_block(2, 4, 12).await.unwrap(), piece(2)[4..]);
+    }
+
+    #[tokio::test]
+    async fn nothing_on_disk_for_a_skipped_file_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        use FilePriority::{Normal, Skip};
+        let storage = storage(dir.path(), &[Skip, Normal, Normal]).await;
+        assert_eq!(storage.read_block(0, 0, 16).await.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(storage.read_block(3, 8, 16).await.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
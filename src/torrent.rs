@@ -1,152 +1,643 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
 use serde::{Serialize, Deserialize};
+use serde_bytes::ByteBuf;
 use sha1::{self, Digest};
 
+use crate::error::{Error, Result};
+
 
-#[derive(Serialize, Deserialize,Debug)]
-pub struct Peer {
-    pub ip: String
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Info {
+    // single file torrents have a length, multi-file ones a list of files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length : Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
+    pub name: String,
+    #[serde(rename = "piece length")]
+    pub plen: usize,
+    // v1 SHA-1 piece hashes, v2-only torrents don't have them
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub pieces: Vec<u8>,
+    // BEP 52, 2 for v2 and hybrid torrents
+    #[serde(rename = "meta version", default, skip_serializing_if = "Option::is_none")]
+    pub meta_version: Option<u32>,
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BTreeMap<String, TreeNode>>,
+    // BEP 27, kept as it came so the info hash still matches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+    // where each v2 file starts and ends, worked out from the file tree
+    // the first time it's needed rather than every time a piece is
+    #[serde(skip)]
+    tree_spans: OnceLock<Vec<(usize, usize)>>,
 }
 
-impl Peer {
-    pub fn new(ip_and_port: &[u8]) -> Peer {
-        assert!(ip_and_port.len() == 6, "Invalid peer length");
-        let mut port = (ip_and_port[4] as u16) << 8;
-        port += ip_and_port[5] as u16;
-        let ip = format!("{}.{}.{}.{}:{}", ip_and_port[0], ip_and_port[1], ip_and_port[2], ip_and_port[3], port);
-        Peer { ip }
-    }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub length: usize,
+    // directories then the file name, relative to the torrent's directory
+    pub path: Vec<String>,
+    // BEP 47, "p" marks the padding hybrid torrents line files up with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
 }
 
+impl FileEntry {
+    pub fn is_pad(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+}
 
-#[derive(Serialize, Deserialize,Debug)]
-pub struct Tracker {
-    pub complete: u32,
-    pub incomplete: u32,
-    pub interval: u32,
-    #[serde(rename = "min interval")]
-    pub min_interval: u32,
-    #[serde(with = "serde_bytes")]    
-    pub peers: Vec<u8>
+// A v2 file tree entry, a directory of further entries or a file, which
+// is a dictionary with an empty key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TreeNode {
+    File {
+        #[serde(rename = "")]
+        file: TreeFile,
+    },
+    Dir(BTreeMap<String, TreeNode>),
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreeFile {
+    pub length: usize,
+    // root of the file's merkle tree, empty files don't have one
+    #[serde(rename = "pieces root", default, skip_serializing_if = "Option::is_none")]
+    pub pieces_root: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
 
+// One of a torrent's files, laid out end to end with the others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct File {
+    // relative to where the torrent is saved, just the name for single
+    // file torrents
+    pub path: PathBuf,
+    // where the file starts in the torrent's data, padding included
+    pub offset: usize,
+    pub length: usize,
+    // v2 only
+    pub pieces_root: Option<[u8; 32]>,
+}
 
-impl IntoIterator for Tracker {
-    type Item = Peer;
-    type IntoIter = PeerIterator;
+impl File {
+    pub fn end(&self) -> usize {
+        self.offset + self.length
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        PeerIterator {
-            peers: self.peers,
-            index: 0
+    // the pieces holding any of the file's data
+    pub fn pieces(&self, plen: usize) -> std::ops::Range<usize> {
+        if self.length == 0 {
+            return 0..0;
         }
+        self.offset / plen..self.end().div_ceil(plen)
     }
 }
 
-pub struct PeerIterator {
-    peers: Vec<u8>,
-    index: usize
+// the v2 files in tree order, which is also the order they're laid out in
+fn walk_tree<'a>(tree: &'a BTreeMap<String, TreeNode>, path: &mut Vec<String>, out: &mut Vec<(Vec<String>, &'a TreeFile)>) {
+    for (name, node) in tree {
+        path.push(name.clone());
+        match node {
+            TreeNode::File { file } => out.push((path.clone(), file)),
+            TreeNode::Dir(children) => walk_tree(children, path, out),
+        }
+        path.pop();
+    }
 }
 
-impl Iterator for PeerIterator {
-    type Item = Peer;
+impl Info {
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.peers.len() {
-            return None;
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    // Peers for a private torrent only come from its tracker, never from
+    // anything that finds them by itself like local discovery.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    pub fn piece_count(&self) -> usize {
+        match self.is_v1() {
+            true => self.pieces.len() / 20,
+            false => self.length().div_ceil(self.plen),
         }
-        let peer = Peer::new(&self.peers[self.index..self.index + 6]);
-        self.index += 6;
-        Some(peer)
     }
-}
 
+    pub fn piece_hash(&self, index: usize) -> &[u8] {
+        let start = index * 20;
+        let end = start + 20;
+        &self.pieces[start..end]
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Info {
-    pub length : usize,
-    pub name: String,
-    #[serde(rename = "piece length")]
-    pub plen: usize,
-    #[serde(with = "serde_bytes")]
-    pub pieces: Vec<u8>
-}
+    fn tree_files(&self) -> Vec<(Vec<String>, &TreeFile)> {
+        let mut files = Vec::new();
+        if let Some(tree) = &self.file_tree {
+            walk_tree(tree, &mut Vec::new(), &mut files);
+        }
+        files
+    }
 
-// impl Info {
+    // where each v2 file starts and ends, every one that isn't empty
+    // starting on a piece boundary
+    fn tree_spans(&self) -> &[(usize, usize)] {
+        self.tree_spans.get_or_init(|| {
+            let mut spans = Vec::new();
+            let mut offset: usize = 0;
+            for (_, file) in self.tree_files() {
+                if file.length > 0 {
+                    offset = offset.next_multiple_of(self.plen);
+                }
+                spans.push((offset, offset + file.length));
+                offset += file.length;
+            }
+            spans
+        })
+    }
 
-//     pub fn new(length: usize, name: String, plen: usize, pieces: Vec<u8>) -> Self {
-//         Self { length, name, plen, pieces }
-//     }
+    // every file's bytes, one after the other
+    pub fn length(&self) -> usize {
+        match (&self.files, self.length) {
+            (Some(files), _) => files.iter().map(|f| f.length).sum(),
+            (None, Some(length)) => length,
+            (None, None) => self.tree_spans().last().map_or(0, |&(_, end)| end),
+        }
+    }
 
-//     pub fn get_piece_count(&self) -> usize {
-//         self.pieces.len() / 20
-//     }
+    // The last piece may be shorter than the piece length, and so may the
+    // last of each file's in a v2-only torrent, where there's no padding.
+    pub fn piece_len(&self, index: usize) -> usize {
+        let start = index * self.plen;
+        if !self.is_v1() && self.is_v2() {
+            // files only ever end further along, the first to end past
+            // the piece's start is the one it's in
+            let spans = self.tree_spans();
+            if let Some(&(_, end)) = spans.get(spans.partition_point(|&(_, end)| end <= start)) {
+                return (end - start).min(self.plen);
+            }
+        }
+        (self.length() - start).min(self.plen)
+    }
 
-//     pub fn get_piece(&self, index: usize) -> &[u8] {
-//         let start = index * 20;
-//         let end = start + 20;
-//         &self.pieces[start..end]
-//     }
+    pub fn is_multi_file(&self) -> bool {
+        match (&self.files, self.length, &self.file_tree) {
+            (Some(_), _, _) => true,
+            (None, None, Some(tree)) => !(tree.len() == 1 && tree.values().all(|n| matches!(n, TreeNode::File { .. }))),
+            _ => false,
+        }
+    }
 
-// }
+    // Fails on paths that could land outside the save directory, a
+    // torrent gets no say in where its files end up beyond that. Padding
+    // isn't a file, it only moves the next one along.
+    pub fn files(&self) -> Result<Vec<File>> {
+        let tree = self.tree_files();
+        let root_of = |path: &[String]| -> Result<Option<[u8; 32]>> {
+            let Some((_, file)) = tree.iter().find(|(p, _)| p == path) else {
+                return Ok(None);
+            };
+            match &file.pieces_root {
+                Some(root) => Ok(Some(root.as_slice().try_into().map_err(|_| {
+                    Error::Bencode(format!("pieces root of {:?} isn't 32 bytes", path))
+                })?)),
+                None => Ok(None),
+            }
+        };
 
-fn urlencode(t: &[u8]) -> String {
-    let mut encoded = String::new();
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+        if let Some(entries) = &self.files {
+            let mut offset = 0;
+            let mut files = Vec::with_capacity(entries.len());
+            for entry in entries {
+                if !entry.is_pad() {
+                    files.push(File {
+                        path: safe_path(&entry.path)?,
+                        offset,
+                        length: entry.length,
+                        pieces_root: root_of(&entry.path)?,
+                    });
+                }
+                offset += entry.length;
+            }
+            return Ok(files);
+        }
+        if self.length.is_some() || !self.is_multi_file() {
+            let single = std::slice::from_ref(&self.name);
+            let pieces_root = match tree.as_slice() {
+                [(path, _)] => root_of(path)?,
+                _ => None,
+            };
+            return Ok(vec![File {
+                path: safe_path(single)?,
+                offset: 0,
+                length: self.length(),
+                pieces_root,
+            }]);
+        }
+
+        let mut files = Vec::with_capacity(tree.len());
+        for ((path, file), &(offset, _)) in tree.iter().zip(self.tree_spans()) {
+            files.push(File {
+                path: safe_path(path)?,
+                offset,
+                length: file.length,
+                pieces_root: root_of(path)?,
+            });
+        }
+        Ok(files)
     }
-    encoded
+
 }
 
+fn safe_path(components: &[String]) -> Result<PathBuf> {
+    let path: PathBuf = components.iter().collect();
+    let normal = !components.is_empty()
+        && path.components().count() == components.len()
+        && path.components().all(|c| matches!(c, Component::Normal(_)));
+    if !normal {
+        return Err(Error::Bencode(format!("unsafe file path {:?}", components)));
+    }
+    Ok(path)
+}
 
-#[derive(Debug,Serialize, Deserialize)]
+// the file's path as --only patterns see it, always with forward slashes
+pub fn display_path(path: &Path) -> String {
+    path.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Torrent {
     pub announce : String,
-    pub info : Info
+    pub info : Info,
+    // BEP 19 web seeds, which the spec lets be a single url or a list
+    #[serde(
+        rename = "url-list",
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "one_or_many"
+    )]
+    pub url_list: Vec<String>,
+    // BEP 52, each v2 file's hashes one level below the piece size, keyed
+    // by its pieces root. Files of a piece or less don't need them.
+    #[serde(rename = "piece layers", default, skip_serializing_if = "Option::is_none")]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+    // The metainfo as it was read and where the info dictionary is in it.
+    // The info hash is of those bytes, keys we don't know about included.
+    #[serde(skip)]
+    encoded: Arc<Vec<u8>>,
+    #[serde(skip)]
+    info_span: Range<usize>,
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    // some torrents carry an empty url to mean none
+    let urls = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
 }
 
 impl Torrent {
-    pub fn load_torrent(path: String) -> Self {
-        let encoded_contents = std::fs::read(path).expect("failed to read");
-        let torrent: Torrent =
-        serde_bencode::from_bytes(&encoded_contents).expect("bencode failed");
-        torrent
-    }
-
-    pub fn get_info_hash(&self) -> Vec<u8> {
-        let encoded_info = serde_bencode::to_bytes(&self.info).expect("encode error");
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&encoded_info);
-        let ih = hasher.finalize();
-        ih.to_vec()    
-    }
-
-    pub async fn request_tracker(&self,peer : String ) -> Tracker {
-
-        let params = [
-            ("peer_id".to_owned(), peer.to_owned()),
-            ("port".to_owned(), "6881".to_owned()),
-            ("uploaded".to_owned(), "0".to_owned()),
-            ("downloaded".to_owned(), "0".to_owned()),
-            ("left".to_owned(), self.info.length.to_string()),
-            ("compact".to_owned(), "1".to_owned()),
-        ];
-
-        let params_encoded = serde_urlencoded::to_string(&params).expect("failed to encode");
-        let url = format!(
-            "{}?{}&info_hash={}",
-            self.announce,
-            params_encoded,
-            &urlencode(&self.get_info_hash().as_slice())
+    pub fn load_torrent(path: String) -> Result<Self> {
+        let encoded_contents = std::fs::read(path)?;
+        Self::from_bytes(&encoded_contents)
+    }
+
+    pub fn from_bytes(encoded: &[u8]) -> Result<Self> {
+        let mut torrent: Self = serde_bencode::from_bytes(encoded)?;
+        torrent.info_span = info_span(encoded).ok_or_else(|| Error::Bencode("no info dictionary".to_owned()))?;
+        torrent.encoded = Arc::new(encoded.to_vec());
+        Ok(torrent)
+    }
+
+    // the metainfo exactly as it was read, re-encoded if it wasn't
+    pub fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self.encoded.is_empty() {
+            true => Cow::Owned(serde_bencode::to_bytes(self).expect("encode error")),
+            false => Cow::Borrowed(&self.encoded),
+        }
+    }
+
+    // What the torrent goes by with trackers and peers, v2 and hybrid
+    // torrents use their SHA-256 info hash cut down to 20 bytes.
+    pub fn get_info_hash(&self) -> [u8; 20] {
+        match self.info_hash_v2() {
+            Some(hash) => hash[..20].try_into().expect("20 bytes"),
+            None => self.info_hash_v1(),
+        }
+    }
+
+    // Every swarm the torrent is part of, the one from `get_info_hash`
+    // first. A hybrid is a v1 torrent too, and v1-only peers know it by
+    // its SHA-1 hash (BEP 52).
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.get_info_hash()];
+        if self.info.is_v1() && self.info.is_v2() {
+            hashes.push(self.info_hash_v1());
+        }
+        hashes
+    }
+
+    pub fn info_hash_v1(&self) -> [u8; 20] {
+        sha1::Sha1::digest(self.encoded_info()).into()
+    }
+
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        self.info.is_v2().then(|| sha2::Sha256::digest(self.encoded_info()).into())
+    }
+
+    fn encoded_info(&self) -> Cow<'_, [u8]> {
+        match self.encoded.get(self.info_span.clone()) {
+            Some(info) if !info.is_empty() => Cow::Borrowed(info),
+            // Info only holds strings, integers and bytes, so this can't fail
+            _ => Cow::Owned(serde_bencode::to_bytes(&self.info).expect("encode error")),
+        }
+    }
+}
+
+// where the value of the top level "info" key is in a metainfo file
+fn info_span(encoded: &[u8]) -> Option<Range<usize>> {
+    if encoded.first() != Some(&b'd') {
+        return None;
+    }
+    let mut at = 1;
+    while encoded.get(at)? != &b'e' {
+        let value = skip_value(encoded, at)?;
+        let key = &encoded[encoded[at..value].iter().position(|&b| b == b':')? + at + 1..value];
+        let end = skip_value(encoded, value)?;
+        if key == b"info" {
+            return Some(value..end);
+        }
+        at = end;
+    }
+    None
+}
+
+// the end of the bencoded value starting at `at`
+fn skip_value(encoded: &[u8], at: usize) -> Option<usize> {
+    match *encoded.get(at)? {
+        b'i' => Some(at + encoded[at..].iter().position(|&b| b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut at = at + 1;
+            while encoded.get(at)? != &b'e' {
+                at = skip_value(encoded, at)?;
+            }
+            Some(at + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = at + encoded[at..].iter().position(|&b| b == b':')?;
+            let len: usize = std::str::from_utf8(&encoded[at..colon]).ok()?.parse().ok()?;
+            let end = colon.checked_add(1 + len)?;
+            (end <= encoded.len()).then_some(end)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn string(s: &str) -> String {
+        format!("{}:{}", s.len(), s)
+    }
+
+    // A v1 multi-file torrent of `files`, slash separated paths and their
+    // lengths, with every piece hash zeroed.
+    pub(crate) fn multi_file(files: &[(&str, usize)], plen: usize) -> Torrent {
+        let total: usize = files.iter().map(|(_, len)| len).sum();
+        let mut encoded = String::from("d8:announce0:4:infod5:filesl");
+        for (path, len) in files {
+            let path: String = path.split('/').map(string).collect();
+            encoded.push_str(&format!("d6:lengthi{}e4:pathl{}ee", len, path));
+        }
+        let pieces = "\0".repeat(20 * total.div_ceil(plen));
+        encoded.push_str(&format!("e4:name5:multi12:piece lengthi{}e6:pieces{}:{}ee", plen, pieces.len(), pieces));
+        Torrent::from_bytes(encoded.as_bytes()).unwrap()
+    }
+
+    // A v1 single-file torrent of `data`, hashed so it can be verified.
+    pub(crate) fn single_file(name: &str, data: &[u8], plen: usize) -> Torrent {
+        let pieces: Vec<u8> = data.chunks(plen).flat_map(sha1::Sha1::digest).collect();
+        let mut encoded = format!(
+            "d8:announce0:4:infod6:lengthi{}e4:name{}12:piece lengthi{}e6:pieces{}:",
+            data.len(),
+            string(name),
+            plen,
+            pieces.len()
+        )
+        .into_bytes();
+        encoded.extend_from_slice(&pieces);
+        encoded.extend_from_slice(b"ee");
+        Torrent::from_bytes(&encoded).unwrap()
+    }
+
+    const HYBRID: &[u8] = include_bytes!("../tests/fixtures/hybrid.torrent");
+    const V2: &[u8] = include_bytes!("../tests/fixtures/v2.torrent");
+
+    #[test]
+    fn known_info_hashes() {
+        let hybrid = Torrent::from_bytes(HYBRID).unwrap();
+        assert_eq!(hex::encode(hybrid.info_hash_v1()), "1b04e99ccc5b10b64415d6beb31c50ef805d0156");
+        let v2 = hybrid.info_hash_v2().unwrap();
+        assert_eq!(hex::encode(v2), "7b1ac6d139fc1a62821fe735a1aa78a97c9d1e6a1797b5eea65bb0638bf6cc62");
+        assert_eq!(hybrid.get_info_hash(), v2[..20]);
+        assert_eq!(hybrid.info_hashes(), [hybrid.get_info_hash(), hybrid.info_hash_v1()]);
+
+        let v2 = Torrent::from_bytes(V2).unwrap();
+        assert_eq!(
+            hex::encode(v2.info_hash_v2().unwrap()),
+            "a24f8435f6110f8649c295b715b629aa084ed69a4772bb5e9b38e8a9f4312da9"
+        );
+        assert_eq!(v2.info_hashes(), [v2.get_info_hash()]);
+    }
+
+    // file trees are untagged enums, bencode has to come back out exactly
+    // as it went in
+    #[test]
+    fn file_tree_round_trips() {
+        for encoded in [HYBRID, V2] {
+            let torrent = Torrent::from_bytes(encoded).unwrap();
+            assert_eq!(serde_bencode::to_bytes(&torrent).unwrap(), encoded);
+        }
+        let torrent = Torrent::from_bytes(HYBRID).unwrap();
+        let tree = torrent.info.file_tree.as_ref().unwrap();
+        assert!(matches!(tree["a.txt"], TreeNode::File { .. }));
+        let TreeNode::Dir(dir) = &tree["dir"] else { panic!("dir isn't a directory") };
+        let TreeNode::File { file } = &dir["b.bin"] else { panic!("b.bin isn't a file") };
+        assert_eq!(file.length, 70000);
+        assert_eq!(file.pieces_root.as_ref().map(|r| r.len()), Some(32));
+    }
+
+    #[test]
+    fn hybrid_files_skip_padding() {
+        let torrent = Torrent::from_bytes(HYBRID).unwrap();
+        let files = torrent.info.files().unwrap();
+        let layout: Vec<_> = files.iter().map(|f| (display_path(&f.path), f.offset, f.length)).collect();
+        assert_eq!(
+            layout,
+            [
+                ("a.txt".to_owned(), 0, 50000),
+                ("dir/b.bin".to_owned(), 65536, 70000),
+                ("z.txt".to_owned(), 163840, 20000)
+            ]
         );
+        assert!(files.iter().all(|f| f.pieces_root.is_some()));
+        // padding is part of the piece
+        assert_eq!(torrent.info.piece_len(1), 32768);
+    }
+
+    // without v1 padding each file's last piece is cut short
+    #[test]
+    fn v2_only_pieces_end_with_their_file() {
+        let mut info = Torrent::from_bytes(HYBRID).unwrap().info;
+        info.pieces.clear();
+        info.files = None;
+        assert_eq!(info.length(), 183840);
+        assert_eq!(info.piece_count(), 6);
+        let lens: Vec<_> = (0..6).map(|i| info.piece_len(i)).collect();
+        assert_eq!(lens, [32768, 17232, 32768, 32768, 4464, 20000]);
+        let offsets: Vec<_> = info.files().unwrap().iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, [0, 65536, 163840]);
+
+        let v2 = Torrent::from_bytes(V2).unwrap().info;
+        assert!(!v2.is_multi_file());
+        assert_eq!(v2.piece_count(), 4);
+        assert_eq!(v2.piece_len(3), 100000 - 3 * 32768);
+    }
+
+    // Keys we don't know about still go into the info hash, and the
+    // metainfo is handed back untouched.
+    #[test]
+    fn info_hash_is_of_the_bytes_as_read() {
+        let info = b"d5:filesld6:lengthi3e6:md5sum32:0123456789abcdef0123456789abcdef4:pathl1:aee\
+            d4:attr1:l6:lengthi0e4:pathl1:be12:symlink pathl1:aeee\
+            4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:fooe";
+        let mut encoded = b"d8:announce3:url7:comment2:hi4:info".to_vec();
+        encoded.extend_from_slice(info);
+        encoded.extend_from_slice(b"4:zzzzi1ee");
+
+        let torrent = Torrent::from_bytes(&encoded).unwrap();
+        assert_eq!(torrent.info_hash_v1(), <[u8; 20]>::from(sha1::Sha1::digest(info)));
+        assert_ne!(serde_bencode::to_bytes(&torrent.info).unwrap(), info);
+        assert_eq!(torrent.to_bytes(), encoded.as_slice());
+        assert_eq!(Torrent::from_bytes(&torrent.to_bytes()).unwrap().get_info_hash(), torrent.get_info_hash());
+    }
+
+    // BEP 27: the flag is inside the info dictionary so a private torrent
+    // is a different swarm, and it has to survive being encoded again
+    #[test]
+    fn private_key_is_part_of_the_info_hash() {
+        let public = b"d6:lengthi3e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let private = b"d6:lengthi3e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+        let torrent = |info: &[u8]| {
+            let mut encoded = b"d8:announce3:url4:info".to_vec();
+            encoded.extend_from_slice(info);
+            encoded.push(b'e');
+            Torrent::from_bytes(&encoded).unwrap()
+        };
+        let (public_torrent, private_torrent) = (torrent(public), torrent(private));
+        assert!(!public_torrent.info.is_private());
+        assert!(private_torrent.info.is_private());
+        assert_eq!(private_torrent.info_hash_v1(), <[u8; 20]>::from(sha1::Sha1::digest(private)));
+        assert_ne!(private_torrent.get_info_hash(), public_torrent.get_info_hash());
+        assert_eq!(serde_bencode::to_bytes(&private_torrent.info).unwrap(), private);
+    }
+
+    #[test]
+    fn info_span_rejects_truncated_metainfo() {
+        assert_eq!(info_span(b"d4:infod1:ai1eee"), Some(7..15));
+        assert_eq!(info_span(b"d4:infod1:ai1e"), None);
+        assert_eq!(info_span(b"d4:infod1:a9:xe"), None);
+        assert_eq!(info_span(b"d3:fooi1ee"), None);
+        assert_eq!(info_span(b"l4:infoe"), None);
+    }
+
+    #[test]
+    fn safe_path_only_takes_plain_names() {
+        let path = |parts: &[&str]| safe_path(&parts.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        assert_eq!(path(&["dir", "a.txt"]).unwrap(), Path::new("dir/a.txt"));
+        assert!(path(&[]).is_err());
+        assert!(path(&["..", "a.txt"]).is_err());
+        assert!(path(&["dir", ".."]).is_err());
+        assert!(path(&["/etc", "passwd"]).is_err());
+        assert!(path(&["dir/a.txt"]).is_err());
+        assert!(path(&["."]).is_err());
+        assert!(path(&[""]).is_err());
+    }
 
-        let res = reqwest::get(url).await.expect("failed to get tracker response");
-        //eprintln!("resp: {:?}", res);
-        let body = res.bytes().await.ok().expect("failed to get body"); 
-        //eprintln!("body: {:?}", body);
-        let t : Tracker = serde_bencode::from_bytes(&body).expect("failed to decode");
-        t
+    #[test]
+    fn unsafe_paths_fail_the_whole_torrent() {
+        let torrent = multi_file(&[("a", 10), ("../b", 10)], 16);
+        assert!(torrent.info.files().is_err());
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn file_pieces_cover_partial_pieces_at_both_ends() {
+        let file = |offset, length| File {
+            path: PathBuf::new(),
+            offset,
+            length,
+            pieces_root: None,
+        };
+        assert_eq!(file(0, 16).pieces(16), 0..1);
+        assert_eq!(file(0, 17).pieces(16), 0..2);
+        assert_eq!(file(15, 2).pieces(16), 0..2);
+        assert_eq!(file(16, 16).pieces(16), 1..2);
+        assert_eq!(file(20, 4).pieces(16), 1..2);
+        assert_eq!(file(20, 0).pieces(16), 0..0);
+    }
+
+    #[test]
+    fn multi_file_offsets_run_on() {
+        let torrent = multi_file(&[("a", 10), ("dir/b", 30), ("c", 0), ("d", 5)], 16);
+        let files = torrent.info.files().unwrap();
+        let layout: Vec<_> = files.iter().map(|f| (display_path(&f.path), f.offset, f.length)).collect();
+        assert_eq!(
+            layout,
+            [
+                ("a".to_owned(), 0, 10),
+                ("dir/b".to_owned(), 10, 30),
+                ("c".to_owned(), 40, 0),
+                ("d".to_owned(), 40, 5)
+            ]
+        );
+        assert_eq!(torrent.info.piece_count(), 3);
+        assert_eq!(torrent.info.piece_len(2), 13);
+    }
+
+    #[test]
+    fn url_list_is_one_url_or_many() {
+        let with = |url_list: &str| {
+            let encoded = format!("d8:announce0:4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:e8:url-list{}e", url_list);
+            Torrent::from_bytes(encoded.as_bytes()).unwrap().url_list
+        };
+        assert_eq!(with("8:http://a"), ["http://a"]);
+        assert_eq!(with("l8:http://a8:http://be"), ["http://a", "http://b"]);
+        // an empty url stands for none
+        assert!(with("0:").is_empty());
+        assert_eq!(with("l0:8:http://be"), ["http://b"]);
+        assert!(with("le").is_empty());
+        assert!(multi_file(&[("a", 1)], 1).url_list.is_empty());
+    }
+}
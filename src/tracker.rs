@@ -0,0 +1,155 @@
+use serde::{Serialize, Deserialize};
+
+use crate::error::{Error, Result};
+use crate::peer_id::PeerId;
+use crate::torrent::Torrent;
+
+
+#[derive(Serialize, Deserialize,Debug)]
+pub struct Peer {
+    pub ip: String,
+    // only known when the tracker sent the full peer list
+    pub peer_id: Option<[u8; 20]>,
+}
+
+impl Peer {
+    pub fn new(ip_and_port: &[u8; 6]) -> Peer {
+        let mut port = (ip_and_port[4] as u16) << 8;
+        port += ip_and_port[5] as u16;
+        let ip = format!("{}.{}.{}.{}:{}", ip_and_port[0], ip_and_port[1], ip_and_port[2], ip_and_port[3], port);
+        Peer { ip, peer_id: None }
+    }
+}
+
+#[derive(Serialize, Deserialize,Debug)]
+pub struct PeerEntry {
+    pub ip: String,
+    pub port: u16,
+    #[serde(rename = "peer id", default, with = "serde_bytes")]
+    pub peer_id: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize,Debug)]
+#[serde(untagged)]
+pub enum Peers {
+    Compact(#[serde(with = "serde_bytes")] Vec<u8>),
+    // trackers that ignore compact=1 send a list of dictionaries
+    Full(Vec<PeerEntry>),
+}
+
+
+#[derive(Serialize, Deserialize,Debug)]
+pub struct Tracker {
+    pub complete: u32,
+    pub incomplete: u32,
+    pub interval: u32,
+    #[serde(rename = "min interval")]
+    pub min_interval: u32,
+    pub peers: Peers
+}
+
+
+
+impl IntoIterator for Tracker {
+    type Item = Peer;
+    type IntoIter = PeerIterator;
+
+    fn into_iter(self) -> Self::IntoIter {
+        PeerIterator {
+            peers: self.peers,
+            index: 0
+        }
+    }
+}
+
+pub struct PeerIterator {
+    peers: Peers,
+    index: usize
+}
+
+impl Iterator for PeerIterator {
+    type Item = Peer;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let peer = match &self.peers {
+            Peers::Compact(peers) => {
+                let ip_and_port = peers.get(self.index..self.index + 6)?;
+                Peer::new(ip_and_port.try_into().ok()?)
+            }
+            Peers::Full(peers) => {
+                let entry = peers.get(self.index)?;
+                Peer {
+                    ip: format!("{}:{}", entry.ip, entry.port),
+                    peer_id: entry.peer_id.as_deref().and_then(|id| id.try_into().ok()),
+                }
+            }
+        };
+        self.index += match self.peers {
+            Peers::Compact(_) => 6,
+            Peers::Full(_) => 1,
+        };
+        Some(peer)
+    }
+}
+
+
+fn urlencode(t: &[u8]) -> String {
+    let mut encoded = String::new();
+    for &byte in t {
+        encoded.push('%');
+        encoded.push_str(&hex::encode([byte]));
+    }
+    encoded
+}
+
+
+// what a tracker sends instead of a peer list when it refuses us
+#[derive(Deserialize)]
+struct Failure {
+    #[serde(rename = "failure reason")]
+    reason: String,
+}
+
+#[tracing::instrument(name = "announce", skip_all, fields(tracker = %torrent.announce, left = left))]
+pub async fn request_tracker(torrent: &Torrent, peer_id: &PeerId, port: u16, left: usize) -> Result<Tracker> {
+    request_tracker_as(torrent, &torrent.get_info_hash(), peer_id, port, (0, 0), left).await
+}
+
+// Announces under one particular info hash, hybrids have two.
+// `transferred` is what we've uploaded and downloaded so far, in bytes.
+pub async fn request_tracker_as(
+    torrent: &Torrent,
+    info_hash: &[u8; 20],
+    peer_id: &PeerId,
+    port: u16,
+    (uploaded, downloaded): (u64, u64),
+    left: usize,
+) -> Result<Tracker> {
+
+    let params = [
+        ("port".to_owned(), port.to_string()),
+        ("uploaded".to_owned(), uploaded.to_string()),
+        ("downloaded".to_owned(), downloaded.to_string()),
+        ("left".to_owned(), left.to_string()),
+        ("compact".to_owned(), "1".to_owned()),
+    ];
+
+    let params_encoded =
+        serde_urlencoded::to_string(params).map_err(|e| Error::Tracker(e.to_string()))?;
+    let url = format!(
+        "{}?{}&info_hash={}&peer_id={}",
+        torrent.announce,
+        params_encoded,
+        &urlencode(info_hash),
+        &urlencode(peer_id.as_bytes())
+    );
+
+    let res = reqwest::get(url).await?.error_for_status()?;
+    let body = res.bytes().await?;
+    if let Ok(failure) = serde_bencode::from_bytes::<Failure>(&body) {
+        return Err(Error::Tracker(failure.reason));
+    }
+    let t : Tracker = serde_bencode::from_bytes(&body)?;
+    tracing::debug!(interval = t.interval, "announced");
+    Ok(t)
+}
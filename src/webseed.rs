@@ -0,0 +1,149 @@
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use tokio_util::bytes::Bytes;
+use tracing::debug;
+
+use crate::bandwidth::Bandwidth;
+use crate::connection::Timeouts;
+use crate::error::{Error, Result};
+use crate::stats::{PeerStats, Stats};
+use crate::torrent::{File, Info};
+
+// whether we know how to fetch from a url-list entry, ftp seeds are
+// left alone
+pub fn is_supported(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+// A BEP 19 web seed: an HTTP server with the torrent's files laid out as
+// they'd be on disk. Pieces are put together from Range requests, one per
+// file the piece has data in.
+pub struct WebSeed {
+    url: String,
+    client: reqwest::Client,
+    timeouts: Timeouts,
+    bandwidth: Bandwidth,
+    stats: PeerStats,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, timeouts: Timeouts, bandwidth: Bandwidth, stats: &Stats) -> Result<Self> {
+        let client = reqwest::Client::builder().connect_timeout(timeouts.connect).build()?;
+        // a server never chokes us
+        let mut stats = stats.peer();
+        stats.set_choked(false);
+        Ok(Self {
+            url: url.to_owned(),
+            client,
+            timeouts,
+            bandwidth,
+            stats,
+        })
+    }
+
+    // Where a file is on the server. A url ending in a slash is a
+    // directory the torrent's name goes under, and a multi-file torrent's
+    // files are always in a directory named after it.
+    fn file_url(&self, info: &Info, file: &File) -> String {
+        let mut url = self.url.clone();
+        if !info.is_multi_file() && !url.ends_with('/') {
+            return url;
+        }
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        url.push_str(&encode_segment(&info.name));
+        if info.is_multi_file() {
+            for component in file.path.iter() {
+                url.push('/');
+                url.push_str(&encode_segment(&component.to_string_lossy()));
+            }
+        }
+        url
+    }
+
+    // A piece's data as it came off the wire, unverified. Padding between
+    // files isn't on the server, it's filled in with zeros.
+    pub async fn fetch_piece(&mut self, info: &Info, files: &[File], index: usize) -> Result<Vec<Bytes>> {
+        let start = index * info.plen;
+        let end = start + info.piece_len(index);
+        let mut blocks = Vec::new();
+        self.stats.piece_started();
+        let result = async {
+            let mut at = start;
+            for file in files.iter().filter(|f| f.offset < end && f.end() > start) {
+                if file.offset > at {
+                    blocks.push(Bytes::from(vec![0; file.offset - at]));
+                }
+                let from = start.max(file.offset) - file.offset;
+                let to = end.min(file.end()) - file.offset;
+                let url = self.file_url(info, file);
+                self.fetch_range(&url, from, to, &mut blocks).await?;
+                at = file.offset + to;
+            }
+            if end > at {
+                blocks.push(Bytes::from(vec![0; end - at]));
+            }
+            Ok(())
+        }
+        .await;
+        self.stats.piece_finished();
+        result.map(|()| blocks)
+    }
+
+    // bytes `from..to` of the file at `url`
+    async fn fetch_range(&self, url: &str, from: usize, to: usize, blocks: &mut Vec<Bytes>) -> Result<()> {
+        debug!(%url, from, to, "range request");
+        let request = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", from, to - 1))
+            .send();
+        let mut response = tokio::time::timeout(self.timeouts.request, request)
+            .await
+            .map_err(|_| Error::Timeout("web seed response"))??
+            .error_for_status()?;
+        // a server that ignores Range sends the whole file, which is only
+        // any good if that's what we asked for
+        let whole_file = from == 0 && response.content_length() == Some(to as u64);
+        if response.status() != StatusCode::PARTIAL_CONTENT && !whole_file {
+            return Err(Error::Protocol(format!(
+                "web seed answered a range request with {}",
+                response.status()
+            )));
+        }
+
+        let want = to - from;
+        let mut received = 0;
+        loop {
+            self.bandwidth.download.wait().await;
+            let chunk = tokio::time::timeout(self.timeouts.request, response.chunk())
+                .await
+                .map_err(|_| Error::Timeout("web seed data"))??;
+            let Some(chunk) = chunk else { break };
+            self.bandwidth.download.consume(chunk.len());
+            self.stats.downloaded(chunk.len());
+            received += chunk.len();
+            if received > want {
+                return Err(Error::Protocol(format!("web seed sent more than the {} bytes asked for", want)));
+            }
+            blocks.push(chunk);
+        }
+        if received < want {
+            return Err(Error::Protocol(format!("web seed sent {} of {} bytes", received, want)));
+        }
+        Ok(())
+    }
+}
+
+// percent-encodes everything but RFC 3986's unreserved characters
+fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::new();
+    for &byte in segment.as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
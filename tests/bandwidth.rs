@@ -0,0 +1,130 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::connection::{PeerConnection, Timeouts};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_protocol::Handshake;
+use bittorrent_starter_rust::{Session, SessionConfig};
+
+use common::Behavior;
+
+const LEN: usize = 192 * 1024;
+const PLEN: usize = 32 * 1024;
+const RATE: u64 = 64 * 1024;
+
+// A request timeout under the time a single block takes at RATE, waiting
+// on the limiter mustn't look like the peer stalling.
+fn config() -> SessionConfig {
+    SessionConfig {
+        port: 0,
+        timeouts: Timeouts {
+            request: Duration::from_millis(150),
+            ..Timeouts::default()
+        },
+        ..SessionConfig::default()
+    }
+}
+
+// how long a whole download from a local peer takes once `limit` has
+// been applied to the new session and torrent
+async fn timed_download(config: SessionConfig, limit: impl Fn(&Session, &bittorrent_starter_rust::TorrentHandle)) -> Duration {
+    let data = common::data(LEN);
+    let probe = common::torrent("data.bin", &data, 32 * 1024, "http://127.0.0.1:1/announce");
+    let peer = common::peer(&probe, data.clone(), Behavior::Serve).await;
+    let announce = common::tracker(vec![peer]).await;
+    let torrent = common::torrent("data.bin", &data, 32 * 1024, &announce);
+    assert_eq!(torrent.get_info_hash(), probe.get_info_hash(), "announce isn't part of the info hash");
+
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(config).await.unwrap();
+    let handle = session.add_torrent(torrent, dir.path().join("data.bin")).await.unwrap();
+    limit(&session, &handle);
+
+    let started = Instant::now();
+    handle.start();
+    tokio::time::timeout(Duration::from_secs(20), handle.wait_complete())
+        .await
+        .expect("download finished")
+        .unwrap();
+    let elapsed = started.elapsed();
+    assert_eq!(std::fs::read(dir.path().join("data.bin")).unwrap(), data);
+    elapsed
+}
+
+// how long downloading all but the first piece from a new session
+// seeding the data takes, once `limit` has been applied. The first piece
+// is left out so waiting to be unchoked doesn't count.
+async fn timed_upload(config: SessionConfig, limit: impl Fn(&Session, &bittorrent_starter_rust::TorrentHandle)) -> Duration {
+    let data = common::data(LEN + PLEN);
+    let torrent = common::torrent("data.bin", &data, PLEN, "http://127.0.0.1:1/announce");
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("data.bin"), &data).unwrap();
+    let session = Session::new(config).await.unwrap();
+    let handle = session.add_torrent(torrent.clone(), dir.path().join("data.bin")).await.unwrap();
+    limit(&session, &handle);
+    handle.start();
+
+    let addr = format!("127.0.0.1:{}", session.port());
+    let handshake = Handshake::new(torrent.get_info_hash(), &PeerId::generate());
+    let (mut conn, _) = PeerConnection::connect(&addr, &handshake, torrent.info.piece_count(), Timeouts::default())
+        .await
+        .unwrap();
+    let download = async {
+        conn.download_piece(&torrent.info, 0).await.unwrap();
+        let started = Instant::now();
+        for index in 1..torrent.info.piece_count() {
+            let blocks = conn.download_piece(&torrent.info, index).await.unwrap();
+            assert_eq!(blocks.concat(), data[index * PLEN..(index + 1) * PLEN]);
+        }
+        started.elapsed()
+    };
+    tokio::time::timeout(Duration::from_secs(20), download).await.expect("upload finished")
+}
+
+fn assert_near_rate(elapsed: Duration) {
+    let expected = LEN as f64 / RATE as f64;
+    let actual = elapsed.as_secs_f64();
+    assert!(
+        actual > expected * 0.7 && actual < expected * 1.5,
+        "took {:.2}s, {:.2}s expected at the limit",
+        actual,
+        expected
+    );
+}
+
+#[tokio::test]
+async fn session_limit_holds_download_to_its_rate() {
+    let config = SessionConfig {
+        max_download_rate: Some(RATE),
+        ..config()
+    };
+    assert_near_rate(timed_download(config, |_, _| {}).await);
+}
+
+#[tokio::test]
+async fn torrent_limit_holds_download_to_its_rate() {
+    let elapsed = timed_download(config(), |_, handle| handle.bandwidth().download.set_rate(Some(RATE))).await;
+    assert_near_rate(elapsed);
+}
+
+#[tokio::test]
+async fn unlimited_download_is_quick() {
+    let elapsed = timed_download(config(), |_, _| {}).await;
+    assert!(elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+}
+
+#[tokio::test]
+async fn session_limit_holds_upload_to_its_rate() {
+    let config = SessionConfig {
+        max_upload_rate: Some(RATE),
+        ..config()
+    };
+    assert_near_rate(timed_upload(config, |_, _| {}).await);
+}
+
+#[tokio::test]
+async fn torrent_limit_holds_upload_to_its_rate() {
+    let elapsed = timed_upload(config(), |_, handle| handle.bandwidth().upload.set_rate(Some(RATE))).await;
+    assert_near_rate(elapsed);
+}
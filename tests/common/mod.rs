@@ -0,0 +1,233 @@
+// Loopback stand-ins for the rest of a swarm: a tracker that hands out a
+// fixed peer list and peers that serve a torrent's data, or misbehave in
+// ways the tests want to see handled.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_protocol::{Handshake, PeerMessage, PeerMessageCodec};
+use bittorrent_starter_rust::torrent::Torrent;
+use futures::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::Framed;
+
+// deterministic but not repetitive, so a block in the wrong place fails
+// the hash check
+pub fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+pub fn torrent(name: &str, data: &[u8], plen: usize, announce: &str) -> Torrent {
+    encode_torrent(name, data, plen, announce, false)
+}
+
+// Same as torrent, with the BEP 27 private flag set.
+pub fn private_torrent(name: &str, data: &[u8], plen: usize, announce: &str) -> Torrent {
+    encode_torrent(name, data, plen, announce, true)
+}
+
+fn encode_torrent(name: &str, data: &[u8], plen: usize, announce: &str, private: bool) -> Torrent {
+    let pieces: Vec<u8> = data.chunks(plen).flat_map(Sha1::digest).collect();
+    let mut encoded = format!(
+        "d8:announce{}:{}4:infod6:lengthi{}e4:name{}:{}12:piece lengthi{}e6:pieces{}:",
+        announce.len(),
+        announce,
+        data.len(),
+        name.len(),
+        name,
+        plen,
+        pieces.len()
+    )
+    .into_bytes();
+    encoded.extend_from_slice(&pieces);
+    if private {
+        encoded.extend_from_slice(b"7:privatei1e");
+    }
+    encoded.extend_from_slice(b"ee");
+    Torrent::from_bytes(&encoded).expect("valid torrent")
+}
+
+// A multi-file torrent named "multi" with `data` split between `files`,
+// slash separated paths and their lengths.
+pub fn multi_torrent(files: &[(&str, usize)], data: &[u8], plen: usize, announce: &str) -> Torrent {
+    assert_eq!(files.iter().map(|(_, len)| len).sum::<usize>(), data.len());
+    let pieces: Vec<u8> = data.chunks(plen).flat_map(Sha1::digest).collect();
+    let mut encoded = format!("d8:announce{}:{}4:infod5:filesl", announce.len(), announce);
+    for (path, len) in files {
+        let path: String = path.split('/').map(|c| format!("{}:{}", c.len(), c)).collect();
+        encoded.push_str(&format!("d6:lengthi{}e4:pathl{}ee", len, path));
+    }
+    encoded.push_str(&format!("e4:name5:multi12:piece lengthi{}e6:pieces{}:", plen, pieces.len()));
+    let mut encoded = encoded.into_bytes();
+    encoded.extend_from_slice(&pieces);
+    encoded.extend_from_slice(b"ee");
+    Torrent::from_bytes(&encoded).expect("valid torrent")
+}
+
+// An HTTP tracker that answers every announce with `peers`. Returns its
+// announce url.
+pub async fn tracker(peers: Vec<SocketAddr>) -> String {
+    recording_tracker(peers).await.0
+}
+
+// Same as tracker, also handing back the query string of every announce
+// it gets.
+pub async fn recording_tracker(peers: Vec<SocketAddr>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let announces = Arc::new(Mutex::new(Vec::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut compact = Vec::new();
+    for peer in &peers {
+        let SocketAddr::V4(peer) = peer else { panic!("ipv4 only") };
+        compact.extend_from_slice(&peer.ip().octets());
+        compact.extend_from_slice(&peer.port().to_be_bytes());
+    }
+    let mut body = format!(
+        "d8:completei1e10:incompletei0e8:intervali60e12:min intervali60e5:peers{}:",
+        compact.len()
+    )
+    .into_bytes();
+    body.extend_from_slice(&compact);
+    body.push(b'e');
+
+    let recorded = announces.clone();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else { return };
+            let body = body.clone();
+            let recorded = recorded.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                if let Some(query) = request.split(' ').nth(1).and_then(|path| path.split_once('?')) {
+                    recorded.lock().unwrap().push(query.1.to_owned());
+                }
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            });
+        }
+    });
+    (format!("http://{}/announce", addr), announces)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behavior {
+    // answers every request
+    Serve,
+    // unchokes and keeps saying it has pieces, but never sends a block
+    Stall,
+    // chokes the first request and drops everything asked for so far,
+    // then unchokes and serves
+    ChokeOnce,
+    // sends a Have for every piece but the last instead of a bitfield
+    HaveOnly,
+    // never says what it has, answers every request all the same
+    NoBitfield,
+}
+
+// A peer on a local port that has all of `data` and behaves as told.
+pub async fn peer(torrent: &Torrent, data: Vec<u8>, behavior: Behavior) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent.get_info_hash();
+    let plen = torrent.info.plen;
+    let piece_count = torrent.info.piece_count();
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { return };
+            let data = data.clone();
+            tokio::spawn(serve(stream, info_hash, data, plen, piece_count, behavior));
+        }
+    });
+    addr
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    info_hash: [u8; 20],
+    data: Vec<u8>,
+    plen: usize,
+    piece_count: usize,
+    behavior: Behavior,
+) {
+    let Ok(theirs) = Handshake::read(&mut stream).await else { return };
+    assert_eq!(theirs.info_hash(), &info_hash);
+    if Handshake::new(info_hash, &PeerId::generate()).write(&mut stream).await.is_err() {
+        return;
+    }
+    let mut framed = Framed::new(stream, PeerMessageCodec);
+    let announced = match behavior {
+        Behavior::HaveOnly => {
+            let mut sent = true;
+            for i in 0..piece_count - 1 {
+                sent &= framed.send(PeerMessage::Have(i as u32)).await.is_ok();
+            }
+            sent
+        }
+        Behavior::NoBitfield => true,
+        _ => {
+            let mut bitfield = vec![0; piece_count.div_ceil(8)];
+            for i in 0..piece_count {
+                bitfield[i / 8] |= 0x80 >> (i % 8);
+            }
+            framed.send(PeerMessage::Bitfield(bitfield.into())).await.is_ok()
+        }
+    };
+    if !announced || framed.send(PeerMessage::Unchoke).await.is_err() {
+        return;
+    }
+
+    let mut chatter = tokio::time::interval(Duration::from_millis(100));
+    let mut choked = false;
+    loop {
+        let msg = tokio::select! {
+            msg = framed.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => return,
+            },
+            _ = chatter.tick(), if behavior == Behavior::Stall => {
+                // never quiet, never useful
+                if framed.send(PeerMessage::Have(0)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        if behavior == Behavior::ChokeOnce && !choked && matches!(msg, PeerMessage::Request { .. }) {
+            // a choke lets us forget whatever was asked for before it
+            choked = true;
+            if framed.send(PeerMessage::Choke).await.is_err() {
+                return;
+            }
+            let _ = tokio::time::timeout(Duration::from_millis(100), async {
+                while framed.next().await.is_some() {}
+            })
+            .await;
+            if framed.send(PeerMessage::Unchoke).await.is_err() {
+                return;
+            }
+            continue;
+        }
+        if let (PeerMessage::Request { index, begin, length }, false) = (msg, behavior == Behavior::Stall) {
+            let start = index as usize * plen + begin as usize;
+            let block = Bytes::copy_from_slice(&data[start..start + length as usize]);
+            if framed.send(PeerMessage::Piece { index, begin, block }).await.is_err() {
+                return;
+            }
+        }
+    }
+}
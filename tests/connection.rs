@@ -0,0 +1,74 @@
+mod common;
+
+use std::time::Duration;
+
+use bittorrent_starter_rust::connection::{PeerConnection, Timeouts};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_protocol::Handshake;
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::Error;
+
+use common::Behavior;
+
+const PLEN: usize = 32 * 1024;
+
+async fn connect(behavior: Behavior) -> (PeerConnection, Torrent, Vec<u8>) {
+    let data = common::data(4 * PLEN);
+    let torrent = common::torrent("data.bin", &data, PLEN, "http://127.0.0.1:1/announce");
+    let addr = common::peer(&torrent, data.clone(), behavior).await;
+    // short enough that a request left hanging fails the test quickly
+    let timeouts = Timeouts {
+        request: Duration::from_secs(2),
+        ..Timeouts::default()
+    };
+    let handshake = Handshake::new(torrent.get_info_hash(), &PeerId::generate());
+    let (conn, _) = PeerConnection::connect(&addr.to_string(), &handshake, torrent.info.piece_count(), timeouts)
+        .await
+        .unwrap();
+    (conn, torrent, data)
+}
+
+async fn download(conn: &mut PeerConnection, torrent: &Torrent, index: usize) -> Result<Vec<u8>, Error> {
+    let blocks = tokio::time::timeout(Duration::from_secs(5), conn.download_piece(&torrent.info, index))
+        .await
+        .expect("piece finished")?;
+    Ok(blocks.concat())
+}
+
+// the requests a choke threw away are asked for again once we're
+// unchoked, nothing is left waiting on a block that will never come
+#[tokio::test]
+async fn choked_requests_are_requeued() {
+    let (mut conn, torrent, data) = connect(Behavior::ChokeOnce).await;
+    for index in 0..torrent.info.piece_count() {
+        let piece = download(&mut conn, &torrent, index).await.unwrap();
+        assert_eq!(piece, data[index * PLEN..(index + 1) * PLEN]);
+    }
+}
+
+// haves alone are enough to know what a peer has, and what it doesn't
+#[tokio::test]
+async fn haves_without_a_bitfield() {
+    let (mut conn, torrent, data) = connect(Behavior::HaveOnly).await;
+    let last = torrent.info.piece_count() - 1;
+    for index in 0..last {
+        let piece = download(&mut conn, &torrent, index).await.unwrap();
+        assert_eq!(piece, data[index * PLEN..(index + 1) * PLEN]);
+    }
+    assert_eq!(conn.has_piece(0), Some(true));
+    assert_eq!(conn.has_piece(last), Some(false));
+    let result = download(&mut conn, &torrent, last).await;
+    assert!(matches!(result, Err(Error::Protocol(_))), "{:?}", result.map(|p| p.len()));
+}
+
+// a peer that never says what it has is assumed to have everything
+#[tokio::test]
+async fn no_bitfield_at_all() {
+    let (mut conn, torrent, data) = connect(Behavior::NoBitfield).await;
+    assert_eq!(conn.has_piece(0), None);
+    for index in 0..torrent.info.piece_count() {
+        let piece = download(&mut conn, &torrent, index).await.unwrap();
+        assert_eq!(piece, data[index * PLEN..(index + 1) * PLEN]);
+    }
+    assert_eq!(conn.has_piece(0), None);
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_protocol::Handshake;
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::{Session, SessionConfig};
+use tokio::net::TcpStream;
+
+const HYBRID: &[u8] = include_bytes!("fixtures/hybrid.torrent");
+
+// what tests/fixtures/hybrid.torrent was made from, file by file
+fn data(file: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 + file * 7 + i / 997) as u8).collect()
+}
+
+// A seeding hybrid has to answer peers from both of its swarms, each
+// under the info hash they asked for.
+#[tokio::test]
+async fn hybrid_is_seeded_under_both_info_hashes() {
+    let torrent = Torrent::from_bytes(HYBRID).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    for (k, file) in torrent.info.files().unwrap().iter().enumerate() {
+        let path = dir.path().join(&file.path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data(k, file.length)).unwrap();
+    }
+    let hashes = torrent.info_hashes();
+    assert_eq!(hashes, [torrent.get_info_hash(), torrent.info_hash_v1()]);
+
+    let session = Session::new(SessionConfig {
+        port: 0,
+        ..SessionConfig::default()
+    })
+    .await
+    .unwrap();
+    let handle = session.add_torrent(torrent, dir.path()).await.unwrap();
+    assert!(handle.progress().is_complete());
+    handle.start();
+
+    for info_hash in hashes {
+        let mut stream = TcpStream::connect(("127.0.0.1", session.port())).await.unwrap();
+        Handshake::new(info_hash, &PeerId::generate()).write(&mut stream).await.unwrap();
+        let theirs = tokio::time::timeout(Duration::from_secs(5), Handshake::read(&mut stream))
+            .await
+            .expect("handshake answered")
+            .unwrap();
+        assert_eq!(theirs.info_hash(), &info_hash);
+    }
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use std::time::Duration;
+
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_protocol::{Handshake, PeerMessage, PeerMessageCodec};
+use bittorrent_starter_rust::{Session, SessionConfig};
+use futures::StreamExt;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+use common::Behavior;
+
+const PLEN: usize = 32 * 1024;
+
+async fn session() -> Session {
+    Session::new(SessionConfig {
+        port: 0,
+        ..SessionConfig::default()
+    })
+    .await
+    .unwrap()
+}
+
+// A paused torrent doesn't leave incoming peers hanging, they are closed
+// instead of waiting in a queue nobody reads.
+#[tokio::test]
+async fn paused_torrent_closes_incoming_peers() {
+    let data = common::data(4 * PLEN);
+    let torrent = common::torrent("data.bin", &data, PLEN, "http://127.0.0.1:1/announce");
+    let info_hash = torrent.get_info_hash();
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("data.bin"), &data).unwrap();
+
+    let session = session().await;
+    let handle = session.add_torrent(torrent, dir.path().join("data.bin")).await.unwrap();
+    handle.pause();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", session.port())).await.unwrap();
+    Handshake::new(info_hash, &PeerId::generate()).write(&mut stream).await.unwrap();
+    let mut buf = [0; 68];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("connection closed")
+        .unwrap_or(0);
+    assert_eq!(n, 0, "paused torrent answered");
+}
+
+// Peers that connect while we are still downloading are told what we
+// have so far.
+#[tokio::test]
+async fn downloading_torrent_uploads_to_incoming_peers() {
+    let data = common::data(4 * PLEN);
+    let probe = common::torrent("data.bin", &data, PLEN, "http://127.0.0.1:1/announce");
+    let stalling = common::peer(&probe, data.clone(), Behavior::Stall).await;
+    let torrent = common::torrent("data.bin", &data, PLEN, &common::tracker(vec![stalling]).await);
+    let info_hash = torrent.get_info_hash();
+
+    // only the first piece is on disk, the stalling peer never sends the rest
+    let dir = tempfile::tempdir().unwrap();
+    let mut partial = vec![0; data.len()];
+    partial[..PLEN].copy_from_slice(&data[..PLEN]);
+    std::fs::write(dir.path().join("data.bin"), &partial).unwrap();
+
+    let session = session().await;
+    let handle = session.add_torrent(torrent, dir.path().join("data.bin")).await.unwrap();
+    assert!(!handle.progress().is_complete());
+    handle.start();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", session.port())).await.unwrap();
+    Handshake::new(info_hash, &PeerId::generate()).write(&mut stream).await.unwrap();
+    let theirs = tokio::time::timeout(Duration::from_secs(5), Handshake::read(&mut stream))
+        .await
+        .expect("handshake answered")
+        .unwrap();
+    assert_eq!(theirs.info_hash(), &info_hash);
+
+    let mut framed = Framed::new(stream, PeerMessageCodec);
+    let msg = tokio::time::timeout(Duration::from_secs(5), framed.next())
+        .await
+        .expect("bitfield sent")
+        .unwrap()
+        .unwrap();
+    match msg {
+        PeerMessage::Bitfield(bits) => assert_eq!(&bits[..], &[0b1000_0000]),
+        msg => panic!("expected a bitfield, got {:?}", msg),
+    }
+}
@@ -0,0 +1,146 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::connection::Timeouts;
+use bittorrent_starter_rust::lsd::LocalDiscovery;
+use bittorrent_starter_rust::{Error, Session, SessionConfig};
+
+// nothing listens here, so the tracker always fails
+const NO_TRACKER: &str = "http://127.0.0.1:1/announce";
+
+fn config(discovery: Duration) -> SessionConfig {
+    SessionConfig {
+        port: 0,
+        local_discovery: true,
+        timeouts: Timeouts {
+            discovery,
+            ..Timeouts::default()
+        },
+        ..SessionConfig::default()
+    }
+}
+
+// Two sessions on this machine find each other through the multicast
+// group alone, the tracker is never any help.
+#[tokio::test]
+async fn sessions_find_each_other_without_a_tracker() {
+    let data: Vec<u8> = common::data(100 * 1024).into_iter().rev().collect();
+    let seeding = tempfile::tempdir().unwrap();
+    std::fs::write(seeding.path().join("lsd.bin"), &data).unwrap();
+    let seeder = Session::new(config(Duration::from_secs(30))).await.unwrap();
+    let seed = seeder
+        .add_torrent(common::torrent("lsd.bin", &data, 16 * 1024, NO_TRACKER), seeding.path().join("lsd.bin"))
+        .await
+        .unwrap();
+    assert!(seed.progress().is_complete());
+    seed.start();
+
+    let downloading = tempfile::tempdir().unwrap();
+    let session = Session::new(config(Duration::from_secs(30))).await.unwrap();
+    let handle = session
+        .add_torrent(common::torrent("lsd.bin", &data, 16 * 1024, NO_TRACKER), downloading.path().join("lsd.bin"))
+        .await
+        .unwrap();
+    handle.start();
+    tokio::time::timeout(Duration::from_secs(20), handle.wait_complete())
+        .await
+        .expect("found the other session")
+        .unwrap();
+    assert_eq!(std::fs::read(downloading.path().join("lsd.bin")).unwrap(), data);
+}
+
+// with no one to find, the wait for local peers runs out
+#[tokio::test]
+async fn waiting_for_local_peers_times_out() {
+    let data = common::data(1000);
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(config(Duration::from_millis(500))).await.unwrap();
+    let handle = session
+        .add_torrent(common::torrent("alone.bin", &data, 16 * 1024, NO_TRACKER), dir.path().join("alone.bin"))
+        .await
+        .unwrap();
+    let started = Instant::now();
+    handle.start();
+    let result = tokio::time::timeout(Duration::from_secs(10), handle.wait_complete())
+        .await
+        .expect("gave up");
+    assert!(matches!(&result, Err(Error::Stopped(e)) if e.contains("local peers")), "{:?}", result);
+    assert!(started.elapsed() >= Duration::from_millis(500));
+}
+
+// A private torrent is never announced to the local network, a public
+// one running alongside it is, and without its tracker it gives up
+// rather than wait on local peers.
+#[tokio::test]
+async fn private_torrents_stay_off_the_local_network() {
+    let listening = LocalDiscovery::bind(1).unwrap();
+    let session = Session::new(config(Duration::from_secs(30))).await.unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let data = common::data(1000);
+    std::fs::write(dir.path().join("public.bin"), &data).unwrap();
+    std::fs::write(dir.path().join("private.bin"), &data).unwrap();
+    let public = session
+        .add_torrent(common::torrent("public.bin", &data, 16 * 1024, NO_TRACKER), dir.path().join("public.bin"))
+        .await
+        .unwrap();
+    let private = session
+        .add_torrent(common::private_torrent("private.bin", &data, 16 * 1024, NO_TRACKER), dir.path().join("private.bin"))
+        .await
+        .unwrap();
+    assert!(private.is_private() && !public.is_private());
+    private.start();
+    public.start();
+
+    let mut heard = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            heard.extend(listening.recv().await.unwrap().info_hashes);
+        }
+    })
+    .await;
+    assert!(heard.contains(public.info_hash()));
+    assert!(!heard.contains(private.info_hash()));
+
+    let missing = tempfile::tempdir().unwrap();
+    let downloading = session
+        .add_torrent(common::private_torrent("other.bin", &common::data(2000), 16 * 1024, NO_TRACKER), missing.path().join("other.bin"))
+        .await
+        .unwrap();
+    downloading.start();
+    let result = tokio::time::timeout(Duration::from_secs(10), downloading.wait_complete())
+        .await
+        .expect("gave up without waiting on local peers");
+    assert!(matches!(&result, Err(Error::Stopped(e)) if !e.contains("local peers")), "{:?}", result);
+}
+
+// A hybrid is announced under both its info hashes, and a peer that only
+// knows it by its v1 one still gets an answer.
+#[tokio::test]
+async fn hybrids_are_found_by_either_info_hash() {
+    let torrent = bittorrent_starter_rust::torrent::Torrent::from_bytes(include_bytes!("fixtures/hybrid.torrent")).unwrap();
+    let hashes = torrent.info_hashes();
+    let peer = LocalDiscovery::bind(1).unwrap();
+    let session = Session::new(config(Duration::from_secs(30))).await.unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let handle = session.add_torrent(torrent, dir.path()).await.unwrap();
+    handle.start();
+
+    // heard from the session with both hashes within `wait`
+    let heard_both = |wait| {
+        tokio::time::timeout(wait, async {
+            loop {
+                let announce = peer.recv().await.unwrap();
+                if hashes.iter().all(|h| announce.info_hashes.contains(h)) {
+                    return announce.peer.port();
+                }
+            }
+        })
+    };
+    assert_eq!(heard_both(Duration::from_secs(5)).await, Ok(session.port()));
+
+    // the next regular announce is minutes away, this one is an answer
+    peer.announce(&hashes[1..]).await.unwrap();
+    assert_eq!(heard_both(Duration::from_secs(5)).await, Ok(session.port()));
+}
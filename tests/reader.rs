@@ -0,0 +1,132 @@
+mod common;
+
+use std::io::SeekFrom;
+use std::time::Duration;
+
+use bittorrent_starter_rust::{DownloadMode, Session, SessionConfig};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use common::Behavior;
+
+const PLEN: usize = 16 * 1024;
+// b starts part way into the first piece and ends part way into the last
+const FILES: [(&str, usize); 3] = [("a", 10000), ("dir/b", 50000), ("c", 5536)];
+const B: std::ops::Range<usize> = 10000..60000;
+
+fn config() -> SessionConfig {
+    SessionConfig {
+        port: 0,
+        ..SessionConfig::default()
+    }
+}
+
+fn write_files(dir: &std::path::Path, data: &[u8]) {
+    let mut at = 0;
+    for (path, len) in FILES {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, &data[at..at + len]).unwrap();
+        at += len;
+    }
+}
+
+#[tokio::test]
+async fn reads_and_seeks_across_pieces() {
+    let data = common::data(65536);
+    let dir = tempfile::tempdir().unwrap();
+    write_files(dir.path(), &data);
+    let session = Session::new(config()).await.unwrap();
+    let torrent = common::multi_torrent(&FILES, &data, PLEN, "http://127.0.0.1:1/announce");
+    let handle = session.add_torrent(torrent, dir.path()).await.unwrap();
+    assert!(handle.progress().is_complete());
+
+    let mut reader = handle.reader(1).await.unwrap();
+    assert_eq!(reader.len(), 50000);
+    let mut all = Vec::new();
+    reader.read_to_end(&mut all).await.unwrap();
+    assert_eq!(all, data[B]);
+    assert_eq!(reader.position(), 50000);
+
+    // straddling the boundary between the second and third pieces
+    let at = 2 * PLEN - B.start - 10;
+    reader.seek(SeekFrom::Start(at as u64)).await.unwrap();
+    let mut buf = [0; 100];
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, data[B.start + at..B.start + at + 100]);
+
+    reader.seek(SeekFrom::Current(-50)).await.unwrap();
+    reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, data[B.start + at + 50..B.start + at + 150]);
+
+    reader.seek(SeekFrom::End(-5)).await.unwrap();
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail).await.unwrap();
+    assert_eq!(tail, data[B.end - 5..B.end]);
+
+    assert!(reader.seek(SeekFrom::Current(-60000)).await.is_err());
+    // past the end reads nothing
+    reader.seek(SeekFrom::Start(60000)).await.unwrap();
+    assert_eq!(reader.read(&mut buf).await.unwrap(), 0);
+}
+
+// reading ahead of the download waits for the pieces to come in
+#[tokio::test]
+async fn reads_wait_for_the_download() {
+    let data = common::data(65536);
+    let probe = common::multi_torrent(&FILES, &data, PLEN, "http://127.0.0.1:1/announce");
+    let peer = common::peer(&probe, data.clone(), Behavior::Serve).await;
+    let announce = common::tracker(vec![peer]).await;
+    let torrent = common::multi_torrent(&FILES, &data, PLEN, &announce);
+
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(config()).await.unwrap();
+    let handle = session.add_torrent(torrent, dir.path()).await.unwrap();
+    handle.set_mode(DownloadMode::Streaming);
+    let mut reader = handle.reader(1).await.unwrap();
+    reader.seek(SeekFrom::End(-100)).await.unwrap();
+    handle.start();
+
+    let mut tail = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), reader.read_to_end(&mut tail))
+        .await
+        .expect("read finished")
+        .unwrap();
+    assert_eq!(tail, data[B.end - 100..B.end]);
+}
+
+// nothing is coming for a paused torrent, a read fails rather than hangs
+#[tokio::test]
+async fn reading_a_paused_torrent_fails() {
+    let data = common::data(65536);
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(config()).await.unwrap();
+    let torrent = common::multi_torrent(&FILES, &data, PLEN, "http://127.0.0.1:1/announce");
+    let handle = session.add_torrent(torrent, dir.path()).await.unwrap();
+
+    let mut reader = handle.reader(0).await.unwrap();
+    let mut buf = [0; 10];
+    let err = tokio::time::timeout(Duration::from_secs(5), reader.read(&mut buf))
+        .await
+        .expect("read gave up")
+        .unwrap_err();
+    assert!(err.to_string().contains("paused"), "{}", err);
+}
+
+// a download that fails pauses the torrent, readers get told why
+#[tokio::test]
+async fn reading_a_failed_torrent_gives_the_reason() {
+    let data = common::data(65536);
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(config()).await.unwrap();
+    let torrent = common::multi_torrent(&FILES, &data, PLEN, "http://127.0.0.1:1/announce");
+    let handle = session.add_torrent(torrent, dir.path()).await.unwrap();
+    let mut reader = handle.reader(0).await.unwrap();
+    handle.start();
+
+    let mut buf = [0; 10];
+    let err = tokio::time::timeout(Duration::from_secs(10), reader.read(&mut buf))
+        .await
+        .expect("read gave up")
+        .unwrap_err();
+    assert_eq!(Some(err.to_string()), handle.progress().error);
+}
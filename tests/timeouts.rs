@@ -0,0 +1,50 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bittorrent_starter_rust::connection::{PeerConnection, Timeouts};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::peer_protocol::Handshake;
+use bittorrent_starter_rust::Error;
+
+use common::Behavior;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(300);
+
+async fn connect(behavior: Behavior) -> (PeerConnection, bittorrent_starter_rust::torrent::Torrent) {
+    let data = common::data(64 * 1024);
+    let torrent = common::torrent("data.bin", &data, 32 * 1024, "http://127.0.0.1:1/announce");
+    let addr = common::peer(&torrent, data, behavior).await;
+    let timeouts = Timeouts {
+        request: REQUEST_TIMEOUT,
+        ..Timeouts::default()
+    };
+    let handshake = Handshake::new(torrent.get_info_hash(), &PeerId::generate());
+    let (conn, _) = PeerConnection::connect(&addr.to_string(), &handshake, torrent.info.piece_count(), timeouts)
+        .await
+        .unwrap();
+    (conn, torrent)
+}
+
+// a peer that keeps the connection busy with haves, but never sends a
+// block, still runs out the request timeout
+#[tokio::test]
+async fn chatty_peer_that_never_answers_times_out() {
+    let (mut conn, torrent) = connect(Behavior::Stall).await;
+    let started = Instant::now();
+    let result = tokio::time::timeout(Duration::from_secs(5), conn.download_piece(&torrent.info, 0))
+        .await
+        .expect("request timeout fired");
+    assert!(matches!(result, Err(Error::Timeout("block"))), "{:?}", result.map(|b| b.len()));
+    let elapsed = started.elapsed();
+    assert!(elapsed >= REQUEST_TIMEOUT && elapsed < REQUEST_TIMEOUT * 3, "gave up after {:?}", elapsed);
+}
+
+#[tokio::test]
+async fn answering_peer_is_left_alone() {
+    let (mut conn, torrent) = connect(Behavior::Serve).await;
+    for index in 0..torrent.info.piece_count() {
+        let blocks = conn.download_piece(&torrent.info, index).await.unwrap();
+        assert_eq!(blocks.iter().map(|b| b.len()).sum::<usize>(), torrent.info.piece_len(index));
+    }
+}
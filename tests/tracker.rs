@@ -0,0 +1,49 @@
+mod common;
+
+use std::time::Duration;
+
+use bittorrent_starter_rust::{Session, SessionConfig};
+
+use common::Behavior;
+
+const LEN: usize = 96 * 1024;
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|kv| kv.strip_prefix(name)?.strip_prefix('='))
+}
+
+// The tracker hears what we've actually moved: nothing when we start, the
+// whole torrent downloaded when we come back to seed it.
+#[tokio::test]
+async fn announces_report_what_was_transferred() {
+    let data = common::data(LEN);
+    let probe = common::torrent("data.bin", &data, 32 * 1024, "http://127.0.0.1:1/announce");
+    let peer = common::peer(&probe, data.clone(), Behavior::Serve).await;
+    let (announce, announces) = common::recording_tracker(vec![peer]).await;
+    let torrent = common::torrent("data.bin", &data, 32 * 1024, &announce);
+
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(SessionConfig {
+        port: 0,
+        ..SessionConfig::default()
+    })
+    .await
+    .unwrap();
+    let handle = session.add_torrent(torrent, dir.path().join("data.bin")).await.unwrap();
+    handle.start();
+    tokio::time::timeout(Duration::from_secs(10), handle.wait_complete())
+        .await
+        .expect("download finished")
+        .unwrap();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while announces.lock().unwrap().len() < 2 {
+        assert!(tokio::time::Instant::now() < deadline, "no announce to seed");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let announces = announces.lock().unwrap();
+    let fields = |query| ["uploaded", "downloaded", "left"].map(|name| param(query, name));
+    let len = LEN.to_string();
+    assert_eq!(fields(&announces[0]), [Some("0"), Some("0"), Some(len.as_str())]);
+    assert_eq!(fields(&announces[1]), [Some("0"), Some(len.as_str()), Some("0")]);
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use bittorrent_starter_rust::bandwidth::Bandwidth;
+use bittorrent_starter_rust::connection::Timeouts;
+use bittorrent_starter_rust::stats::Stats;
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::webseed::WebSeed;
+use bittorrent_starter_rust::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const PLEN: usize = 16384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Server {
+    // answers ranges with 206 and just the bytes asked for
+    Ranges,
+    // always sends the whole file with a 200
+    IgnoresRanges,
+    // a 206 with only half the range in it
+    Short,
+}
+
+// A web seed on a local port serving `files`, keyed by their url path.
+// Returns the url to give the torrent.
+async fn server(files: HashMap<String, Vec<u8>>, behavior: Server) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else { return };
+            let files = files.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8(request).unwrap();
+                let path = request.split(' ').nth(1).unwrap();
+                let range = request.lines().find_map(|line| line.strip_prefix("range: bytes=")).map(|range| {
+                    let (from, to) = range.split_once('-').unwrap();
+                    (from.parse::<usize>().unwrap(), to.parse::<usize>().unwrap() + 1)
+                });
+                let (status, body) = match (files.get(path), range, behavior) {
+                    (None, _, _) => ("404 Not Found", &[][..]),
+                    (Some(file), Some((from, to)), Server::Ranges) => ("206 Partial Content", &file[from..to]),
+                    (Some(file), Some((from, to)), Server::Short) => ("206 Partial Content", &file[from..(from + to) / 2]),
+                    (Some(file), _, _) => ("200 OK", &file[..]),
+                };
+                let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            });
+        }
+    });
+    format!("http://{}/seed", addr)
+}
+
+// Three files under "multi": the first piece is exactly a and b, the
+// second and third all c.
+fn layout() -> (Torrent, Vec<u8>, HashMap<String, Vec<u8>>) {
+    let data = common::data(10000 + 6384 + 20000);
+    let (a, rest) = data.split_at(10000);
+    let (b, c) = rest.split_at(6384);
+    let mut encoded = String::from("d8:announce0:4:infod5:filesl");
+    for (path, len) in [("1:a", a.len()), ("3:dir5:b c.x", b.len()), ("1:c", c.len())] {
+        encoded.push_str(&format!("d6:lengthi{}e4:pathl{}ee", len, path));
+    }
+    encoded.push_str(&format!("e4:name5:multi12:piece lengthi{}e6:pieces60:", PLEN));
+    let mut encoded = encoded.into_bytes();
+    encoded.extend_from_slice(&[0; 60]);
+    encoded.extend_from_slice(b"ee");
+    let torrent = Torrent::from_bytes(&encoded).unwrap();
+
+    let files = HashMap::from([
+        ("/seed/multi/a".to_owned(), a.to_vec()),
+        // names are percent-encoded in the url
+        ("/seed/multi/dir/b%20c.x".to_owned(), b.to_vec()),
+        ("/seed/multi/c".to_owned(), c.to_vec()),
+    ]);
+    (torrent, data, files)
+}
+
+fn seed(url: &str) -> WebSeed {
+    let timeouts = Timeouts {
+        request: Duration::from_secs(5),
+        ..Timeouts::default()
+    };
+    WebSeed::new(url, timeouts, Bandwidth::new(None, None), &Stats::default()).unwrap()
+}
+
+#[tokio::test]
+async fn pieces_are_put_together_across_files() {
+    let (torrent, data, files) = layout();
+    let url = server(files, Server::Ranges).await;
+    let info = &torrent.info;
+    let files = info.files().unwrap();
+    let mut seed = seed(&url);
+    for index in 0..info.piece_count() {
+        let piece = seed.fetch_piece(info, &files, index).await.unwrap().concat();
+        let start = index * PLEN;
+        assert_eq!(piece, data[start..start + info.piece_len(index)], "piece {}", index);
+    }
+}
+
+// a whole file in a 200 is fine if the whole file is what we wanted
+#[tokio::test]
+async fn server_ignoring_ranges_is_only_good_for_whole_files() {
+    let (torrent, data, files) = layout();
+    let url = server(files, Server::IgnoresRanges).await;
+    let info = &torrent.info;
+    let files = info.files().unwrap();
+    let mut seed = seed(&url);
+    assert_eq!(seed.fetch_piece(info, &files, 0).await.unwrap().concat(), data[..PLEN]);
+    let err = seed.fetch_piece(info, &files, 1).await.unwrap_err();
+    assert!(matches!(err, Error::Protocol(_)), "{:?}", err);
+}
+
+#[tokio::test]
+async fn short_body_is_an_error() {
+    let (torrent, _, files) = layout();
+    let url = server(files, Server::Short).await;
+    let info = &torrent.info;
+    let files = info.files().unwrap();
+    let err = seed(&url).fetch_piece(info, &files, 1).await.unwrap_err();
+    assert!(matches!(&err, Error::Protocol(message) if message.contains("8192 of 16384")), "{:?}", err);
+}
+
+#[tokio::test]
+async fn missing_file_is_an_error() {
+    let (torrent, _, mut files) = layout();
+    files.remove("/seed/multi/c");
+    let url = server(files, Server::Ranges).await;
+    let info = &torrent.info;
+    let files = info.files().unwrap();
+    assert!(seed(&url).fetch_piece(info, &files, 2).await.is_err());
+}